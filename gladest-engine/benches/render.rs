@@ -0,0 +1,55 @@
+//! Rendering throughput of single formulas.
+//!
+//! Formulas use Typst syntax and the embedded fonts, so the benchmark needs neither
+//! network access for mitex nor any system fonts. The render cache is disabled; every
+//! iteration compiles and exports the formula again.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use gladest_engine::{FontConfig, FontSource, InputSyntax, RenderEngine, RenderFormat};
+
+const FORMULAS: &[(&str, &str, bool)] = &[
+    ("simple inline", "a^2 + b^2 = c^2", true),
+    (
+        "big matrix",
+        "mat(a_11, a_12, a_13, a_14; a_21, a_22, a_23, a_24; a_31, a_32, a_33, a_34; a_41, a_42, a_43, a_44)",
+        false,
+    ),
+    (
+        "integral",
+        "integral_(-infinity)^infinity e^(-x^2) dif x = sqrt(pi)",
+        false,
+    ),
+];
+
+fn engine() -> RenderEngine {
+    let font_config = FontConfig::builder()
+        .body_font(FontSource::System("New Computer Modern".to_string()))
+        .math_font(FontSource::System("New Computer Modern Math".to_string()))
+        .include_embedded_fonts(true)
+        .input_syntax(InputSyntax::Typst)
+        .build();
+    RenderEngine::with_font_config(font_config).with_cache_capacity(0)
+}
+
+fn render(c: &mut Criterion) {
+    let engine = engine();
+
+    for format in [RenderFormat::Svg, RenderFormat::Png] {
+        let mut group = c.benchmark_group(format!("render_formula/{format:?}"));
+        for &(name, formula, is_inline) in FORMULAS {
+            group.bench_function(name, |b| {
+                b.iter(|| {
+                    engine
+                        .render_formula(black_box(formula), is_inline, format, None)
+                        .unwrap()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
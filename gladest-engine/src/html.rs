@@ -0,0 +1,773 @@
+//! Rendering of every formula element in an HTML document.
+
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use ego_tree::{NodeId, NodeRef};
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+pub use scraper::Selector;
+use scraper::{ElementRef, Html, Node};
+
+use crate::{
+    DEFAULT_CLASS_PREFIX, DEFAULT_PPI, FormulaRenderResult, RenderEngine, RenderError,
+    RenderFormat, Result, strip_delimiters,
+};
+
+/// Elements holding formulas, unless [`HtmlOptions::selector`] says otherwise
+pub const DEFAULT_SELECTOR: &str = "eq";
+
+/// Formula elements holding display math, unless [`HtmlOptions::display_selector`] says otherwise
+pub const DEFAULT_DISPLAY_SELECTOR: &str = "[env=displaymath]";
+
+/// Formula elements holding inline math, unless [`HtmlOptions::inline_selector`] says otherwise
+pub const DEFAULT_INLINE_SELECTOR: &str = "[env=math]";
+
+/// Formulas rendered by an earlier run, which record their source in `data-gladst-formula`
+const RENDERED_SELECTOR: &str = "[data-gladst-formula][data-gladst-hash]";
+
+/// Stylesheet for rendered formulas and error markers, with `{prefix}` standing for the
+/// class prefix; see [`default_stylesheet`]
+const DEFAULT_STYLESHEET: &str = r#"/* Formulas rendered by gladst */
+.{prefix} {
+  max-width: 100%;
+  object-fit: contain;
+}
+.{prefix} > svg {
+  width: 100%;
+  height: 100%;
+}
+img.{prefix}.displaymath {
+  display: block;
+  margin: 0.75em auto;
+}
+.{prefix}-error {
+  font-family: monospace;
+  font-size: 0.9em;
+  white-space: nowrap;
+  cursor: help;
+}
+@media (max-width: 600px) {
+  img.{prefix}.displaymath {
+    height: auto !important;
+  }
+}
+"#;
+
+/// Default CSS for documents rendered with `class_prefix`: inline formulas keep the
+/// baseline alignment of their inline styles, display images are centered, wide formulas
+/// shrink to fit narrow screens and error markers stand out
+pub fn default_stylesheet(class_prefix: &str) -> String {
+    DEFAULT_STYLESHEET.replace("{prefix}", class_prefix)
+}
+
+/// Settings of [`render_html`]
+#[derive(Debug, Clone)]
+pub struct HtmlOptions {
+    /// Elements holding formulas
+    pub selector: Selector,
+    /// Formula elements that hold display math.
+    /// Explicit delimiters like `$$...$$` in an element take precedence.
+    pub display_selector: Selector,
+    /// Formula elements that hold inline math, unless they also match `display_selector`
+    pub inline_selector: Selector,
+    /// Whether formula elements matching neither selector, e.g. an `<eq>` without an
+    /// `env` attribute or with an unknown one, are inline rather than display math
+    pub default_inline: bool,
+    /// Image format, unless a formula's `format` attribute names another
+    pub format: RenderFormat,
+    /// Resolution of raster images, unless a formula's `ppi` attribute sets another
+    pub ppi: f32,
+    /// Class prefix of the generated `<img>` tags
+    pub class_prefix: String,
+    /// Number display formulas `(1)`, `(2)`, ... in document order
+    pub number_equations: bool,
+    /// Display size of SVG images relative to their rendered size, see
+    /// [`FormulaRenderResult::scaled`]
+    pub svg_scale: f64,
+    /// Render this many formulas at a time and free the compiler's memory in between,
+    /// bounding peak memory on very large documents; all at once when unset
+    pub chunk_size: Option<NonZeroUsize>,
+    /// Render formulas left by an earlier run again even if neither the formula nor the
+    /// settings changed, e.g. when their images are written somewhere that is replaced
+    pub rerender_unchanged: bool,
+    /// Keep the original text and env of each formula element in `data-gladst-source` and
+    /// `data-gladst-env` attributes of its image, see [`FormulaRenderResult::source`]
+    pub keep_source: bool,
+}
+
+impl Default for HtmlOptions {
+    fn default() -> Self {
+        Self {
+            selector: parse_selector(DEFAULT_SELECTOR).expect("default selector is valid"),
+            display_selector: parse_selector(DEFAULT_DISPLAY_SELECTOR)
+                .expect("default display selector is valid"),
+            inline_selector: parse_selector(DEFAULT_INLINE_SELECTOR)
+                .expect("default inline selector is valid"),
+            default_inline: true,
+            format: RenderFormat::Svg,
+            ppi: DEFAULT_PPI,
+            class_prefix: DEFAULT_CLASS_PREFIX.to_string(),
+            number_equations: false,
+            svg_scale: 1.0,
+            chunk_size: None,
+            rerender_unchanged: false,
+            keep_source: false,
+        }
+    }
+}
+
+/// Parse a CSS selector, e.g. for [`HtmlOptions::selector`]
+pub fn parse_selector(selector: &str) -> Result<Selector> {
+    Selector::parse(selector)
+        .map_err(|error| RenderError::InvalidSelector(format!("{:?}: {}", selector, error)))
+}
+
+/// A formula element of a document with the settings it is rendered with
+#[derive(Debug, Clone)]
+pub struct HtmlFormula {
+    /// Position among the document's formula elements, starting at 0
+    pub index: usize,
+    pub formula: String,
+    pub is_inline: bool,
+    /// Text of the element, delimiters included; for an image of an earlier run, the
+    /// text it recorded in `data-gladst-source`, or else its formula
+    pub source: String,
+    /// Equation number of a numbered display formula
+    pub number: Option<usize>,
+    pub format: RenderFormat,
+    pub ppi: f32,
+    /// Alt text from the element's `alt` or `label` attribute; the formula source is
+    /// used when neither is set
+    pub alt: Option<String>,
+    /// [`FormulaRenderResult::render_hash`] of an element rendered by an earlier run,
+    /// from its `data-gladst-hash` attribute
+    pub previous_hash: Option<u64>,
+    /// Line of the element in the source document, starting at 1, if it could be found
+    pub line: Option<usize>,
+}
+
+impl HtmlFormula {
+    /// An `<img>` tag showing `result` from `src`, with the element's alt text if it has one
+    pub fn img_tag(&self, result: &FormulaRenderResult, src: &str, class_prefix: &str) -> String {
+        match &self.alt {
+            Some(alt) => result.img_tag_with_alt(src, class_prefix, alt),
+            None => result.img_tag(src, class_prefix),
+        }
+    }
+}
+
+/// A formula that failed to render; the document shows an error marker in its place
+#[derive(Debug)]
+pub struct HtmlFormulaError<E = RenderError> {
+    /// Position among the document's formula elements, starting at 0
+    pub index: usize,
+    pub formula: String,
+    /// Line of the formula element in the source document, starting at 1
+    pub line: Option<usize>,
+    pub error: E,
+}
+
+/// Result of [`render_html_with`]
+#[derive(Debug)]
+pub struct HtmlOutput<E = RenderError> {
+    pub html: String,
+    /// Number of formula elements in the document, including those left as they were
+    /// because an earlier run rendered them with the same settings
+    pub formula_count: usize,
+    /// Formulas that failed to render, in document order
+    pub errors: Vec<HtmlFormulaError<E>>,
+}
+
+/// Callbacks of [`render_html_with`]. Formulas render in parallel, so the callbacks for
+/// different formulas may run concurrently and in any order.
+pub trait HtmlHooks: Sync {
+    /// Error of a failed formula: a render error or one from [`Self::image_src`] or
+    /// [`Self::formula_html`]
+    type Error: From<RenderError> + Send;
+
+    /// Called as each formula element is found, e.g. to show progress on documents
+    /// large enough that finding the formulas takes a while
+    fn formula_collected(&self, _formula: &HtmlFormula) {}
+
+    /// Called once with every formula of the document that is rendered, before any is
+    fn formulas_found(&self, _formulas: &[HtmlFormula]) {}
+
+    /// Called for a `format` or `ppi` attribute that cannot be parsed; the formula
+    /// uses the setting of [`HtmlOptions`] instead
+    fn invalid_attribute(&self, _index: usize, _name: &str, _value: &str) {}
+
+    /// The `src` of a rendered formula's `<img>`; a data URL embedding the image unless
+    /// overridden, e.g. to write the image to a file
+    fn image_src(&self, result: &FormulaRenderResult) -> std::result::Result<String, Self::Error> {
+        Ok(result.data_url())
+    }
+
+    /// The HTML that replaces a rendered formula's element; unless overridden, an `<img>`
+    /// tag whose `src` comes from [`Self::image_src`]
+    fn formula_html(
+        &self,
+        formula: &HtmlFormula,
+        result: &FormulaRenderResult,
+        class_prefix: &str,
+    ) -> std::result::Result<String, Self::Error> {
+        let src = self.image_src(result)?;
+        Ok(formula.img_tag(result, &src, class_prefix))
+    }
+
+    /// Called after each formula with its result, `None` if it failed, and the time
+    /// spent rendering it
+    fn formula_rendered(
+        &self,
+        _formula: &HtmlFormula,
+        _result: Option<&FormulaRenderResult>,
+        _duration: Duration,
+    ) {
+    }
+
+    /// Called after every formula has rendered, before the document is written out
+    fn serializing(&self) {}
+}
+
+/// No callbacks; images are embedded as data URLs
+impl HtmlHooks for () {
+    type Error = RenderError;
+}
+
+/// Render every formula element of an HTML document and replace it with an `<img>` tag.
+/// A formula that fails is replaced by an error marker and returned with its error.
+///
+/// Images rendered by an earlier run are recognized by their `data-gladst-formula` and
+/// `data-gladst-hash` attributes: they are left as they are if the formula and settings
+/// are unchanged and rendered again otherwise, so a document can be edited and rendered
+/// in place repeatedly.
+pub fn render_html(
+    html: &str,
+    engine: &RenderEngine,
+    options: &HtmlOptions,
+) -> (String, Vec<HtmlFormulaError>) {
+    let output = render_html_with(html, engine, options, &());
+    (output.html, output.errors)
+}
+
+/// Like [`render_html`], calling `hooks` around each formula
+pub fn render_html_with<H: HtmlHooks>(
+    html: &str,
+    engine: &RenderEngine,
+    options: &HtmlOptions,
+    hooks: &H,
+) -> HtmlOutput<H::Error> {
+    let document = Html::parse_document(html);
+    let (node_ids, formulas) = collect_formulas(html, &document, options, hooks);
+
+    let formula_count = formulas.len();
+    let (node_ids, formulas): (Vec<NodeId>, Vec<HtmlFormula>) = node_ids
+        .into_iter()
+        .zip(formulas)
+        .filter(|(_, formula)| options.rerender_unchanged || !is_unchanged(formula, engine))
+        .unzip();
+    if formulas.is_empty() {
+        return HtmlOutput {
+            html: document.html(),
+            formula_count,
+            errors: Vec::new(),
+        };
+    }
+    hooks.formulas_found(&formulas);
+
+    // Rendered HTML for each formula element, keyed by its node in the document
+    let replacements = Mutex::new(HashMap::<NodeId, String>::new());
+    let errors = Mutex::new(Vec::<HtmlFormulaError<H::Error>>::new());
+
+    let render = |(node_id, formula): (NodeId, HtmlFormula)| {
+        let started = Instant::now();
+        let rendered = match formula.number {
+            Some(number) => engine.render_formula_numbered(
+                &formula.formula,
+                formula.format,
+                Some(formula.ppi),
+                Some(number),
+            ),
+            None => engine.render_formula(
+                &formula.formula,
+                formula.is_inline,
+                formula.format,
+                Some(formula.ppi),
+            ),
+        };
+        let duration = started.elapsed();
+        // An empty element has nothing to show; drop it instead of leaving it in the output
+        if matches!(rendered, Err(RenderError::EmptyFormula)) {
+            hooks.formula_rendered(&formula, None, duration);
+            replacements.lock().unwrap().insert(node_id, String::new());
+            return;
+        }
+        let rendered = rendered
+            .map(|result| match result.format {
+                RenderFormat::Svg => result.scaled(options.svg_scale),
+                _ => result,
+            })
+            // Recorded so a later run can leave unchanged formulas as they are
+            .map(FormulaRenderResult::with_tracking)
+            .map(|result| {
+                if options.keep_source {
+                    result.with_source(formula.source.clone())
+                } else {
+                    result
+                }
+            });
+
+        let replacement = match rendered.map_err(H::Error::from).and_then(|result| {
+            // Only with `FontConfig::allow_empty_render`; the formula is left as it is
+            let html = if result.data.is_empty() {
+                None
+            } else {
+                Some(hooks.formula_html(&formula, &result, &options.class_prefix)?)
+            };
+            Ok((result, html))
+        }) {
+            Ok((result, html)) => {
+                hooks.formula_rendered(&formula, Some(&result), duration);
+                html
+            }
+            Err(error) => {
+                hooks.formula_rendered(&formula, None, duration);
+                let marker = error_marker(&formula, &options.class_prefix);
+                errors.lock().unwrap().push(HtmlFormulaError {
+                    index: formula.index,
+                    line: formula.line,
+                    formula: formula.formula,
+                    error,
+                });
+                Some(marker)
+            }
+        };
+
+        if let Some(replacement) = replacement {
+            replacements.lock().unwrap().insert(node_id, replacement);
+        }
+    };
+
+    // With a chunk size, each chunk finishes and the compiler's memo is freed before the
+    // next one starts, so memory no longer grows with the length of the document
+    let chunk_size = options.chunk_size.map_or(formulas.len(), NonZeroUsize::get);
+    let mut tasks = node_ids.into_iter().zip(formulas);
+    loop {
+        let chunk: Vec<(NodeId, HtmlFormula)> = tasks.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunk.into_par_iter().for_each(&render);
+        if options.chunk_size.is_some() {
+            engine.evict_compiler_cache();
+        }
+    }
+
+    hooks.serializing();
+    let replacements = replacements.into_inner().unwrap();
+    let mut errors = errors.into_inner().unwrap();
+    // Sort errors by formula index for consistent output
+    errors.sort_by_key(|error| error.index);
+
+    HtmlOutput {
+        html: serialize_with_replacements(&document, &replacements),
+        formula_count,
+        errors,
+    }
+}
+
+/// Whether `formula` was rendered by an earlier run exactly as it would be now
+fn is_unchanged(formula: &HtmlFormula, engine: &RenderEngine) -> bool {
+    formula.previous_hash.is_some_and(|previous_hash| {
+        previous_hash
+            == engine.formula_hash(
+                &formula.formula,
+                formula.is_inline,
+                formula.number,
+                formula.format,
+                Some(formula.ppi),
+            )
+    })
+}
+
+/// Every formula element of an HTML document with the settings it would be rendered with,
+/// in document order
+pub fn find_formulas(html: &str, options: &HtmlOptions) -> Vec<HtmlFormula> {
+    let document = Html::parse_document(html);
+    collect_formulas(html, &document, options, &()).1
+}
+
+/// The formula elements of `document`, parsed from `html`, and the nodes they live in
+fn collect_formulas<H: HtmlHooks>(
+    html: &str,
+    document: &Html,
+    options: &HtmlOptions,
+    hooks: &H,
+) -> (Vec<NodeId>, Vec<HtmlFormula>) {
+    let mut node_ids = Vec::new();
+    let mut formulas = Vec::new();
+    let mut equation_count = 0;
+    let rendered_selector = parse_selector(RENDERED_SELECTOR).expect("rendered selector is valid");
+    let mut source = SourceTags::new(html);
+    // Elements seen so far of each tag name
+    let mut tag_counts = HashMap::<String, usize>::new();
+
+    // Formula elements and images of earlier runs, in document order, with the number of
+    // elements of the same tag name before them
+    let elements = document
+        .root_element()
+        .descendants()
+        .filter_map(ElementRef::wrap)
+        .filter_map(|element| {
+            let count = tag_counts
+                .entry(element.value().name().to_string())
+                .or_default();
+            let ordinal = *count;
+            *count += 1;
+            (options.selector.matches(&element) || rendered_selector.matches(&element))
+                .then_some((element, ordinal))
+        });
+
+    for (index, (element, ordinal)) in elements.enumerate() {
+        let previous = rendered_selector
+            .matches(&element)
+            .then(|| previous_render(&element));
+        let (formula, is_inline, previous_hash, source) = match previous {
+            Some((formula, is_inline, previous_hash)) => {
+                let kept_source = element.value().attr("data-gladst-source");
+                // An image without the kept source is rendered again to add it
+                let previous_hash =
+                    previous_hash.filter(|_| !options.keep_source || kept_source.is_some());
+                let source = kept_source.map_or_else(|| formula.clone(), str::to_string);
+                (formula, is_inline, previous_hash, source)
+            }
+            None => {
+                let text = element.text().collect::<String>();
+                // Explicit delimiters win over the display selector
+                match strip_delimiters(&text) {
+                    Some((formula, is_inline)) => {
+                        (formula.to_string(), is_inline, None, text.clone())
+                    }
+                    None => (
+                        text.clone(),
+                        is_inline_element(&element, options),
+                        None,
+                        text,
+                    ),
+                }
+            }
+        };
+
+        let number =
+            (options.number_equations && !is_inline && !formula.trim().is_empty()).then(|| {
+                equation_count += 1;
+                equation_count
+            });
+
+        // `ppi` and `format` attributes override the options for one formula
+        let format = match element.value().attr("format") {
+            Some(value) => RenderFormat::from_name(value.trim()).unwrap_or_else(|| {
+                hooks.invalid_attribute(index, "format", value);
+                options.format
+            }),
+            None => options.format,
+        };
+        let ppi = match element.value().attr("ppi") {
+            Some(value) => match value.trim().parse::<f32>() {
+                Ok(ppi) if ppi.is_finite() && ppi > 0.0 => ppi,
+                _ => {
+                    hooks.invalid_attribute(index, "ppi", value);
+                    options.ppi
+                }
+            },
+            None => options.ppi,
+        };
+
+        // Images of earlier runs carry the alt text as `alt` or `aria-label`, or the
+        // formula source if there was none
+        let alt = ["alt", "label", "aria-label"]
+            .into_iter()
+            .filter_map(|name| element.value().attr(name))
+            .map(str::trim)
+            .find(|alt| !alt.is_empty())
+            .filter(|alt| previous_hash.is_none() || *alt != formula)
+            .map(str::to_string);
+
+        let formula = HtmlFormula {
+            index,
+            formula,
+            is_inline,
+            source,
+            number,
+            format,
+            ppi,
+            alt,
+            previous_hash,
+            line: source_line(&element, ordinal, &mut source),
+        };
+        hooks.formula_collected(&formula);
+        node_ids.push(element.id());
+        formulas.push(formula);
+    }
+
+    (node_ids, formulas)
+}
+
+/// Whether a formula element without delimiters holds inline math
+fn is_inline_element(element: &ElementRef, options: &HtmlOptions) -> bool {
+    if options.display_selector.matches(element) {
+        false
+    } else if options.inline_selector.matches(element) {
+        true
+    } else {
+        options.default_inline
+    }
+}
+
+/// Formula, whether it is inline and render hash recorded on an image of an earlier run
+fn previous_render(element: &ElementRef) -> (String, bool, Option<u64>) {
+    let element = element.value();
+    let formula = element.attr("data-gladst-formula").unwrap_or_default();
+    let hash = element
+        .attr("data-gladst-hash")
+        .and_then(|hash| u64::from_str_radix(hash.trim(), 16).ok());
+    let is_inline = match element.attr("data-gladst-env") {
+        Some(env) => env != "displaymath",
+        None => !element.classes().any(|class| class == "displaymath"),
+    };
+    (formula.to_string(), is_inline, hash)
+}
+
+/// Line of a formula element: from its `data-gladst-line` attribute, which
+/// [`markdown_to_html`](crate::markdown::markdown_to_html) sets to the line in the Markdown,
+/// or else from the position of its start tag in the HTML
+fn source_line(element: &ElementRef, ordinal: usize, source: &mut SourceTags) -> Option<usize> {
+    match element.value().attr("data-gladst-line") {
+        Some(line) => line.trim().parse().ok(),
+        None => source.line(element.value().name(), ordinal),
+    }
+}
+
+/// Positions of start tags in the source of a document. The parser does not keep them,
+/// so the `n`th element of a tag name is taken to start at the `n`th `<name` in the
+/// source; a tag name that also appears in comments or scripts may give a wrong line.
+struct SourceTags<'a> {
+    html: &'a str,
+    /// Byte offsets of the start tags found so far, by tag name
+    offsets: HashMap<String, Vec<usize>>,
+    /// Byte offsets of the line breaks
+    line_breaks: Vec<usize>,
+}
+
+impl<'a> SourceTags<'a> {
+    fn new(html: &'a str) -> Self {
+        Self {
+            html,
+            offsets: HashMap::new(),
+            line_breaks: html.match_indices('\n').map(|(offset, _)| offset).collect(),
+        }
+    }
+
+    /// Line of the `ordinal`th start tag of `name`, starting at 1
+    fn line(&mut self, name: &str, ordinal: usize) -> Option<usize> {
+        let html = self.html;
+        let offsets = self
+            .offsets
+            .entry(name.to_string())
+            .or_insert_with(|| start_tags(html, name));
+        let offset = *offsets.get(ordinal)?;
+        Some(
+            self.line_breaks
+                .partition_point(|&line_break| line_break < offset)
+                + 1,
+        )
+    }
+}
+
+/// Byte offsets of every `<name` in `html` that starts a tag, ignoring ASCII case
+fn start_tags(html: &str, name: &str) -> Vec<usize> {
+    let bytes = html.as_bytes();
+    let name = name.as_bytes();
+    (0..bytes.len())
+        .filter(|&offset| {
+            let end = offset + 1 + name.len();
+            bytes[offset] == b'<'
+                && bytes
+                    .get(offset + 1..end)
+                    .is_some_and(|tag| tag.eq_ignore_ascii_case(name))
+                && bytes
+                    .get(end)
+                    .is_none_or(|next| next.is_ascii_whitespace() || matches!(next, b'>' | b'/'))
+        })
+        .collect()
+}
+
+/// Shown in place of a formula that failed to render
+fn error_marker(formula: &HtmlFormula, class_prefix: &str) -> String {
+    let location = match formula.line {
+        Some(line) => format!(" at line {}", line),
+        None => String::new(),
+    };
+    // Counted in characters, so multibyte text is never cut inside a character
+    let excerpt = if formula.formula.chars().count() > 20 {
+        format!(
+            "{}...",
+            formula.formula.chars().take(17).collect::<String>()
+        )
+    } else {
+        formula.formula.clone()
+    };
+    format!(
+        r#"<span class="{}-error" style="color: red; background-color: #ffe6e6; padding: 2px 4px; border-radius: 3px;" title="Formula render error{location} - see logs for details">[Formula Error #{}: {}]</span>"#,
+        encode_double_quoted_attribute(class_prefix),
+        formula.index + 1,
+        encode_text(&excerpt)
+    )
+}
+
+/// Elements whose text content is written without escaping
+const RAW_TEXT_ELEMENTS: &[&str] = &[
+    "script",
+    "style",
+    "xmp",
+    "iframe",
+    "noembed",
+    "noframes",
+    "plaintext",
+    "noscript",
+];
+
+/// Serialize `document`, writing the HTML in `replacements` in place of the
+/// nodes it is keyed by. Subtrees without replacements are serialized by
+/// scraper as-is; only the ancestors of replaced nodes are written here.
+fn serialize_with_replacements(document: &Html, replacements: &HashMap<NodeId, String>) -> String {
+    let ancestors: HashSet<NodeId> = replacements
+        .keys()
+        .filter_map(|id| document.tree.get(*id))
+        .flat_map(|node| node.ancestors().map(|ancestor| ancestor.id()))
+        .collect();
+
+    let mut output = String::with_capacity(replacements.values().map(String::len).sum());
+    write_node(document.tree.root(), replacements, &ancestors, &mut output);
+    output
+}
+
+fn write_node(
+    node: NodeRef<Node>,
+    replacements: &HashMap<NodeId, String>,
+    ancestors: &HashSet<NodeId>,
+    output: &mut String,
+) {
+    if let Some(replacement) = replacements.get(&node.id()) {
+        output.push_str(replacement);
+        return;
+    }
+
+    let rewrite_children = ancestors.contains(&node.id());
+    match node.value() {
+        Node::Element(element) if rewrite_children => {
+            output.push('<');
+            output.push_str(element.name());
+            for (name, value) in element.attrs() {
+                output.push_str(&format!(
+                    r#" {}="{}""#,
+                    name,
+                    encode_double_quoted_attribute(value)
+                ));
+            }
+            output.push('>');
+            for child in node.children() {
+                write_node(child, replacements, ancestors, output);
+            }
+            output.push_str(&format!("</{}>", element.name()));
+        }
+        Node::Element(_) => {
+            if let Some(element) = ElementRef::wrap(node) {
+                output.push_str(&element.html());
+            }
+        }
+        Node::Document | Node::Fragment => {
+            for child in node.children() {
+                write_node(child, replacements, ancestors, output);
+            }
+        }
+        Node::Text(text) => {
+            let is_raw = node
+                .parent()
+                .and_then(|parent| parent.value().as_element())
+                .is_some_and(|parent| RAW_TEXT_ELEMENTS.contains(&parent.name()));
+            let text: &str = text;
+            if is_raw {
+                output.push_str(text);
+            } else {
+                output.push_str(&encode_text(text));
+            }
+        }
+        Node::Comment(comment) => output.push_str(&format!("<!--{}-->", &**comment)),
+        Node::Doctype(doctype) => output.push_str(&format!("<!DOCTYPE {}>", doctype.name())),
+        Node::ProcessingInstruction(instruction) => {
+            output.push_str(&format!("<?{} {}>", instruction.target, instruction.data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FontConfig, InputSyntax};
+
+    /// Two identical formula elements and one whose attributes come in another order
+    const DOCUMENT: &str = r#"<p>a <eq env="math" class="f">x^2</eq> b <eq env="math" class="f">x^2</eq> c <eq class="f" env="math">y</eq> d</p>"#;
+
+    /// Replace every `<img .../>` tag in `html` with `[img]`
+    fn without_images(html: &str) -> String {
+        let mut output = String::new();
+        let mut rest = html;
+        while let Some(start) = rest.find("<img") {
+            let end = start + rest[start..].find("/>").unwrap() + 2;
+            output.push_str(&rest[..start]);
+            output.push_str("[img]");
+            rest = &rest[end..];
+        }
+        output.push_str(rest);
+        output
+    }
+
+    #[test]
+    fn replaces_each_formula_element_once_in_place() {
+        let document = Html::parse_document(DOCUMENT);
+        let selector = parse_selector(DEFAULT_SELECTOR).unwrap();
+        let replacements: HashMap<NodeId, String> = document
+            .select(&selector)
+            .enumerate()
+            .map(|(index, element)| (element.id(), format!("[{index}]")))
+            .collect();
+        assert_eq!(replacements.len(), 3);
+
+        let html = serialize_with_replacements(&document, &replacements);
+        assert!(html.contains("<p>a [0] b [1] c [2] d</p>"), "{html}");
+        assert!(!html.contains("<eq"), "{html}");
+    }
+
+    #[test]
+    fn renders_identical_and_reordered_elements() {
+        let font_config = FontConfig::embedded()
+            .into_builder()
+            .input_syntax(InputSyntax::Typst)
+            .build();
+        let engine = RenderEngine::with_font_config(font_config);
+
+        let (html, errors) = render_html(DOCUMENT, &engine, &HtmlOptions::default());
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(
+            without_images(&html).contains("<p>a [img] b [img] c [img] d</p>"),
+            "{html}"
+        );
+        assert!(!html.contains("<eq"), "{html}");
+        assert!(!html.contains("PLACEHOLDER"), "{html}");
+    }
+}
@@ -1,27 +1,194 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Duration,
+};
 
 use allsorts::{
     binary::read::ReadScope,
     font_data::FontData,
+    subset::{SubsetProfile, subset},
     tables::{FontTableProvider, NameTable},
     tag,
 };
-use anyhow::{Context, Result};
 use base64::{Engine, engine::general_purpose};
 use derive_typst_intoval::{IntoDict, IntoValue};
-use html_escape::encode_text;
+use html_escape::encode_double_quoted_attribute;
+use image::{
+    ExtendedColorType, ImageEncoder,
+    codecs::{jpeg::JpegEncoder, webp::WebPEncoder},
+};
+use lru::LruCache;
+use ravif::{Encoder as AvifEncoder, Img, RGBA8};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use tiny_skia::IntRect;
+pub use tiny_skia::Pixmap;
 use typst::{
+    diag::{FileError, Severity, SourceDiagnostic, Warned},
     foundations::{Dict, IntoValue},
-    layout::PagedDocument,
+    layout::{Abs, Frame, FrameItem, PagedDocument, Point, Size},
+    syntax::Span,
+    text::{Font, FontInfo, FontVariant},
 };
 use typst_as_lib::{
-    TypstAsLibError, TypstEngine, TypstTemplateMainFile, typst_kit_options::TypstKitFontOptions,
+    TypstAsLibError, TypstEngine, TypstTemplateMainFile, file_resolver::FileSystemResolver,
+    typst_kit_options::TypstKitFontOptions,
 };
+use typst_kit::fonts::FontSearcher;
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub mod html;
+pub mod markdown;
+pub mod sprite;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum RenderFormat {
     Png,
     Svg,
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+impl RenderFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            RenderFormat::Svg => "image/svg+xml",
+            RenderFormat::Png => "image/png",
+            RenderFormat::Jpeg => "image/jpeg",
+            RenderFormat::WebP => "image/webp",
+            RenderFormat::Avif => "image/avif",
+        }
+    }
+
+    /// File extension for images in this format, without the leading dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RenderFormat::Svg => "svg",
+            RenderFormat::Png => "png",
+            RenderFormat::Jpeg => "jpg",
+            RenderFormat::WebP => "webp",
+            RenderFormat::Avif => "avif",
+        }
+    }
+
+    /// Parse a format name like `"png"` or `"jpg"`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(RenderFormat::Png),
+            "svg" => Some(RenderFormat::Svg),
+            "jpeg" | "jpg" => Some(RenderFormat::Jpeg),
+            "webp" => Some(RenderFormat::WebP),
+            "avif" => Some(RenderFormat::Avif),
+            _ => None,
+        }
+    }
+}
+
+/// An sRGB color with an alpha channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+
+    /// Create an opaque color
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+
+    /// Look up one of Typst's predefined color names
+    pub fn named(name: &str) -> Option<Self> {
+        let color = match name.to_ascii_lowercase().as_str() {
+            "black" => Color::rgb(0x00, 0x00, 0x00),
+            "gray" => Color::rgb(0xaa, 0xaa, 0xaa),
+            "silver" => Color::rgb(0xdd, 0xdd, 0xdd),
+            "white" => Color::rgb(0xff, 0xff, 0xff),
+            "navy" => Color::rgb(0x00, 0x1f, 0x3f),
+            "blue" => Color::rgb(0x00, 0x74, 0xd9),
+            "aqua" => Color::rgb(0x7f, 0xdb, 0xff),
+            "teal" => Color::rgb(0x39, 0xcc, 0xcc),
+            "eastern" => Color::rgb(0x23, 0x9d, 0xad),
+            "purple" => Color::rgb(0xb1, 0x0d, 0xc9),
+            "fuchsia" => Color::rgb(0xf0, 0x12, 0xbe),
+            "maroon" => Color::rgb(0x85, 0x14, 0x4b),
+            "red" => Color::rgb(0xff, 0x41, 0x36),
+            "orange" => Color::rgb(0xff, 0x85, 0x1b),
+            "yellow" => Color::rgb(0xff, 0xdc, 0x00),
+            "olive" => Color::rgb(0x3d, 0x99, 0x70),
+            "green" => Color::rgb(0x2e, 0xcc, 0x40),
+            "lime" => Color::rgb(0x01, 0xff, 0x70),
+            _ => return None,
+        };
+        Some(color)
+    }
+
+    /// Format the color as a `#rrggbbaa` hex string
+    pub fn to_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}{:02x}", self.r, self.g, self.b, self.a)
+    }
+
+    /// Format the color as a Typst `rgb(...)` expression
+    fn to_typst(self) -> String {
+        format!("rgb(\"{}\")", self.to_hex())
+    }
+}
+
+impl FromStr for Color {
+    type Err = RenderError;
+
+    /// Parse `#rgb`, `#rrggbb`, `#rrggbbaa` (the `#` is optional) or a Typst color name
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if let Some(color) = Self::named(value) {
+            return Ok(color);
+        }
+
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(RenderError::InvalidColor(format!(
+                "Invalid color '{}': expected a hex code like #1e1e1e or a color name",
+                value
+            )));
+        }
+
+        // Every character is a hex digit, so parsing a slice cannot fail
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or_default();
+        let short = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).unwrap_or_default() * 17;
+        let color = match hex.len() {
+            3 => Color::rgb(short(0), short(1), short(2)),
+            6 => Color::rgb(channel(0), channel(2), channel(4)),
+            8 => Color {
+                r: channel(0),
+                g: channel(2),
+                b: channel(4),
+                a: channel(6),
+            },
+            _ => {
+                return Err(RenderError::InvalidColor(format!(
+                    "Invalid color '{}': hex codes must have 3, 6 or 8 digits",
+                    value
+                )));
+            }
+        };
+        Ok(color)
+    }
 }
 
 #[derive(Debug)]
@@ -35,32 +202,361 @@ pub struct FontNames {
 }
 
 /// Font source configuration
-#[derive(Debug, Clone, Eq, PartialEq)]
+///
+/// File and data sources take the index of the face to use in a font collection
+/// (`.ttc`/`.otc`); `None` selects the first face.
+#[derive(Clone, Eq, PartialEq)]
 pub enum FontSource {
     /// Use a font file from the filesystem
-    File(String),
+    File(String, Option<usize>),
     /// Use a system font by name
     System(String),
     /// Use font data directly from memory
-    Data(Vec<u8>),
+    Data(Vec<u8>, Option<usize>),
+}
+
+impl FontSource {
+    /// Index of the selected face within a font collection
+    fn face_index(&self) -> usize {
+        match self {
+            Self::File(_, index) | Self::Data(_, index) => index.unwrap_or(0),
+            Self::System(_) => 0,
+        }
+    }
+}
+
+/// Font data is summarized by its size instead of listing every byte
+impl fmt::Debug for FontSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path, index) => f.debug_tuple("File").field(path).field(index).finish(),
+            Self::System(name) => f.debug_tuple("System").field(name).finish(),
+            Self::Data(data, index) => write!(f, "Data(<{} bytes>, {:?})", data.len(), index),
+        }
+    }
+}
+
+/// Page margins around a formula in em units; unset sides keep the default spacing
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Margins {
+    pub top: Option<f64>,
+    pub bottom: Option<f64>,
+    pub left: Option<f64>,
+    pub right: Option<f64>,
+}
+
+impl Margins {
+    const DEFAULT_VERTICAL: f64 = 0.455;
+    const DEFAULT_HORIZONTAL: f64 = 0.0;
+
+    /// Format the margins as a Typst dictionary for `#set page(margin: ...)`
+    fn to_typst(self) -> String {
+        format!(
+            "(left: {}em, right: {}em, top: {}em, bottom: {}em)",
+            self.left.unwrap_or(Self::DEFAULT_HORIZONTAL),
+            self.right.unwrap_or(Self::DEFAULT_HORIZONTAL),
+            self.top.unwrap_or(Self::DEFAULT_VERTICAL),
+            self.bottom.unwrap_or(Self::DEFAULT_VERTICAL),
+        )
+    }
+}
+
+/// Pixel size a raster render is scaled to, see [`RenderEngine::render_formula_to_size`].
+/// With both dimensions set the formula keeps its aspect ratio and fits inside the box.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TargetSize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl TargetSize {
+    /// Resolution that scales an area of `width_pt` by `height_pt` to this size, or `None`
+    /// if no dimension is set or the area is empty
+    fn ppi_for(self, width_pt: f64, height_pt: f64) -> Option<f32> {
+        let scale_x = self.width.map(|width| width as f64 / width_pt);
+        let scale_y = self.height.map(|height| height as f64 / height_pt);
+        let scale = match (scale_x, scale_y) {
+            (Some(x), Some(y)) => x.min(y),
+            (Some(scale), None) | (None, Some(scale)) => scale,
+            (None, None) => return None,
+        };
+        (scale.is_finite() && scale > 0.0).then_some((scale * 72.0) as f32)
+    }
+}
+
+/// Settings of the image encoders, see [`RenderEngine::with_encode_options`] and
+/// [`RenderEngine::render_formula_with_encoding`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EncodeOptions {
+    /// JPEG quality from 1 to 100
+    pub jpeg_quality: u8,
+    /// Background that transparent areas are flattened onto for JPEG output
+    pub jpeg_background: Color,
+    /// AVIF quality from 0 to 100
+    pub avif_quality: u8,
+    /// AVIF encoder speed from 1 (smallest output) to 10 (fastest)
+    pub avif_speed: u8,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            jpeg_quality: 90,
+            jpeg_background: Color::WHITE,
+            avif_quality: 80,
+            avif_speed: 6,
+        }
+    }
+}
+
+/// Syntax of the formulas handed to the engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputSyntax {
+    /// LaTeX math, converted to Typst by the mitex package
+    #[default]
+    Latex,
+    /// Native Typst math, evaluated directly without mitex
+    Typst,
+}
+
+/// Direction of the text in formulas, see [`FontConfig::text_dir`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextDir {
+    #[default]
+    Ltr,
+    /// Right to left, for labels in Arabic, Hebrew and other right-to-left scripts
+    Rtl,
+}
+
+impl TextDir {
+    fn to_typst(self) -> &'static str {
+        match self {
+            TextDir::Ltr => "ltr",
+            TextDir::Rtl => "rtl",
+        }
+    }
 }
 
-/// Font configuration for rendering
+/// Font configuration for rendering. Build one with [`FontConfig::builder`] or start from
+/// [`FontConfig::default`] or [`FontConfig::embedded`]; new fields may be added in any
+/// release, so it cannot be written as a struct literal outside this crate.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct FontConfig {
     /// Font used for body text
     pub body_font: FontSource,
     /// Font used for mathematical expressions
     pub math_font: FontSource,
+    /// Fonts tried in order for glyphs the body font lacks
+    pub body_font_fallbacks: Vec<FontSource>,
+    /// Fonts tried in order for glyphs the math font lacks. [`FALLBACK_MATH_FONT`] is
+    /// always tried last, so math renders even when none of these fonts is installed.
+    pub math_font_fallbacks: Vec<FontSource>,
     /// Whether to include system fonts in the search
     pub include_system_fonts: bool,
+    /// Whether the fonts bundled with Typst (New Computer Modern, its math companion,
+    /// Libertinus Serif and DejaVu Sans Mono) can be found by name like system fonts.
+    /// Font files and data are always found by their family names, whatever this says.
+    pub include_embedded_fonts: bool,
+    /// Color of the rendered glyphs, black when unset
+    pub text_color: Option<Color>,
+    /// Solid page color behind the formula, transparent when unset
+    pub background: Option<Color>,
+    /// Whether formulas are written in LaTeX or Typst math syntax
+    pub input_syntax: InputSyntax,
+    /// Direction of the text in formulas, set with `#set text(dir: ...)`
+    pub text_dir: TextDir,
+    /// Version of the mitex package that converts LaTeX input, as `major.minor.patch`;
+    /// see [`FontConfig::check_mitex_version`]
+    pub mitex_version: String,
+    /// Further Typst packages imported by the template, as `@namespace/name:version`
+    /// (e.g. `@preview/whalogen:0.3.0` for chemistry). Their exports can be called from
+    /// formulas in Typst syntax; mitex cannot call Typst functions from LaTeX. They are
+    /// resolved like mitex, so they must be downloadable or in a local package directory.
+    pub extra_imports: Vec<String>,
+    /// NFC-normalize formulas before rendering and, for LaTeX input, replace the symbols
+    /// in [`LATEX_NORMALIZATIONS`], which editors insert but mitex does not understand
+    pub normalize_input: bool,
+    /// Shared definitions (e.g. `\newcommand` macros) prepended to every formula
+    pub preamble: Option<String>,
+    /// Font size in points; one em of the reported dimensions equals this size
+    pub font_size_pt: f64,
+    /// Padding around the formula, included in the reported dimensions
+    pub margin_em: Margins,
+    /// Widest a formula may be, in em; a wider formula is set in a block this wide so
+    /// Typst breaks it into lines at its operators. Unlimited when unset.
+    pub max_width_em: Option<f64>,
+    /// Crop the output to the visible ink, discarding margins and empty space
+    pub trim: bool,
+    /// Minify SVG output by dropping comments and redundant whitespace
+    pub minify_svg: bool,
+    /// Largest raster, in pixels, a formula may need; bigger renders fail instead of
+    /// allocating the pixmap
+    pub max_pixels: u64,
+    /// Retry a formula that fails because of its math font (e.g. a font without math
+    /// tables) once with [`FALLBACK_MATH_FONT`], which is bundled for this purpose, and
+    /// mark the result with [`FormulaRenderResult::used_fallback`]
+    pub retry_with_fallback_font: bool,
+    /// Return raster output of zero width or height as a result with empty `data`,
+    /// which HTML rendering leaves in place, instead of failing with
+    /// [`RenderError::EmptyRender`]
+    pub allow_empty_render: bool,
+    /// Longest a single formula may take to compile before it fails with
+    /// [`RenderError::Timeout`]; unlimited when unset. Typst compilation cannot be
+    /// interrupted, so an abandoned compilation keeps running on its own thread until it
+    /// finishes and its result is discarded; only the caller stops waiting for it.
+    pub formula_timeout: Option<Duration>,
+    /// Never download packages; mitex must already be in `package_dir` or in the local
+    /// Typst package directories
+    pub offline: bool,
+    /// Directory laid out like Typst's package directories (`<namespace>/<name>/<version>`),
+    /// searched before anything else, e.g. to vendor mitex for offline builds
+    pub package_dir: Option<String>,
 }
 
 impl PartialEq for FontConfig {
     fn eq(&self, other: &Self) -> bool {
         self.body_font == other.body_font
             && self.math_font == other.math_font
+            && self.body_font_fallbacks == other.body_font_fallbacks
+            && self.math_font_fallbacks == other.math_font_fallbacks
             && self.include_system_fonts == other.include_system_fonts
+            && self.include_embedded_fonts == other.include_embedded_fonts
+            && self.text_color == other.text_color
+            && self.background == other.background
+            && self.input_syntax == other.input_syntax
+            && self.text_dir == other.text_dir
+            && self.mitex_version == other.mitex_version
+            && self.extra_imports == other.extra_imports
+            && self.normalize_input == other.normalize_input
+            && self.preamble == other.preamble
+            && self.font_size_pt == other.font_size_pt
+            && self.margin_em == other.margin_em
+            && self.max_width_em == other.max_width_em
+            && self.trim == other.trim
+            && self.minify_svg == other.minify_svg
+            && self.max_pixels == other.max_pixels
+            && self.retry_with_fallback_font == other.retry_with_fallback_font
+            && self.allow_empty_render == other.allow_empty_render
+            && self.formula_timeout == other.formula_timeout
+            && self.offline == other.offline
+            && self.package_dir == other.package_dir
+    }
+}
+
+impl FontConfig {
+    /// A builder starting from the default configuration
+    pub fn builder() -> FontConfigBuilder {
+        FontConfigBuilder::default()
+    }
+
+    /// A builder starting from this configuration
+    pub fn into_builder(self) -> FontConfigBuilder {
+        FontConfigBuilder::from(self)
+    }
+
+    /// Every configured font source, primaries first followed by their fallbacks
+    pub fn font_sources(&self) -> impl Iterator<Item = &FontSource> {
+        [&self.body_font, &self.math_font]
+            .into_iter()
+            .chain(&self.body_font_fallbacks)
+            .chain(&self.math_font_fallbacks)
+    }
+
+    /// Check that [`Self::mitex_version`] is a `major.minor.patch` version, the form
+    /// Typst package versions take. Whether that version exists is only known once it is
+    /// loaded, which fails with [`RenderError::PackageUnavailable`].
+    pub fn check_mitex_version(&self) -> Result<()> {
+        if is_package_version(&self.mitex_version) {
+            Ok(())
+        } else {
+            Err(RenderError::InvalidPackageVersion(
+                self.mitex_version.clone(),
+            ))
+        }
+    }
+
+    /// Check that every entry of [`Self::extra_imports`] is a package spec of the form
+    /// `@namespace/name:major.minor.patch`
+    pub fn check_extra_imports(&self) -> Result<()> {
+        for spec in &self.extra_imports {
+            let is_valid = spec
+                .strip_prefix('@')
+                .and_then(|spec| spec.split_once('/'))
+                .and_then(|(namespace, rest)| {
+                    let (name, version) = rest.split_once(':')?;
+                    Some(
+                        is_package_name(namespace)
+                            && is_package_name(name)
+                            && is_package_version(version),
+                    )
+                })
+                .unwrap_or(false);
+            if !is_valid {
+                return Err(RenderError::InvalidPackageSpec(spec.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every font file can be loaded and has the selected face, so a broken or
+    /// unsupported file is reported up front instead of the engine silently rendering with
+    /// another font
+    pub fn check_font_files(&self) -> Result<()> {
+        for source in self.font_sources() {
+            let FontSource::File(path, index) = source else {
+                continue;
+            };
+            let data = load_font_file(path)?;
+            if let Some(index) = *index {
+                let face_count = FontInfo::iter(&data).count();
+                if index >= face_count {
+                    return Err(invalid_font(format!(
+                        "{path} has {face_count} face(s), no face at index {index}"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fonts bundled with the engine (New Computer Modern, its math companion, Libertinus
+    /// Serif and DejaVu Sans Mono) and no system fonts, so output is identical on every
+    /// machine regardless of what is installed. Rendering with a font outside this set
+    /// fails with [`RenderError::FontNotFound`].
+    pub fn embedded() -> Self {
+        let mut math_font = None;
+        let mut body_faces = Vec::new();
+        for data in typst_assets::fonts() {
+            let Some(info) = FontInfo::new(data, 0) else {
+                continue;
+            };
+            if info.family == FALLBACK_MATH_FONT {
+                math_font.get_or_insert(FontSource::Data(data.to_vec(), None));
+            } else {
+                // The regular New Computer Modern face comes first and names the family
+                let is_primary =
+                    info.family == "New Computer Modern" && info.variant == FontVariant::default();
+                let face = FontSource::Data(data.to_vec(), None);
+                if is_primary {
+                    body_faces.insert(0, face);
+                } else {
+                    body_faces.push(face);
+                }
+            }
+        }
+
+        let mut body_faces = body_faces.into_iter();
+        Self {
+            body_font: body_faces
+                .next()
+                .unwrap_or_else(|| FontSource::System("New Computer Modern".to_string())),
+            math_font: math_font
+                .unwrap_or_else(|| FontSource::System(FALLBACK_MATH_FONT.to_string())),
+            body_font_fallbacks: body_faces.collect(),
+            include_system_fonts: false,
+            ..Self::default()
+        }
     }
 }
 
@@ -69,22 +565,293 @@ impl Default for FontConfig {
         Self {
             body_font: FontSource::System("serif".to_string()),
             math_font: FontSource::System("Fira Math".to_string()),
+            body_font_fallbacks: Vec::new(),
+            math_font_fallbacks: Vec::new(),
             include_system_fonts: true,
+            include_embedded_fonts: false,
+            text_color: None,
+            background: None,
+            input_syntax: InputSyntax::Latex,
+            text_dir: TextDir::Ltr,
+            mitex_version: DEFAULT_MITEX_VERSION.to_string(),
+            extra_imports: Vec::new(),
+            normalize_input: false,
+            preamble: None,
+            font_size_pt: 10.0,
+            margin_em: Margins::default(),
+            max_width_em: None,
+            trim: false,
+            minify_svg: false,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            retry_with_fallback_font: false,
+            allow_empty_render: false,
+            formula_timeout: None,
+            offline: false,
+            package_dir: None,
+        }
+    }
+}
+
+/// Builder of a [`FontConfig`], starting from [`FontConfig::default`] or an existing
+/// configuration. Unlike a struct literal, it keeps compiling as fields are added.
+#[derive(Debug, Clone, Default)]
+pub struct FontConfigBuilder {
+    config: FontConfig,
+}
+
+impl FontConfigBuilder {
+    /// Font used for body text
+    pub fn body_font(mut self, body_font: FontSource) -> Self {
+        self.config.body_font = body_font;
+        self
+    }
+
+    /// Font used for mathematical expressions
+    pub fn math_font(mut self, math_font: FontSource) -> Self {
+        self.config.math_font = math_font;
+        self
+    }
+
+    /// Fonts tried in order for glyphs the body font lacks
+    pub fn body_font_fallbacks(mut self, body_font_fallbacks: Vec<FontSource>) -> Self {
+        self.config.body_font_fallbacks = body_font_fallbacks;
+        self
+    }
+
+    /// Fonts tried in order for glyphs the math font lacks
+    pub fn math_font_fallbacks(mut self, math_font_fallbacks: Vec<FontSource>) -> Self {
+        self.config.math_font_fallbacks = math_font_fallbacks;
+        self
+    }
+
+    /// Whether to include system fonts in the search
+    pub fn include_system_fonts(mut self, include_system_fonts: bool) -> Self {
+        self.config.include_system_fonts = include_system_fonts;
+        self
+    }
+
+    /// Whether the fonts bundled with Typst can be found by name
+    pub fn include_embedded_fonts(mut self, include_embedded_fonts: bool) -> Self {
+        self.config.include_embedded_fonts = include_embedded_fonts;
+        self
+    }
+
+    /// Color of the rendered glyphs, black when unset
+    pub fn text_color(mut self, text_color: Option<Color>) -> Self {
+        self.config.text_color = text_color;
+        self
+    }
+
+    /// Direction of the text in formulas
+    pub fn text_dir(mut self, text_dir: TextDir) -> Self {
+        self.config.text_dir = text_dir;
+        self
+    }
+
+    /// Solid page color behind the formula, transparent when unset
+    pub fn background(mut self, background: Option<Color>) -> Self {
+        self.config.background = background;
+        self
+    }
+
+    /// Whether formulas are written in LaTeX or Typst math syntax
+    pub fn input_syntax(mut self, input_syntax: InputSyntax) -> Self {
+        self.config.input_syntax = input_syntax;
+        self
+    }
+
+    /// Version of the mitex package, see [`FontConfig::check_mitex_version`]
+    pub fn mitex_version(mut self, mitex_version: String) -> Self {
+        self.config.mitex_version = mitex_version;
+        self
+    }
+
+    /// Further Typst packages imported by the template, see [`FontConfig::extra_imports`]
+    pub fn extra_imports(mut self, extra_imports: Vec<String>) -> Self {
+        self.config.extra_imports = extra_imports;
+        self
+    }
+
+    /// Normalize formulas before rendering, see [`FontConfig::normalize_input`]
+    pub fn normalize_input(mut self, normalize_input: bool) -> Self {
+        self.config.normalize_input = normalize_input;
+        self
+    }
+
+    /// Shared definitions prepended to every formula
+    pub fn preamble(mut self, preamble: Option<String>) -> Self {
+        self.config.preamble = preamble;
+        self
+    }
+
+    /// Font size in points
+    pub fn font_size_pt(mut self, font_size_pt: f64) -> Self {
+        self.config.font_size_pt = font_size_pt;
+        self
+    }
+
+    /// Padding around the formula
+    pub fn margin_em(mut self, margin_em: Margins) -> Self {
+        self.config.margin_em = margin_em;
+        self
+    }
+
+    /// Widest a formula may be, in em, before it is broken into lines
+    pub fn max_width_em(mut self, max_width_em: Option<f64>) -> Self {
+        self.config.max_width_em = max_width_em;
+        self
+    }
+
+    /// Crop the output to the visible ink
+    pub fn trim(mut self, trim: bool) -> Self {
+        self.config.trim = trim;
+        self
+    }
+
+    /// Minify SVG output
+    pub fn minify_svg(mut self, minify_svg: bool) -> Self {
+        self.config.minify_svg = minify_svg;
+        self
+    }
+
+    /// Largest raster, in pixels, a formula may need
+    pub fn max_pixels(mut self, max_pixels: u64) -> Self {
+        self.config.max_pixels = max_pixels;
+        self
+    }
+
+    /// Retry formulas that fail because of their math font with [`FALLBACK_MATH_FONT`]
+    pub fn retry_with_fallback_font(mut self, retry_with_fallback_font: bool) -> Self {
+        self.config.retry_with_fallback_font = retry_with_fallback_font;
+        self
+    }
+
+    /// Return zero-size raster renders with empty data instead of failing
+    pub fn allow_empty_render(mut self, allow_empty_render: bool) -> Self {
+        self.config.allow_empty_render = allow_empty_render;
+        self
+    }
+
+    /// Longest a single formula may take to compile
+    pub fn formula_timeout(mut self, formula_timeout: Option<Duration>) -> Self {
+        self.config.formula_timeout = formula_timeout;
+        self
+    }
+
+    /// Never download packages
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.config.offline = offline;
+        self
+    }
+
+    /// Directory searched for packages before anything else
+    pub fn package_dir(mut self, package_dir: Option<String>) -> Self {
+        self.config.package_dir = package_dir;
+        self
+    }
+
+    pub fn build(self) -> FontConfig {
+        self.config
+    }
+}
+
+impl From<FontConfig> for FontConfigBuilder {
+    fn from(config: FontConfig) -> Self {
+        Self { config }
+    }
+}
+
+/// Expand a leading `~` to the user's home directory. Paths are returned
+/// unchanged when they don't start with `~` or no home directory is known.
+pub fn expand_tilde(path: &str) -> String {
+    let rest = if path == "~" {
+        ""
+    } else if let Some(rest) = path.strip_prefix("~/").or_else(|| path.strip_prefix("~\\")) {
+        rest
+    } else {
+        return path.to_string();
+    };
+
+    match dirs::home_dir() {
+        Some(home) if rest.is_empty() => home.to_string_lossy().into_owned(),
+        Some(home) => home.join(rest).to_string_lossy().into_owned(),
+        None => path.to_string(),
+    }
+}
+
+/// Math delimiter pairs and whether they mark inline math.
+/// `$$` comes before `$` so the longer delimiter wins.
+const DELIMITERS: &[(&str, &str, bool)] = &[
+    ("$$", "$$", false),
+    ("\\[", "\\]", false),
+    ("\\(", "\\)", true),
+    ("$", "$", true),
+];
+
+/// Strip a pair of math delimiters (`$...$`, `$$...$$`, `\(...\)` or `\[...\]`)
+/// from `text`. Returns the bare formula and whether it is inline math.
+/// Text like `$a$ and $b$`, where the closing delimiter shows up inside, is
+/// left alone.
+pub fn strip_delimiters(text: &str) -> Option<(&str, bool)> {
+    let text = text.trim();
+    DELIMITERS.iter().find_map(|&(open, close, inline)| {
+        let inner = text.strip_prefix(open)?.strip_suffix(close)?.trim();
+        (!inner.is_empty() && !inner.contains(close)).then_some((inner, inline))
+    })
+}
+
+/// Whether an opening or closing delimiter marks inline math.
+/// Anything other than `$$`, `\[` and `\]` is treated as inline.
+pub fn is_inline_delimiter(delimiter: &str) -> bool {
+    !matches!(delimiter, "$$" | "\\[" | "\\]")
+}
+
+/// Whether font data is WOFF2 compressed, which the engine can only load once decompressed
+pub fn is_woff2(font_data: &[u8]) -> bool {
+    font_data.starts_with(b"wOF2")
+}
+
+/// Read a font file for the engine, decompressing WOFF2 to OpenType.
+/// Fails with a message naming the file if it is not a font the engine can load.
+pub fn load_font_file(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(decode_font)
+        .map_err(|e| RenderError::InvalidFont(format!("{}: {}", path, e)))
+}
+
+/// Check that font data is TrueType, OpenType or a collection of them, decompressing
+/// WOFF2 data to OpenType first
+pub fn decode_font_data(font_data: &[u8]) -> Result<Vec<u8>> {
+    decode_font(font_data.to_vec()).map_err(RenderError::InvalidFont)
+}
+
+fn decode_font(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    match data.get(..4) {
+        Some(b"wOF2") => woff2::convert_woff2_to_ttf(&mut data.as_slice())
+            .map_err(|e| format!("failed to decompress WOFF2 font: {}", e)),
+        Some(b"wOFF") => {
+            Err("WOFF fonts are not supported; convert the font to WOFF2, TTF or OTF".to_string())
         }
+        Some(b"\x00\x01\x00\x00" | b"OTTO" | b"true" | b"ttcf") => Ok(data),
+        _ => Err("not a TrueType, OpenType or WOFF2 font".to_string()),
     }
 }
 
 pub fn read_font_names(font_data: &[u8], font_index: usize) -> Result<FontNames> {
-    // Parse the font file (supports OpenType, WOFF, WOFF2, CFF)
+    // Parse the font file: TrueType, OpenType or a collection of them, WOFF or WOFF2. The
+    // engine itself cannot load WOFF fonts, see `decode_font`.
     let scope = ReadScope::new(font_data);
-    let font = scope.read::<FontData<'_>>()?;
+    let font = scope.read::<FontData<'_>>().map_err(invalid_font)?;
 
     // Get table provider for the specified font index (useful for font collections)
-    let provider = font.table_provider(font_index)?;
+    let provider = font.table_provider(font_index).map_err(invalid_font)?;
 
     // Read the NAME table
-    let name_data = provider.read_table_data(tag::NAME)?;
-    let name = ReadScope::new(&name_data).read::<NameTable<'_>>()?;
+    let name_data = provider.read_table_data(tag::NAME).map_err(invalid_font)?;
+    let name = ReadScope::new(&name_data)
+        .read::<NameTable<'_>>()
+        .map_err(invalid_font)?;
 
     // Extract various name types with fallback logic
     let family_name = name
@@ -105,12 +872,50 @@ pub fn read_font_names(font_data: &[u8], font_index: usize) -> Result<FontNames>
     })
 }
 
+/// Subset a font to the given glyph ids, keeping the tables a web font needs.
+/// Glyph 0 (`.notdef`) is always kept, as font consumers expect it to exist.
+pub fn subset_font(font_data: &[u8], font_index: usize, glyph_ids: &[u16]) -> Result<Vec<u8>> {
+    let scope = ReadScope::new(font_data);
+    let font = scope.read::<FontData<'_>>().map_err(invalid_font)?;
+    let provider = font.table_provider(font_index).map_err(invalid_font)?;
+
+    let mut glyphs: Vec<u16> = std::iter::once(0)
+        .chain(glyph_ids.iter().copied())
+        .collect();
+    glyphs.sort_unstable();
+    glyphs.dedup();
+
+    subset(&provider, &glyphs, &SubsetProfile::Web).map_err(invalid_font)
+}
+
 #[derive(Debug, Clone, IntoValue, IntoDict)]
 struct FormulaContent {
     formula: String,
     inline: bool,
+    /// Body font overriding the configured one for this render, empty to keep it
     body_font: String,
+    /// Math font overriding the configured one for this render, empty to keep it
     math_font: String,
+    preamble: String,
+    /// Equation number shown next to a display formula
+    number: Option<usize>,
+}
+
+impl FormulaContent {
+    /// The inputs written as a Typst dictionary literal
+    fn to_typst(&self) -> String {
+        let mut fields = vec![
+            format!("formula: {}", typst_string(&self.formula)),
+            format!("inline: {}", self.inline),
+            format!("body_font: {}", typst_string(&self.body_font)),
+            format!("math_font: {}", typst_string(&self.math_font)),
+            format!("preamble: {}", typst_string(&self.preamble)),
+        ];
+        if let Some(number) = self.number {
+            fields.push(format!("number: {number}"));
+        }
+        format!("({})", fields.join(", "))
+    }
 }
 
 impl From<FormulaContent> for Dict {
@@ -130,81 +935,465 @@ pub struct RenderResult {
     pub height_em: f64,
 }
 
+/// Typst package that converts LaTeX formulas, imported only for LaTeX input
+const MITEX_PACKAGE: &str = "@preview/mitex";
+
+/// Default for [`FontConfig::mitex_version`]
+pub const DEFAULT_MITEX_VERSION: &str = "0.2.5";
+
+/// Default for [`FontConfig::max_pixels`], about 400 MB of RGBA pixels
+pub const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+
+/// Math font bundled with the engine; it ends every math font chain and is used by
+/// [`FontConfig::retry_with_fallback_font`]
+pub const FALLBACK_MATH_FONT: &str = "New Computer Modern Math";
+
+/// Replacements made in LaTeX formulas by [`FontConfig::normalize_input`]. Commands are
+/// followed by a space so they don't run into the next letter.
+pub const LATEX_NORMALIZATIONS: &[(char, &str)] = &[
+    ('\u{2212}', "-"),        // − minus sign
+    ('\u{2013}', "-"),        // – en dash
+    ('\u{00D7}', "\\times "), // ×
+    ('\u{00F7}', "\\div "),   // ÷
+    ('\u{00B7}', "\\cdot "),  // ·
+    ('\u{00B1}', "\\pm "),    // ±
+    ('\u{2264}', "\\leq "),   // ≤
+    ('\u{2265}', "\\geq "),   // ≥
+    ('\u{2260}', "\\neq "),   // ≠
+    ('\u{00A0}', " "),        // no-break space
+];
+
+/// Resolution of raster output when a render does not name one
+pub const DEFAULT_PPI: f32 = 1200.0;
+
+/// Number of rendered formulas kept by a freshly constructed engine
+const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+/// Renders formulas with one font configuration. Building an engine searches fonts and
+/// loads font files, so build it once and share it: rendering takes `&self` and the
+/// engine is `Send + Sync`, so a reference can be used from many threads at once.
 pub struct RenderEngine {
-    engine: TypstEngine<TypstTemplateMainFile>,
+    /// Shared with the threads that compile under [`FontConfig::formula_timeout`]
+    engine: Arc<TypstEngine<TypstTemplateMainFile>>,
     font_config: FontConfig,
+    cache: Option<Mutex<LruCache<u64, FormulaRenderResult>>>,
+    /// Last equation number handed out by [`RenderEngine::render_formula_numbered`]
+    equation_counter: AtomicUsize,
+    /// Format used by [`RenderEngine::render_formula_default`]
+    default_format: RenderFormat,
+    /// Resolution used when a render passes no `ppi`
+    default_ppi: f32,
+    /// Encoder settings used unless a render passes its own
+    encode_options: EncodeOptions,
+    /// Hash of the settings in `font_config` that affect rendered images
+    config_hash: u64,
+    /// Template generated from `font_config`, with the font names already resolved
+    template: String,
 }
 
+// Rendering threads share one engine; keep it usable from all of them
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<RenderEngine>();
+};
+
+#[derive(Debug, Clone)]
 pub struct FormulaRenderResult {
     pub formula: String,
     pub is_inline: bool,
     pub format: RenderFormat,
     pub data: Vec<u8>,
+    /// Width of the formula in points, after trimming if enabled
+    pub x_pt: f64,
+    /// Height of the formula in points, after trimming if enabled
+    pub y_pt: f64,
+    /// Width in em, i.e. `x_pt` divided by [`FontConfig::font_size_pt`]
     pub x_em: f64,
+    /// Height in em, i.e. `y_pt` divided by [`FontConfig::font_size_pt`]
     pub y_em: f64,
+    /// Distance from the text baseline to the bottom of the image in em units.
+    /// Zero for display formulas.
+    pub depth_em: f64,
+    /// The configured math font failed and the formula was rendered with
+    /// [`FALLBACK_MATH_FONT`] instead, see [`FontConfig::retry_with_fallback_font`]
+    pub used_fallback: bool,
+    /// Number of glyphs on the compiled page, a cheap measure of how complex the
+    /// formula is and roughly how long it takes to render
+    pub glyph_count: usize,
+    /// Hash of the formula and every setting it was rendered with, see
+    /// [`RenderEngine::formula_hash`]
+    pub render_hash: u64,
+    /// Non-fatal compiler diagnostics, e.g. for deprecated syntax that a later version may
+    /// reject. Warnings about font families that are not installed are left out; fonts
+    /// requested for a render fail with [`RenderError::FontNotFound`] instead.
+    pub warnings: Vec<FormulaDiagnostic>,
+    /// Original text of the element the formula came from, delimiters included. When set,
+    /// the HTML methods record it in `data-gladst-source` and the env (`math` or
+    /// `displaymath`) in `data-gladst-env`, so the element can be restored later; see
+    /// [`Self::with_source`].
+    pub source: Option<String>,
+    /// Whether the HTML methods record the formula and [`Self::render_hash`] in
+    /// `data-gladst-formula` and `data-gladst-hash` attributes, which let a later run of
+    /// [`html::render_html`] leave the image as it is; see [`Self::with_tracking`].
+    pub tracked: bool,
 }
 
-/// Helper function to format Typst compilation errors with detailed information
-fn format_typst_error(error: &TypstAsLibError, formula: &str) -> String {
+/// A compiled formula page with its measurements
+struct RenderedPage {
+    doc: PagedDocument,
+    /// Rasterized (and possibly trimmed) page, if it was needed
+    pixmap: Option<Pixmap>,
+    /// Visible area of the page in points as (left, top, width, height)
+    bounds_pt: (f64, f64, f64, f64),
+    trimmed: bool,
+    x_em: f64,
+    y_em: f64,
+    depth_em: f64,
+    glyph_count: usize,
+    warnings: Vec<FormulaDiagnostic>,
+}
+
+/// Stack the pages of a formula that broke across pages onto its first page, top to
+/// bottom, so every export sees the whole formula
+fn stack_pages(doc: &mut PagedDocument) {
+    if doc.pages.len() < 2 {
+        return;
+    }
+    let width = doc
+        .pages
+        .iter()
+        .map(|page| page.frame.width())
+        .fold(Abs::zero(), Abs::max);
+    let height = doc.pages.iter().map(|page| page.frame.height()).sum();
+
+    let mut frame = Frame::hard(Size::new(width, height));
+    let mut y = Abs::zero();
+    for page in &doc.pages {
+        frame.push_frame(Point::with_y(y), page.frame.clone());
+        y += page.frame.height();
+    }
+    doc.pages.truncate(1);
+    doc.pages[0].frame = frame;
+}
+
+/// The message of a compile error caused by a package that could not be resolved
+fn package_error(error: &TypstAsLibError) -> Option<String> {
     match error {
-        TypstAsLibError::TypstSource(diagnostics) => {
-            let mut error_msg = "Failed to compile the formula\n".to_string();
+        TypstAsLibError::TypstFile(FileError::Package(error)) => Some(error.to_string()),
+        TypstAsLibError::TypstSource(diagnostics) => diagnostics
+            .iter()
+            .find(|diagnostic| {
+                // e.g. "package not found (...)" or "failed to download package (...)"
+                diagnostic.message.starts_with("package ")
+                    || diagnostic.message.contains(" package (")
+            })
+            .map(|diagnostic| diagnostic.message.to_string()),
+        _ => None,
+    }
+}
 
-            if diagnostics.is_empty() {
-                error_msg.push_str("Compilation failed with unspecified diagnostics.\n");
-                return error_msg;
-            }
+/// How serious a [`FormulaDiagnostic`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
 
-            for (i, diagnostic) in diagnostics.iter().enumerate() {
-                error_msg.push_str(&format!("\n{:?} #{}: ", diagnostic.severity, i + 1));
+/// A message from the Typst compiler about a formula
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormulaDiagnostic {
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+    /// File the diagnostic points into, relative to the project root. `None` for
+    /// formula text that was evaluated at runtime, which has no file of its own.
+    pub path: Option<String>,
+    /// Byte range within `path`, when the compiler recorded one
+    pub range: Option<Range<usize>>,
+    pub hints: Vec<String>,
+    /// Call sites that led to the diagnostic, e.g. "error occurred in this function call: at main.typ"
+    pub trace: Vec<String>,
+}
 
-                // Add the main error message
-                error_msg.push_str(&diagnostic.message);
-                error_msg.push('\n');
+impl FormulaDiagnostic {
+    fn from_source(diagnostic: &SourceDiagnostic) -> Self {
+        let path_of = |span: Span| {
+            span.id()
+                .map(|file_id| file_id.vpath().as_rootless_path().display().to_string())
+        };
 
-                // Add span information
-                let span = diagnostic.span;
-                if let Some(file_id) = span.id() {
-                    let path_repr = file_id.vpath().as_rootless_path().display().to_string();
-                    error_msg.push_str(&format!("  Location: {}\n", path_repr));
-                    // Optionally, include byte range if useful, though it's less user-friendly
-                    // let range = span.range();
-                    // error_msg.push_str(&format!("    Byte Range: {}-{}\n", range.start, range.end));
-                } else {
-                    error_msg.push_str("  Location: No specific source file associated (detached span or nil FileId).\n");
-                }
+        Self {
+            severity: match diagnostic.severity {
+                Severity::Error => DiagnosticSeverity::Error,
+                Severity::Warning => DiagnosticSeverity::Warning,
+            },
+            message: diagnostic.message.to_string(),
+            path: path_of(diagnostic.span),
+            range: diagnostic.span.range(),
+            hints: diagnostic
+                .hints
+                .iter()
+                .map(|hint| hint.to_string())
+                .collect(),
+            trace: diagnostic
+                .trace
+                .iter()
+                .map(|point| {
+                    let location = match path_of(point.span) {
+                        Some(path) => format!("at {}", path),
+                        None => "at detached span or nil FileId".to_string(),
+                    };
+                    format!("{}: {}", point.v, location)
+                })
+                .collect(),
+        }
+    }
+}
 
-                // Add trace information if available
-                if !diagnostic.trace.is_empty() {
-                    error_msg.push_str("  Trace:\n");
-                    for point in &diagnostic.trace {
-                        // point is Spanned<Tracepoint>
-                        // point.v is the Tracepoint enum
-                        // point.span is the Span of this tracepoint
-                        let trace_span_info = if let Some(trace_file_id) = point.span.id() {
-                            format!("at {}", trace_file_id.vpath().as_rootless_path().display())
-                        } else {
-                            "at detached span or nil FileId".to_string()
-                        };
-                        error_msg.push_str(&format!("    - {}: {}\n", point.v, trace_span_info));
-                    }
-                }
+/// A font cut down to the glyphs of one formula, see [`RenderEngine::subset_fonts`]
+#[derive(Debug, Clone)]
+pub struct SubsetFont {
+    pub family: String,
+    /// Font file containing only the used glyphs
+    pub data: Vec<u8>,
+    /// Number of glyphs the formula uses from this font
+    pub glyph_count: usize,
+}
 
-                // Add hints if available
-                if !diagnostic.hints.is_empty() {
-                    error_msg.push_str("  Hints:\n");
-                    for hint in &diagnostic.hints {
-                        error_msg.push_str(&format!("    - {}\n", hint));
-                    }
-                }
-            }
-            error_msg
+/// Result type of the engine's fallible functions
+pub type Result<T, E = RenderError> = std::result::Result<T, E>;
+
+/// Errors returned by the render engine
+#[derive(Debug)]
+pub enum RenderError {
+    /// The formula failed to compile
+    Compile {
+        formula: String,
+        /// Structured compiler diagnostics; empty for errors that carry only a message
+        diagnostics: Vec<FormulaDiagnostic>,
+        message: String,
+    },
+    /// A font requested for a render is not available to the engine
+    FontNotFound(String),
+    /// The mitex package needed for LaTeX input could not be loaded; holds the loader's message
+    PackageUnavailable(String),
+    /// The formula is empty or only whitespace, so there is nothing to render
+    EmptyFormula,
+    /// The formula produced no pixels; holds the formula
+    EmptyRender(String),
+    /// Compilation took longer than [`FontConfig::formula_timeout`]
+    Timeout { formula: String, timeout: Duration },
+    /// The raster would exceed [`FontConfig::max_pixels`]
+    TooLarge {
+        formula: String,
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+    /// The rendered image could not be encoded
+    Encode {
+        format: RenderFormat,
+        formula: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A rendered image could not be written to a file
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Font data could not be parsed
+    InvalidFont(String),
+    /// A color string could not be parsed
+    InvalidColor(String),
+    /// A CSS selector could not be parsed
+    InvalidSelector(String),
+    /// A package version is not of the form `major.minor.patch`
+    InvalidPackageVersion(String),
+    /// A package is not of the form `@namespace/name:major.minor.patch`
+    InvalidPackageSpec(String),
+}
+
+impl RenderError {
+    fn compile(error: &TypstAsLibError, formula: &str) -> Self {
+        let diagnostics = match error {
+            TypstAsLibError::TypstSource(diagnostics) => diagnostics
+                .iter()
+                .map(FormulaDiagnostic::from_source)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let message = format_typst_error(error, &diagnostics, formula);
+
+        Self::Compile {
+            formula: formula.to_string(),
+            diagnostics,
+            message,
         }
-        TypstAsLibError::TypstFile(file_err) => {
-            format!(
-                "File Error while processing formula '{}': {}\n",
-                formula, file_err
-            )
+    }
+
+    fn encode(
+        format: RenderFormat,
+        formula: &str,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> Self {
+        Self::Encode {
+            format,
+            formula: formula.to_string(),
+            source: source.into(),
+        }
+    }
+
+    /// Whether the error comes from a font, e.g. one that was not found or lacks the
+    /// tables math typesetting needs
+    pub fn is_font_error(&self) -> bool {
+        match self {
+            Self::FontNotFound(_) | Self::InvalidFont(_) => true,
+            Self::Compile { diagnostics, .. } => diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.message.contains("font")),
+            _ => false,
+        }
+    }
+
+    /// Compiler diagnostics of a [`RenderError::Compile`]; empty for every other error
+    pub fn diagnostics(&self) -> &[FormulaDiagnostic] {
+        match self {
+            Self::Compile { diagnostics, .. } => diagnostics,
+            _ => &[],
+        }
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compile { message, .. } => f.write_str(message),
+            Self::FontNotFound(font) => write!(
+                f,
+                "Font '{}' is not available to the render engine; add it to the font configuration or install it as a system font",
+                font
+            ),
+            Self::PackageUnavailable(message) => write!(
+                f,
+                "Failed to load the {} package needed for LaTeX input: {}\n\
+                 Check that the mitex version exists, network access and the Typst \
+                 package cache, or use Typst math syntax, which needs no packages",
+                MITEX_PACKAGE, message
+            ),
+            Self::EmptyFormula => f.write_str("Formula is empty"),
+            Self::EmptyRender(formula) => {
+                write!(f, "Formula rendered to an empty image: {}", formula)
+            }
+            Self::Timeout { formula, timeout } => write!(
+                f,
+                "Formula did not compile within {} ms and was abandoned: {}",
+                timeout.as_millis(),
+                formula
+            ),
+            Self::TooLarge {
+                formula,
+                width,
+                height,
+                max_pixels,
+            } => write!(
+                f,
+                "Formula would render to {}x{} pixels, more than the limit of {}; lower the ppi or raise the limit: {}",
+                width, height, max_pixels, formula
+            ),
+            Self::Encode {
+                format, formula, ..
+            } => {
+                let name = match format {
+                    RenderFormat::Png => "PNG",
+                    RenderFormat::Svg => "SVG",
+                    RenderFormat::Jpeg => "JPEG",
+                    RenderFormat::WebP => "WebP",
+                    RenderFormat::Avif => "AVIF",
+                };
+                write!(f, "Failed to encode {} for formula: {}", name, formula)
+            }
+            Self::Write { path, source } => {
+                write!(f, "Failed to write {}: {}", path.display(), source)
+            }
+            Self::InvalidFont(message) => write!(f, "Failed to read font data: {}", message),
+            Self::InvalidColor(message) => f.write_str(message),
+            Self::InvalidSelector(message) => write!(f, "Invalid CSS selector {}", message),
+            Self::InvalidPackageVersion(version) => write!(
+                f,
+                "Invalid package version {:?}, expected major.minor.patch (e.g. {})",
+                version, DEFAULT_MITEX_VERSION
+            ),
+            Self::InvalidPackageSpec(spec) => write!(
+                f,
+                "Invalid package {:?}, expected @namespace/name:major.minor.patch",
+                spec
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Encode { source, .. } => Some(source.as_ref()),
+            Self::Write { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_font(error: impl fmt::Display) -> RenderError {
+    RenderError::InvalidFont(error.to_string())
+}
+
+/// Helper function to format Typst compilation errors with detailed information.
+/// `diagnostics` are the structured form of a `TypstSource` error.
+fn format_typst_error(
+    error: &TypstAsLibError,
+    diagnostics: &[FormulaDiagnostic],
+    formula: &str,
+) -> String {
+    match error {
+        TypstAsLibError::TypstSource(_) => {
+            let mut error_msg = "Failed to compile the formula\n".to_string();
+
+            if diagnostics.is_empty() {
+                error_msg.push_str("Compilation failed with unspecified diagnostics.\n");
+                return error_msg;
+            }
+
+            for (i, diagnostic) in diagnostics.iter().enumerate() {
+                error_msg.push_str(&format!("\n{:?} #{}: ", diagnostic.severity, i + 1));
+                error_msg.push_str(&diagnostic.message);
+                error_msg.push('\n');
+
+                match &diagnostic.path {
+                    Some(path) => error_msg.push_str(&format!("  Location: {}\n", path)),
+                    None => error_msg.push_str(
+                        "  Location: No specific source file associated (detached span or nil FileId).\n",
+                    ),
+                }
+
+                if !diagnostic.trace.is_empty() {
+                    error_msg.push_str("  Trace:\n");
+                    for point in &diagnostic.trace {
+                        error_msg.push_str(&format!("    - {}\n", point));
+                    }
+                }
+
+                if !diagnostic.hints.is_empty() {
+                    error_msg.push_str("  Hints:\n");
+                    for hint in &diagnostic.hints {
+                        error_msg.push_str(&format!("    - {}\n", hint));
+                    }
+                }
+            }
+            error_msg
+        }
+        TypstAsLibError::TypstFile(file_err) => {
+            format!(
+                "File Error while processing formula '{}': {}\n",
+                formula, file_err
+            )
         }
         TypstAsLibError::MainSourceFileDoesNotExist(file_id) => {
             // Here file_id is FileId, not Option<FileId>, so direct usage is correct.
@@ -241,44 +1430,104 @@ impl RenderEngine {
         Self::with_font_config(FontConfig::default())
     }
 
+    /// Create a render engine that only uses the fonts bundled with it.
+    /// See [`FontConfig::embedded`].
+    pub fn new_embedded() -> Self {
+        Self::with_font_config(FontConfig::embedded())
+    }
+
+    /// Create a render engine with default fonts that never downloads packages.
+    /// See [`FontConfig::offline`].
+    pub fn new_offline() -> Self {
+        Self::with_font_config(FontConfig {
+            offline: true,
+            ..FontConfig::default()
+        })
+    }
+
     /// Create a new render engine with custom font configuration
     pub fn with_font_config(font_config: FontConfig) -> Self {
-        let source = Self::generate_template(&font_config);
-
-        let mut engine_builder = TypstEngine::builder()
-            .main_file(source)
-            .with_package_file_resolver();
+        // Read every font file once; the data is both handed to the engine and searched
+        // for the family names the template refers to, so renders never touch the files
+        let font_files: HashMap<&str, Vec<u8>> = font_config
+            .font_sources()
+            .filter_map(|source| match source {
+                FontSource::File(path, _) => match load_font_file(path) {
+                    Ok(data) => Some((path.as_str(), data)),
+                    Err(error) => {
+                        log::warn!("{error}");
+                        None
+                    }
+                },
+                _ => None,
+            })
+            .collect();
+        let template = Self::generate_template(&font_config, &font_files);
+        log::debug!("Generated Typst template:\n{template}");
+
+        let mut engine_builder = TypstEngine::builder().main_file(template.clone());
+
+        // Only LaTeX input (mitex) and extra imports need packages; plain Typst math needs
+        // no package resolver and therefore no network or package cache access
+        if font_config.input_syntax == InputSyntax::Latex || !font_config.extra_imports.is_empty() {
+            for root in Self::local_package_roots(&font_config) {
+                engine_builder = engine_builder.add_file_resolver(
+                    FileSystemResolver::new(root.clone()).local_package_root(root),
+                );
+            }
+            if !font_config.offline {
+                engine_builder = engine_builder.with_package_file_resolver();
+            }
+        }
 
         // Configure font search options
         let font_options = TypstKitFontOptions::default()
             .include_system_fonts(font_config.include_system_fonts)
-            .include_embedded_fonts(false);
+            .include_embedded_fonts(font_config.include_embedded_fonts);
 
         // Apply font search configuration
         engine_builder = engine_builder.search_fonts_with(font_options);
 
-        // Collect additional font data for the engine
-        let mut font_data = Vec::new();
-
-        // Only add Data fonts to the engine's font collection
-        // System and File fonts will be handled by the font search mechanism
-        if let FontSource::Data(data) = &font_config.body_font {
-            font_data.push(data.as_slice());
-        }
-        if let FontSource::Data(data) = &font_config.math_font {
-            font_data.push(data.as_slice());
-        }
-
-        // Load font files if specified and add them to the font collection
-        if let FontSource::File(path) = &font_config.body_font {
-            if let Ok(data) = std::fs::read(path) {
-                font_data.push(Box::leak(data.into_boxed_slice()));
-            }
-        }
-        if let FontSource::File(path) = &font_config.math_font {
-            if let Ok(data) = std::fs::read(path) {
-                font_data.push(Box::leak(data.into_boxed_slice()));
-            }
+        // Decompress WOFF2 data up front so the engine borrows buffers owned by this scope
+        // instead of leaking them; the builder copies the bytes it needs.
+        // Fonts that fail to load are reported by `FontConfig::check_font_files`.
+        let owned_font_data: Vec<Vec<u8>> = font_config
+            .font_sources()
+            .filter_map(|source| match source {
+                FontSource::Data(data, _) if is_woff2(data) => decode_font_data(data).ok(),
+                _ => None,
+            })
+            .collect();
+
+        // Add Data fonts that need no decompression as they are;
+        // System fonts will be handled by the font search mechanism
+        let mut font_data: Vec<&[u8]> = font_config
+            .font_sources()
+            .filter_map(|source| match source {
+                FontSource::Data(data, _) if !is_woff2(data) => Some(data.as_slice()),
+                _ => None,
+            })
+            .collect();
+
+        // Add the loaded font files to the font collection
+        font_data.extend(
+            font_config
+                .font_sources()
+                .filter_map(|source| match source {
+                    FontSource::File(path, _) => font_files.get(path.as_str()),
+                    _ => None,
+                })
+                .map(Vec::as_slice),
+        );
+        font_data.extend(owned_font_data.iter().map(Vec::as_slice));
+
+        // The fallback math font ends every math font chain and backs the retry, so it
+        // must be available whatever fonts are installed; the font search already has it
+        // when it includes the embedded fonts
+        if !font_config.include_embedded_fonts {
+            font_data.extend(typst_assets::fonts().filter(|data| {
+                FontInfo::new(data, 0).is_some_and(|info| info.family == FALLBACK_MATH_FONT)
+            }));
         }
 
         // Add collected font data to the engine if any
@@ -286,57 +1535,284 @@ impl RenderEngine {
             engine_builder = engine_builder.fonts(font_data);
         }
 
-        let engine = engine_builder.build();
+        let engine = Arc::new(engine_builder.build());
+        let config_hash = Self::hash_config(&font_config, &template);
 
         Self {
             engine,
+            config_hash,
+            template,
             font_config,
+            cache: None,
+            equation_counter: AtomicUsize::new(0),
+            default_format: RenderFormat::Svg,
+            default_ppi: DEFAULT_PPI,
+            encode_options: EncodeOptions::default(),
         }
+        .with_cache_capacity(DEFAULT_CACHE_CAPACITY)
     }
 
-    /// Generate the Typst template based on font configuration
-    fn generate_template(font_config: &FontConfig) -> String {
-        let body_font = Self::font_source_to_typst_name(&font_config.body_font);
-        let math_font = Self::font_source_to_typst_name(&font_config.math_font);
+    /// Existing directories that packages are loaded from without downloading: the
+    /// configured `package_dir`, then in offline mode Typst's local and cached packages
+    fn local_package_roots(font_config: &FontConfig) -> Vec<PathBuf> {
+        let configured = font_config
+            .package_dir
+            .as_deref()
+            .map(|dir| PathBuf::from(expand_tilde(dir)));
+        let system = [dirs::data_dir(), dirs::cache_dir()]
+            .into_iter()
+            .flatten()
+            .map(|dir| dir.join("typst").join("packages"))
+            .filter(|_| font_config.offline);
+
+        configured
+            .into_iter()
+            .chain(system)
+            .filter(|dir| dir.is_dir())
+            .collect()
+    }
 
-        format!(
-            r#"#import sys: inputs
-#import "@preview/mitex:0.2.5": *
+    /// Bound the render cache to `capacity` formulas, evicting the least recently used.
+    /// A capacity of zero disables caching.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache =
+            NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity)));
+        self
+    }
+
+    /// Render with `format` when no format is given, see [`Self::render_formula_default`]
+    pub fn with_default_format(mut self, format: RenderFormat) -> Self {
+        self.default_format = format;
+        self
+    }
+
+    /// Render raster images at `ppi` when a render passes `None`, instead of [`DEFAULT_PPI`]
+    pub fn with_default_ppi(mut self, ppi: f32) -> Self {
+        self.default_ppi = ppi;
+        self
+    }
+
+    /// Encode raster images with `options` unless a render passes its own, see
+    /// [`Self::render_formula_with_encoding`]
+    pub fn with_encode_options(mut self, options: EncodeOptions) -> Self {
+        self.encode_options = options;
+        self
+    }
+
+    /// Format used when a render names none
+    pub fn default_format(&self) -> RenderFormat {
+        self.default_format
+    }
+
+    /// Resolution used when a render passes no `ppi`
+    pub fn default_ppi(&self) -> f32 {
+        self.default_ppi
+    }
 
-{}
-#set page(fill: none, width: auto, height: auto, margin: (left: 0pt, right: 0pt, top: 0.455em, bottom: 0.455em))
-{}
+    /// Encoder settings used when a render passes none
+    pub fn encode_options(&self) -> &EncodeOptions {
+        &self.encode_options
+    }
+
+    /// Free the compiler's memoized intermediate results, which otherwise grow with every
+    /// distinct formula compiled. Later renders recompute what they need. The memo is
+    /// shared by all engines in the process.
+    pub fn evict_compiler_cache(&self) {
+        comemo::evict(0);
+    }
+
+    /// Drop every cached render result
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    fn cache_capacity(&self) -> usize {
+        self.cache
+            .as_ref()
+            .map_or(0, |cache| cache.lock().unwrap().cap().get())
+    }
 
-#let content = inputs.formula
+    /// Hash the settings that change rendered images: the template holds the fonts, colors,
+    /// size and margins, the rest is applied around compilation
+    fn hash_config(font_config: &FontConfig, template: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        template.hash(&mut hasher);
+        font_config.preamble.hash(&mut hasher);
+        font_config.include_system_fonts.hash(&mut hasher);
+        font_config.include_embedded_fonts.hash(&mut hasher);
+        font_config.normalize_input.hash(&mut hasher);
+        font_config.trim.hash(&mut hasher);
+        font_config.minify_svg.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Generate the Typst template based on font configuration, naming file fonts by the
+    /// family in their data in `font_files`
+    fn generate_template(font_config: &FontConfig, font_files: &HashMap<&str, Vec<u8>>) -> String {
+        let body_font = Self::font_chain_to_typst(
+            &font_config.body_font,
+            &font_config.body_font_fallbacks,
+            font_files,
+        );
+        // A missing math font (e.g. the default Fira Math) falls through to the bundled one
+        // instead of leaving the formula to whatever fonts Typst can find
+        let bundled_math_font = FontSource::System(FALLBACK_MATH_FONT.to_string());
+        let math_font = Self::font_chain_to_typst(
+            &font_config.math_font,
+            font_config
+                .math_font_fallbacks
+                .iter()
+                .chain([&bundled_math_font]),
+            font_files,
+        );
+        let font_size = font_config.font_size_pt;
+
+        // `formula(wrap)` typesets the formula; inline math is boxed, which keeps its
+        // baseline, unless it has to wrap
+        let (imports, body) = match font_config.input_syntax {
+            InputSyntax::Latex => (
+                format!(
+                    "#import {}: *\n",
+                    typst_string(&format!("{MITEX_PACKAGE}:{}", font_config.mitex_version))
+                ),
+                r#"#let formula(wrap) = if not inline {
+  mitex(content, numbering: equation-numbering)
+} else if wrap {
+  mi(content)
+} else {
+  box(mi(content))
+}"#,
+            ),
+            InputSyntax::Typst => (
+                String::new(),
+                r#"#let formula(wrap) = {
+  let equation = math.equation(
+    block: not inline,
+    numbering: equation-numbering,
+    eval(content, mode: "math", scope: extra-scope),
+  )
+  if inline and not wrap { box(equation) } else { equation }
+}"#,
+            ),
+        };
+
+        // Every extra package is imported as a module, whose exports make up the scope
+        // formulas in Typst syntax are evaluated in
+        let mut extra_imports = String::new();
+        let mut extra_scope = "(:)".to_string();
+        for (index, spec) in font_config.extra_imports.iter().enumerate() {
+            extra_imports.push_str(&format!(
+                "#import {} as extra-package-{index}\n",
+                typst_string(spec)
+            ));
+            extra_scope.push_str(&format!(" + dictionary(extra-package-{index})"));
+        }
+
+        format!(
+            r#"#import sys: inputs
+{imports}
+{extra_imports}#let extra-scope = {extra_scope}
+{body_font_rule}
+#set text(font: inputs.body_font) if inputs.body_font != ""
+#set page(fill: {background}, width: auto, height: auto, margin: {margins})
+{math_font_rule}
+#show math.equation: it => {{
+  set text(font: inputs.math_font) if inputs.math_font != ""
+  it
+}}
+{text_color_rule}
+{text_dir_rule}
+
+#let content = inputs.preamble + inputs.formula
 #let inline = inputs.inline
 
-#if inline [
-  #mi(content)
-] else [
-  #mitex(content)
-]"#,
-            if !body_font.is_empty() {
-                format!("#set text(font: \"{body_font}\", size: 10pt)")
-            } else {
-                "#set text(size: 10pt)".to_string()
+#let number = inputs.at("number", default: none)
+#let equation-numbering = if number != none {{ "(1)" }} else {{ none }}
+#if number != none {{
+  counter(math.equation).update(number - 1)
+}}
+
+{body}
+
+#let max-width = {max_width}
+#if max-width == none {{
+  formula(false)
+}} else {{
+  context {{
+    let limit = max-width.to-absolute()
+    if measure(formula(false)).width <= limit {{
+      formula(false)
+    }} else {{
+      block(width: limit, formula(true))
+    }}
+  }}
+}}"#,
+            max_width = match font_config.max_width_em {
+                Some(width) => format!("{width}em"),
+                None => "none".to_string(),
             },
-            if !math_font.is_empty() {
-                format!("#show math.equation: set text(font: \"{math_font}\")")
-            } else {
-                "".to_string()
+            body_font_rule = match body_font {
+                Some(body_font) => format!("#set text(font: {body_font}, size: {font_size}pt)"),
+                None => format!("#set text(size: {font_size}pt)"),
+            },
+            math_font_rule = match math_font {
+                Some(math_font) => format!("#show math.equation: set text(font: {math_font})"),
+                None => "".to_string(),
+            },
+            margins = font_config.margin_em.to_typst(),
+            background = match font_config.background {
+                Some(color) => color.to_typst(),
+                None => "none".to_string(),
+            },
+            text_color_rule = match font_config.text_color {
+                Some(color) => format!("#set text(fill: {})", color.to_typst()),
+                None => "".to_string(),
+            },
+            text_dir_rule = match font_config.text_dir {
+                TextDir::Ltr => "".to_string(),
+                dir => format!("#set text(dir: {})", dir.to_typst()),
             },
         )
     }
 
-    /// Convert FontSource to Typst font name
-    fn font_source_to_typst_name(font_source: &FontSource) -> String {
+    /// Convert a primary font and its fallbacks into a Typst font value, either a single
+    /// string or an array of strings in priority order. Returns `None` if no names resolve.
+    fn font_chain_to_typst<'a>(
+        primary: &'a FontSource,
+        fallbacks: impl IntoIterator<Item = &'a FontSource>,
+        font_files: &HashMap<&str, Vec<u8>>,
+    ) -> Option<String> {
+        // Several faces of one family name it only once
+        let mut seen = HashSet::new();
+        let names: Vec<String> = std::iter::once(primary)
+            .chain(fallbacks)
+            .map(|source| Self::font_source_to_typst_name(source, font_files))
+            .filter(|name| !name.is_empty() && seen.insert(name.clone()))
+            .map(|name| typst_string(&name))
+            .collect();
+
+        match names.len() {
+            0 => None,
+            1 => names.into_iter().next(),
+            _ => Some(format!("({})", names.join(", "))),
+        }
+    }
+
+    /// Convert FontSource to Typst font name, reading file fonts from the data loaded
+    /// into `font_files`
+    fn font_source_to_typst_name(
+        font_source: &FontSource,
+        font_files: &HashMap<&str, Vec<u8>>,
+    ) -> String {
         match font_source {
             FontSource::System(name) => name.clone(),
-            FontSource::File(path) => {
+            FontSource::File(path, _) => {
                 // For file fonts, try to extract the actual font name from the file
                 // If that fails, fall back to using the filename
-                if let Ok(font_data) = std::fs::read(path) {
-                    if let Ok(font_names) = read_font_names(&font_data, 0) {
+                if let Some(font_data) = font_files.get(path.as_str()) {
+                    if let Ok(font_names) = read_font_names(font_data, font_source.face_index()) {
                         if let Some(family_name) = font_names.family_name {
                             return family_name;
                         }
@@ -350,9 +1826,9 @@ impl RenderEngine {
                     .unwrap_or("serif")
                     .to_string()
             }
-            FontSource::Data(data) => {
+            FontSource::Data(data, _) => {
                 // For data fonts, try to extract the actual font name
-                if let Ok(font_names) = read_font_names(data, 0) {
+                if let Ok(font_names) = read_font_names(data, font_source.face_index()) {
                     if let Some(family_name) = font_names.family_name {
                         return family_name;
                     }
@@ -364,7 +1840,14 @@ impl RenderEngine {
 
     /// Update the font configuration and rebuild the engine
     pub fn set_font_config(&mut self, font_config: FontConfig) -> Result<()> {
-        *self = Self::with_font_config(font_config);
+        let capacity = self.cache_capacity();
+        let equation_counter = self.equation_counter.load(Ordering::Relaxed);
+        *self = Self::with_font_config(font_config)
+            .with_cache_capacity(capacity)
+            .with_default_format(self.default_format)
+            .with_default_ppi(self.default_ppi)
+            .with_encode_options(self.encode_options);
+        self.equation_counter = AtomicUsize::new(equation_counter);
         Ok(())
     }
 
@@ -373,6 +1856,16 @@ impl RenderEngine {
         &self.font_config
     }
 
+    /// Render a formula with the engine's default format and resolution, see
+    /// [`Self::with_default_format`] and [`Self::with_default_ppi`]
+    pub fn render_formula_default(
+        &self,
+        formula: &str,
+        is_inline: bool,
+    ) -> Result<FormulaRenderResult> {
+        self.render_formula(formula, is_inline, self.default_format, None)
+    }
+
     pub fn render_formula(
         &self,
         formula: &str,
@@ -380,61 +1873,98 @@ impl RenderEngine {
         format: RenderFormat,
         ppi: Option<f32>,
     ) -> Result<FormulaRenderResult> {
-        let content = FormulaContent {
-            formula: formula.to_string(),
-            inline: is_inline,
-            body_font: Self::font_source_to_typst_name(&self.font_config.body_font),
-            math_font: Self::font_source_to_typst_name(&self.font_config.math_font),
-        };
-
-        let ppi = ppi.unwrap_or(1200.0);
-
-        let result = self.engine.compile_with_input(content);
-
-        let doc: PagedDocument = match result.output {
-            Ok(doc) => doc,
-            Err(error) => {
-                let error_details = format_typst_error(&error, formula);
-                return Err(anyhow::anyhow!("{}", error_details));
-            }
-        };
+        self.render_formula_with_fonts(formula, is_inline, format, ppi, None, None)
+    }
 
-        let page = &doc.pages[0];
-        let size = page.frame.size();
-        let x_pt = size.x.to_pt();
-        let y_pt = size.y.to_pt();
-        const EM_TO_PT: f64 = 10.0;
-        let x_em = x_pt / EM_TO_PT;
-        let y_em = y_pt / EM_TO_PT;
+    /// Render a formula like [`Self::render_formula`], encoding raster images with
+    /// `options` instead of the engine's [`Self::encode_options`]
+    pub fn render_formula_with_encoding(
+        &self,
+        formula: &str,
+        is_inline: bool,
+        format: RenderFormat,
+        ppi: Option<f32>,
+        options: &EncodeOptions,
+    ) -> Result<FormulaRenderResult> {
+        self.render_content(
+            self.formula_content(formula, is_inline),
+            format,
+            ppi,
+            options,
+        )
+    }
 
-        let data = match format {
-            RenderFormat::Svg => typst_svg::svg(page).into_bytes(),
-            RenderFormat::Png => {
-                let pixel_width = (size.x.to_pt() * ppi as f64 / 72.0).round() as u32;
-                let pixel_height = (size.y.to_pt() * ppi as f64 / 72.0).round() as u32;
+    /// Render a formula like [`Self::render_formula`] and write its image to `path`, which
+    /// is used as given; pick an extension matching `format`. The result is returned for
+    /// its dimensions, e.g. to build markup pointing at the file.
+    pub fn render_formula_to_file(
+        &self,
+        formula: &str,
+        is_inline: bool,
+        format: RenderFormat,
+        ppi: Option<f32>,
+        path: &Path,
+    ) -> Result<FormulaRenderResult> {
+        let result = self.render_formula(formula, is_inline, format, ppi)?;
+        std::fs::write(path, &result.data).map_err(|source| RenderError::Write {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Ok(result)
+    }
 
-                if pixel_width == 0 || pixel_height == 0 {
-                    vec![]
-                } else {
-                    let pixmap = typst_render::render(page, ppi / 72.0);
-                    pixmap
-                        .encode_png()
-                        .with_context(|| format!("Failed to encode PNG for formula: {}", formula))?
-                }
-            }
+    /// Hash of a formula together with every setting of this engine that affects its image.
+    /// A result rendered from the same inputs carries the same
+    /// [`FormulaRenderResult::render_hash`], so a document rendered earlier can be checked
+    /// for formulas that need rendering again without rendering them.
+    pub fn formula_hash(
+        &self,
+        formula: &str,
+        is_inline: bool,
+        number: Option<usize>,
+        format: RenderFormat,
+        ppi: Option<f32>,
+    ) -> u64 {
+        let content = FormulaContent {
+            number,
+            ..self.formula_content(formula, is_inline)
         };
-
-        Ok(FormulaRenderResult {
-            formula: formula.to_string(),
-            is_inline,
+        self.cache_key(
+            &content,
             format,
-            data,
-            x_em,
-            y_em,
-        })
+            ppi.unwrap_or(self.default_ppi),
+            &self.encode_options,
+        )
+    }
+
+    /// Render a formula to several formats from a single compilation.
+    ///
+    /// Compiling is the expensive step, so exporting e.g. both SVG and PNG this way costs
+    /// little more than one of them. The results are in the order of `formats`.
+    pub fn render_formula_multi(
+        &self,
+        formula: &str,
+        is_inline: bool,
+        formats: &[RenderFormat],
+        ppi: Option<f32>,
+    ) -> Result<Vec<FormulaRenderResult>> {
+        if formats.is_empty() {
+            return Ok(vec![]);
+        }
+        self.render_content_multi(
+            self.formula_content(formula, is_inline),
+            formats,
+            ppi,
+            &self.encode_options,
+        )
     }
 
-    /// Render formula with custom fonts for this specific render
+    /// Render formula with custom fonts for this specific render.
+    ///
+    /// The fonts are looked up by family name among the fonts the engine was built
+    /// with, i.e. the configured fonts and, if enabled, the system fonts. Naming a
+    /// font the engine cannot find is an error rather than a silent substitution;
+    /// to use a font file, add it to the [`FontConfig`] instead.
     pub fn render_formula_with_fonts(
         &self,
         formula: &str,
@@ -445,96 +1975,1600 @@ impl RenderEngine {
         math_font: Option<&str>,
     ) -> Result<FormulaRenderResult> {
         let content = FormulaContent {
-            formula: formula.to_string(),
-            inline: is_inline,
-            body_font: body_font
-                .unwrap_or(&Self::font_source_to_typst_name(
-                    &self.font_config.body_font,
-                ))
-                .to_string(),
-            math_font: math_font
-                .unwrap_or(&Self::font_source_to_typst_name(
-                    &self.font_config.math_font,
-                ))
-                .to_string(),
+            body_font: body_font.unwrap_or_default().to_string(),
+            math_font: math_font.unwrap_or_default().to_string(),
+            ..self.formula_content(formula, is_inline)
         };
 
-        let ppi = ppi.unwrap_or(1200.0);
-
-        let result = self.engine.compile_with_input(content);
+        self.render_content(content, format, ppi, &self.encode_options)
+    }
 
-        let doc: PagedDocument = match result.output {
-            Ok(doc) => doc,
-            Err(error) => {
-                let error_details = format_typst_error(&error, formula);
-                return Err(anyhow::anyhow!("{}", error_details));
-            }
+    /// Render a display formula followed by a right-aligned equation number like `(1)`.
+    /// Without an explicit `number`, the engine's counter supplies the next one.
+    pub fn render_formula_numbered(
+        &self,
+        formula: &str,
+        format: RenderFormat,
+        ppi: Option<f32>,
+        number: Option<usize>,
+    ) -> Result<FormulaRenderResult> {
+        // Don't use up a number on a formula that will not be shown
+        if formula.trim().is_empty() {
+            return Err(RenderError::EmptyFormula);
+        }
+        let number =
+            number.unwrap_or_else(|| self.equation_counter.fetch_add(1, Ordering::Relaxed) + 1);
+        let content = FormulaContent {
+            number: Some(number),
+            ..self.formula_content(formula, false)
         };
 
-        let page = &doc.pages[0];
-        let size = page.frame.size();
-        let x_pt = size.x.to_pt();
-        let y_pt = size.y.to_pt();
-        const EM_TO_PT: f64 = 10.0;
-        let x_em = x_pt / EM_TO_PT;
-        let y_em = y_pt / EM_TO_PT;
+        self.render_content(content, format, ppi, &self.encode_options)
+    }
 
-        let data = match format {
-            RenderFormat::Svg => typst_svg::svg(page).into_bytes(),
-            RenderFormat::Png => {
-                let pixel_width = (size.x.to_pt() * ppi as f64 / 72.0).round() as u32;
-                let pixel_height = (size.y.to_pt() * ppi as f64 / 72.0).round() as u32;
+    /// Render a formula scaled to a pixel size instead of a fixed ppi, e.g. to give every
+    /// formula the same width. The resolution is derived from the formula's size in points
+    /// (after trimming, if enabled), so the raster is `size` wide or high; `x_em` and `y_em`
+    /// still report the formula's size in em. SVG output is unaffected by `size`.
+    pub fn render_formula_to_size(
+        &self,
+        formula: &str,
+        is_inline: bool,
+        format: RenderFormat,
+        size: TargetSize,
+    ) -> Result<FormulaRenderResult> {
+        let content = self.formula_content(formula, is_inline);
 
-                if pixel_width == 0 || pixel_height == 0 {
-                    vec![]
-                } else {
-                    let pixmap = typst_render::render(page, ppi / 72.0);
-                    pixmap
-                        .encode_png()
-                        .with_context(|| format!("Failed to encode PNG for formula: {}", formula))?
-                }
-            }
-        };
+        // Measure first; trimming finds the ink at the default resolution, which keeps
+        // the final size within a pixel of the target
+        let RenderedPage {
+            bounds_pt: (_, _, x_pt, y_pt),
+            ..
+        } = self.render_page(content.clone(), DEFAULT_PPI, false)?;
 
-        Ok(FormulaRenderResult {
-            formula: formula.to_string(),
-            is_inline,
+        self.render_content(
+            content,
             format,
-            data,
-            x_em,
-            y_em,
-        })
+            size.ppi_for(x_pt, y_pt),
+            &self.encode_options,
+        )
     }
-}
 
-/// Implements the Default trait for RenderEngine.
-impl Default for RenderEngine {
-    fn default() -> Self {
-        Self::new()
+    /// Restart automatic equation numbering at `(1)`, e.g. at the start of a document
+    pub fn reset_equation_counter(&self) {
+        self.equation_counter.store(0, Ordering::Relaxed);
     }
-}
 
-impl FormulaRenderResult {
-    pub fn to_html(&self) -> String {
-        let mime_type = match self.format {
-            RenderFormat::Svg => "image/svg+xml",
-            RenderFormat::Png => "image/png",
+    /// The complete Typst source compiled for a formula, with its inputs written into the
+    /// template instead of passed through `sys.inputs`. It compiles on its own, e.g. in the
+    /// Typst web app, which makes it useful for debugging a formula that fails to render.
+    pub fn generate_source_for(&self, formula: &str, is_inline: bool) -> String {
+        self.content_source(&self.formula_content(formula, is_inline))
+    }
+
+    /// Like [`Self::generate_source_for`], for a display formula numbered `number`
+    pub fn generate_numbered_source_for(&self, formula: &str, number: usize) -> String {
+        let content = FormulaContent {
+            number: Some(number),
+            ..self.formula_content(formula, false)
         };
-        let b64 = general_purpose::STANDARD.encode(&self.data);
-        let formula_escaped = encode_text(&self.formula);
+        self.content_source(&content)
+    }
 
-        format!(
-            r#"<img class="gladst {env}" style="width: {x_em:.4}em; height: {y_em:.4}em; vertical-align: middle;" src="data:{mime_type};base64,{b64}" alt="{formula_escaped}"/>"#,
-            env = if self.is_inline {
-                "math"
-            } else {
-                "displaymath"
-            },
-            x_em = self.x_em,
-            y_em = self.y_em,
-            mime_type = mime_type,
-            b64 = b64,
-            formula_escaped = formula_escaped
+    fn content_source(&self, content: &FormulaContent) -> String {
+        self.template.replacen(
+            "#import sys: inputs",
+            &format!("#let inputs = {}", content.to_typst()),
+            1,
         )
     }
+
+    /// Template inputs for a formula rendered with the configured fonts
+    fn formula_content(&self, formula: &str, is_inline: bool) -> FormulaContent {
+        let formula = if self.font_config.normalize_input {
+            normalize_formula(formula, self.font_config.input_syntax)
+        } else {
+            formula.to_string()
+        };
+
+        FormulaContent {
+            formula,
+            inline: is_inline,
+            body_font: String::new(),
+            math_font: String::new(),
+            preamble: self.preamble_source(),
+            number: None,
+        }
+    }
+
+    /// Family names of every font the engine can use: the configured font files and data,
+    /// plus the system and embedded fonts when the configuration includes them. Sorted and
+    /// deduplicated.
+    pub fn available_fonts(&self) -> Vec<String> {
+        let mut families = Vec::new();
+
+        if self.font_config.include_system_fonts || self.font_config.include_embedded_fonts {
+            let fonts = FontSearcher::new()
+                .include_system_fonts(self.font_config.include_system_fonts)
+                .include_embedded_fonts(self.font_config.include_embedded_fonts)
+                .search();
+            families.extend(fonts.book.families().map(|(family, _)| family.to_string()));
+        }
+
+        for source in self.font_config.font_sources() {
+            let data = match source {
+                FontSource::File(path, _) => load_font_file(path).ok(),
+                FontSource::Data(data, _) => decode_font_data(data).ok(),
+                FontSource::System(_) => None,
+            };
+            if let Some(data) = data {
+                families.extend(FontInfo::iter(&data).map(|info| info.family));
+            }
+        }
+
+        families.sort();
+        families.dedup();
+        families
+    }
+
+    /// Configured system font names, primaries and fallbacks, that the font search did not
+    /// find. Typst substitutes another font for these instead of failing.
+    pub fn validate_fonts(&self) -> Vec<String> {
+        let result: Warned<Result<PagedDocument, TypstAsLibError>> = self
+            .engine
+            .compile_with_input(self.formula_content("x", true));
+        let unknown: HashSet<String> = result
+            .warnings
+            .iter()
+            .filter_map(|warning| warning.message.strip_prefix("unknown font family: "))
+            .map(str::to_lowercase)
+            .collect();
+
+        let missing: Vec<String> = self
+            .font_config
+            .font_sources()
+            .filter_map(|source| match source {
+                FontSource::System(name) if unknown.contains(&name.to_lowercase()) => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        for name in &missing {
+            log::warn!("Font not found: {name}");
+        }
+        missing
+    }
+
+    /// Compile a trivial formula with the configured preamble so that mistakes in the
+    /// preamble are reported once, instead of surfacing as a failure of every formula
+    pub fn check_preamble(&self) -> Result<()> {
+        if self.font_config.preamble.is_none() {
+            return Ok(());
+        }
+
+        self.render_formula("x", true, RenderFormat::Svg, None)?;
+        Ok(())
+    }
+
+    /// Preamble text as passed to the template, terminated by a line break
+    fn preamble_source(&self) -> String {
+        match &self.font_config.preamble {
+            Some(preamble) => format!("{preamble}\n"),
+            None => String::new(),
+        }
+    }
+
+    /// Render many `(formula, is_inline)` pairs with the same engine, returning one result
+    /// per input in the same order so a single failing formula doesn't abort the batch.
+    /// Formulas are rendered in parallel on the rayon thread pool.
+    pub fn render_batch(
+        &self,
+        inputs: &[(String, bool)],
+        format: RenderFormat,
+        ppi: Option<f32>,
+    ) -> Vec<Result<FormulaRenderResult>> {
+        inputs
+            .par_iter()
+            .map(|(formula, is_inline)| self.render_formula(formula, *is_inline, format, ppi))
+            .collect()
+    }
+
+    /// Compile a formula and subset every font it uses to the glyphs it needs. Useful
+    /// when embedding the fonts next to the output, e.g. through a CSS `@font-face`,
+    /// where shipping whole font files would dwarf the formulas themselves.
+    pub fn subset_fonts(&self, formula: &str, is_inline: bool) -> Result<Vec<SubsetFont>> {
+        let content = self.formula_content(formula, is_inline);
+        let page = self.render_page(content, 72.0, false)?;
+
+        let mut used = Vec::new();
+        collect_glyphs(&page.doc.pages[0].frame, &mut used);
+
+        used.into_iter()
+            .map(|(font, glyph_ids)| {
+                let data = subset_font(font.data(), font.index() as usize, &glyph_ids)?;
+                Ok(SubsetFont {
+                    family: font.info().family.clone(),
+                    data,
+                    glyph_count: glyph_ids.len(),
+                })
+            })
+            .collect()
+    }
+
+    /// Render a formula to a premultiplied RGBA pixmap, returning it with its
+    /// width and height in em. Useful for compositing without re-decoding PNG data.
+    pub fn render_formula_pixmap(
+        &self,
+        formula: &str,
+        is_inline: bool,
+        ppi: Option<f32>,
+    ) -> Result<(Pixmap, f64, f64)> {
+        let content = self.formula_content(formula, is_inline);
+
+        let page = self.render_page(content, ppi.unwrap_or(self.default_ppi), true)?;
+        let pixmap = page
+            .pixmap
+            .ok_or_else(|| RenderError::EmptyRender(formula.to_string()))?;
+        Ok((pixmap, page.x_em, page.y_em))
+    }
+
+    /// Compile the template with the given inputs and measure the first page,
+    /// rasterizing it when `rasterize` is set or trimming needs the pixels
+    fn render_page(
+        &self,
+        content: FormulaContent,
+        ppi: f32,
+        rasterize: bool,
+    ) -> Result<RenderedPage> {
+        // mitex and Typst accept an empty formula but produce a blank page
+        if content.formula.trim().is_empty() {
+            return Err(RenderError::EmptyFormula);
+        }
+        let formula = content.formula.clone();
+        let is_inline = content.inline;
+        let requested_fonts: Vec<String> = [&content.body_font, &content.math_font]
+            .into_iter()
+            .filter(|font| !font.is_empty())
+            .cloned()
+            .collect();
+
+        let result = match self.font_config.formula_timeout {
+            Some(timeout) => self.compile_with_timeout(content, timeout)?,
+            None => self.engine.compile_with_input(content),
+        };
+
+        let doc: PagedDocument = match result.output {
+            Ok(doc) => doc,
+            Err(error) => {
+                let error = match package_error(&error) {
+                    Some(package_error) => RenderError::PackageUnavailable(package_error),
+                    None => RenderError::compile(&error, &formula),
+                };
+                log::error!("{error}");
+                return Err(error);
+            }
+        };
+
+        // Typst only warns about unknown families and substitutes its own fonts, which
+        // would silently ignore a font requested for this render
+        for font in &requested_fonts {
+            let unknown = format!("unknown font family: {}", font.to_lowercase());
+            if result
+                .warnings
+                .iter()
+                .any(|warning| warning.message.as_str() == unknown)
+            {
+                return Err(RenderError::FontNotFound(font.clone()));
+            }
+        }
+        // Font chains name fonts that may be missing on purpose, so only other warnings
+        // are worth reporting
+        let warnings = result
+            .warnings
+            .iter()
+            .filter(|warning| !warning.message.starts_with("unknown font family"))
+            .map(FormulaDiagnostic::from_source)
+            .collect();
+
+        let mut doc = doc;
+        stack_pages(&mut doc);
+        let page = &doc.pages[0];
+        let size = page.frame.size();
+        let pixel_width = (size.x.to_pt() * ppi as f64 / 72.0).round() as u32;
+        let pixel_height = (size.y.to_pt() * ppi as f64 / 72.0).round() as u32;
+
+        // Trimming needs the pixmap even for SVG to find the ink
+        let needs_pixmap = rasterize || self.font_config.trim;
+        if needs_pixmap && pixel_width as u64 * pixel_height as u64 > self.font_config.max_pixels {
+            return Err(RenderError::TooLarge {
+                formula,
+                width: pixel_width,
+                height: pixel_height,
+                max_pixels: self.font_config.max_pixels,
+            });
+        }
+        let mut pixmap = (needs_pixmap && pixel_width > 0 && pixel_height > 0)
+            .then(|| typst_render::render(page, ppi / 72.0));
+
+        // Visible area of the page in points as (left, top, width, height)
+        let mut bounds_pt = (0.0, 0.0, size.x.to_pt(), size.y.to_pt());
+        let ink = pixmap
+            .as_ref()
+            .filter(|_| self.font_config.trim)
+            .and_then(|pixmap| ink_bounds(pixmap, self.font_config.background));
+        let trimmed = ink.is_some();
+        if let Some(ink) = ink {
+            let px_to_pt = 72.0 / ppi as f64;
+            bounds_pt = (
+                ink.x() as f64 * px_to_pt,
+                ink.y() as f64 * px_to_pt,
+                ink.width() as f64 * px_to_pt,
+                ink.height() as f64 * px_to_pt,
+            );
+            pixmap = pixmap.and_then(|pixmap| pixmap.clone_rect(ink));
+        }
+
+        let (_, top_pt, x_pt, y_pt) = bounds_pt;
+        let em_to_pt = self.font_config.font_size_pt;
+
+        // Inline formulas are wrapped in a box, whose frame keeps the line baseline.
+        // Display formulas sit on the bottom edge of the output.
+        let bottom_pt = top_pt + y_pt;
+        let baseline_pt = if is_inline {
+            find_baseline(&page.frame).map_or(bottom_pt, |baseline| baseline.to_pt())
+        } else {
+            bottom_pt
+        };
+
+        Ok(RenderedPage {
+            pixmap,
+            bounds_pt,
+            trimmed,
+            x_em: x_pt / em_to_pt,
+            y_em: y_pt / em_to_pt,
+            depth_em: (bottom_pt - baseline_pt) / em_to_pt,
+            glyph_count: count_glyphs(&page.frame),
+            warnings,
+            doc,
+        })
+    }
+
+    /// Compile on a separate thread and stop waiting for it after `timeout`. The thread
+    /// cannot be stopped; if it runs over, it finishes in the background and its result
+    /// is dropped, which lets a batch move on past a runaway formula.
+    fn compile_with_timeout(
+        &self,
+        content: FormulaContent,
+        timeout: Duration,
+    ) -> Result<Warned<std::result::Result<PagedDocument, TypstAsLibError>>> {
+        let formula = content.formula.clone();
+        let engine = Arc::clone(&self.engine);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The receiver is gone if the caller gave up waiting
+            let _ = sender.send(engine.compile_with_input(content));
+        });
+
+        receiver
+            .recv_timeout(timeout)
+            .map_err(|_| RenderError::Timeout { formula, timeout })
+    }
+
+    /// Compile the template with the given inputs and export the first page
+    fn render_content(
+        &self,
+        content: FormulaContent,
+        format: RenderFormat,
+        ppi: Option<f32>,
+        encode: &EncodeOptions,
+    ) -> Result<FormulaRenderResult> {
+        let mut results = self.render_content_multi(content, &[format], ppi, encode)?;
+        Ok(results.remove(0))
+    }
+
+    /// Compile the template with the given inputs once and export the first page to
+    /// each of `formats`, retrying with the fallback math font if enabled
+    fn render_content_multi(
+        &self,
+        content: FormulaContent,
+        formats: &[RenderFormat],
+        ppi: Option<f32>,
+        encode: &EncodeOptions,
+    ) -> Result<Vec<FormulaRenderResult>> {
+        match self.render_content_once(content.clone(), formats, ppi, encode) {
+            Err(error) if self.font_config.retry_with_fallback_font && error.is_font_error() => {
+                let fallback_content = FormulaContent {
+                    math_font: FALLBACK_MATH_FONT.to_string(),
+                    ..content.clone()
+                };
+                // Report the original problem if the fallback does not help either
+                let mut results = self
+                    .render_content_once(fallback_content, formats, ppi, encode)
+                    .map_err(|_| error)?;
+                let ppi = ppi.unwrap_or(self.default_ppi);
+                for result in &mut results {
+                    result.used_fallback = true;
+                    // Identify the render by what was asked for, not the font standing in
+                    result.render_hash = self.cache_key(&content, result.format, ppi, encode);
+                    // Repeats of the formula then skip compiling the failing original
+                    if let Some(cache) = &self.cache {
+                        cache
+                            .lock()
+                            .unwrap()
+                            .put(result.render_hash, result.clone());
+                    }
+                }
+                Ok(results)
+            }
+            results => results,
+        }
+    }
+
+    fn render_content_once(
+        &self,
+        content: FormulaContent,
+        formats: &[RenderFormat],
+        ppi: Option<f32>,
+        encode: &EncodeOptions,
+    ) -> Result<Vec<FormulaRenderResult>> {
+        let formula = content.formula.clone();
+        let is_inline = content.inline;
+        let ppi = ppi.unwrap_or(self.default_ppi);
+
+        let cache_keys: Vec<u64> = formats
+            .iter()
+            .map(|format| self.cache_key(&content, *format, ppi, encode))
+            .collect();
+        let cached: Vec<Option<FormulaRenderResult>> = cache_keys
+            .iter()
+            .map(|cache_key| {
+                self.cache
+                    .as_ref()
+                    .and_then(|cache| cache.lock().unwrap().get(cache_key).cloned())
+            })
+            .collect();
+        if cached.iter().all(Option::is_some) {
+            return Ok(cached.into_iter().flatten().collect());
+        }
+
+        // Only formats that still need encoding decide whether to rasterize
+        let rasterize = formats
+            .iter()
+            .zip(&cached)
+            .any(|(format, cached)| cached.is_none() && *format != RenderFormat::Svg);
+        let page = self.render_page(content, ppi, rasterize)?;
+
+        formats
+            .iter()
+            .zip(cache_keys)
+            .zip(cached)
+            .map(|((format, cache_key), cached)| {
+                if let Some(cached) = cached {
+                    return Ok(cached);
+                }
+                let render_result =
+                    self.encode_page(&page, &formula, is_inline, *format, encode, cache_key)?;
+                if let Some(cache) = &self.cache {
+                    cache.lock().unwrap().put(cache_key, render_result.clone());
+                }
+                Ok(render_result)
+            })
+            .collect()
+    }
+
+    /// Export a compiled page to `format`
+    fn encode_page(
+        &self,
+        rendered: &RenderedPage,
+        formula: &str,
+        is_inline: bool,
+        format: RenderFormat,
+        encode: &EncodeOptions,
+        cache_key: u64,
+    ) -> Result<FormulaRenderResult> {
+        let (left_pt, top_pt, x_pt, y_pt) = rendered.bounds_pt;
+        let page = &rendered.doc.pages[0];
+
+        let data = match format {
+            RenderFormat::Svg => {
+                // Typst numbers glyph, clip path and gradient ids the same way in every
+                // render; make them unique per formula so inline SVGs can share a page
+                let mut svg = prefix_svg_ids(&typst_svg::svg(page), &format!("f{cache_key:016x}-"));
+                if rendered.trimmed {
+                    svg = set_svg_root_attribute(
+                        &svg,
+                        "viewBox",
+                        &format!("{left_pt} {top_pt} {x_pt} {y_pt}"),
+                    );
+                    svg = set_svg_root_attribute(&svg, "width", &format!("{x_pt}pt"));
+                    svg = set_svg_root_attribute(&svg, "height", &format!("{y_pt}pt"));
+                }
+                if self.font_config.minify_svg {
+                    svg = minify_svg(&svg);
+                }
+                svg.into_bytes()
+            }
+            RenderFormat::Png | RenderFormat::Jpeg | RenderFormat::WebP | RenderFormat::Avif => {
+                match &rendered.pixmap {
+                    None if self.font_config.allow_empty_render => vec![],
+                    None => return Err(RenderError::EmptyRender(formula.to_string())),
+                    Some(pixmap) => match format {
+                        RenderFormat::Jpeg => encode_jpeg(
+                            pixmap.data(),
+                            pixmap.width(),
+                            pixmap.height(),
+                            encode.jpeg_background,
+                            encode.jpeg_quality,
+                        )
+                        .map_err(|error| RenderError::encode(format, formula, error))?,
+                        RenderFormat::WebP => {
+                            encode_webp(pixmap.data(), pixmap.width(), pixmap.height())
+                                .map_err(|error| RenderError::encode(format, formula, error))?
+                        }
+                        RenderFormat::Avif => encode_avif(
+                            pixmap.data(),
+                            pixmap.width(),
+                            pixmap.height(),
+                            encode.avif_quality,
+                            encode.avif_speed,
+                        )
+                        .map_err(|error| RenderError::encode(format, formula, error))?,
+                        _ => pixmap
+                            .encode_png()
+                            .map_err(|error| RenderError::encode(format, formula, error))?,
+                    },
+                }
+            }
+        };
+
+        Ok(FormulaRenderResult {
+            formula: formula.to_string(),
+            is_inline,
+            format,
+            data,
+            x_pt,
+            y_pt,
+            x_em: rendered.x_em,
+            y_em: rendered.y_em,
+            depth_em: rendered.depth_em,
+            used_fallback: false,
+            glyph_count: rendered.glyph_count,
+            warnings: rendered.warnings.clone(),
+            render_hash: cache_key,
+            source: None,
+            tracked: false,
+        })
+    }
+
+    /// Hash everything that influences the rendered output of a formula
+    fn cache_key(
+        &self,
+        content: &FormulaContent,
+        format: RenderFormat,
+        ppi: f32,
+        encode: &EncodeOptions,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.config_hash.hash(&mut hasher);
+        content.formula.hash(&mut hasher);
+        content.inline.hash(&mut hasher);
+        content.body_font.hash(&mut hasher);
+        content.math_font.hash(&mut hasher);
+        content.number.hash(&mut hasher);
+        format.hash(&mut hasher);
+        ppi.to_bits().hash(&mut hasher);
+        encode.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Find the smallest pixel rectangle containing every pixel that differs from the page
+/// background, which is transparent unless `background` is set
+fn ink_bounds(pixmap: &Pixmap, background: Option<Color>) -> Option<IntRect> {
+    let width = pixmap.width() as usize;
+    let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+
+    // Pixels are premultiplied; allow for rounding in the rasterizer
+    let page = background.map_or([0; 4], |color| {
+        let alpha = color.a as u32;
+        let premultiply = |channel: u8| ((channel as u32 * alpha + 127) / 255) as u8;
+        [
+            premultiply(color.r),
+            premultiply(color.g),
+            premultiply(color.b),
+            color.a,
+        ]
+    });
+    let is_ink = |pixel: &[u8]| {
+        pixel
+            .iter()
+            .zip(page)
+            .any(|(channel, page)| channel.abs_diff(page) > 1)
+    };
+
+    for (i, pixel) in pixmap.data().chunks_exact(4).enumerate() {
+        if is_ink(pixel) {
+            let (x, y) = (i % width, i / width);
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+
+    if left >= right || top >= bottom {
+        return None;
+    }
+    IntRect::from_ltrb(left as i32, top as i32, right as i32, bottom as i32)
+}
+
+/// Replace (or add) an attribute on the root `<svg>` element
+fn set_svg_root_attribute(svg: &str, name: &str, value: &str) -> String {
+    let Some(tag_start) = svg.find("<svg") else {
+        return svg.to_string();
+    };
+    let Some(tag_len) = svg[tag_start..].find('>') else {
+        return svg.to_string();
+    };
+    let tag_end = tag_start + tag_len;
+    let tag = &svg[tag_start..tag_end];
+
+    let needle = format!(" {name}=\"");
+    let new_tag = match tag.find(&needle) {
+        Some(attr_start) => {
+            let value_start = attr_start + needle.len();
+            let value_end = value_start + tag[value_start..].find('"').unwrap_or(0);
+            format!("{}{}{}", &tag[..value_start], value, &tag[value_end..])
+        }
+        None => format!("{tag} {name}=\"{value}\""),
+    };
+
+    format!("{}{}{}", &svg[..tag_start], new_tag, &svg[tag_end..])
+}
+
+/// Prefix every id defined in an SVG, and every reference to one, with `prefix`. Only
+/// attributes of tags are rewritten; text, comments and CDATA sections are kept as they are.
+fn prefix_svg_ids(svg: &str, prefix: &str) -> String {
+    const VERBATIM: [(&str, &str); 4] = [
+        ("<!--", "-->"),
+        ("<![CDATA[", "]]>"),
+        ("<?", "?>"),
+        ("<!", ">"),
+    ];
+
+    let mut output = String::with_capacity(svg.len());
+    let mut rest = svg;
+
+    while let Some(tag_start) = rest.find('<') {
+        output.push_str(&rest[..tag_start]);
+        rest = &rest[tag_start..];
+
+        let verbatim = VERBATIM
+            .iter()
+            .find(|(open, _)| rest.starts_with(open))
+            .map(|(open, close)| {
+                rest[open.len()..]
+                    .find(close)
+                    .map_or(rest.len(), |end| open.len() + end + close.len())
+            });
+        if let Some(end) = verbatim {
+            output.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let mut quote = None;
+        let mut tag_len = rest.len();
+        for (i, c) in rest.char_indices() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, '>') => {
+                    tag_len = i + 1;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        output.push_str(&prefix_tag_ids(&rest[..tag_len], prefix));
+        rest = &rest[tag_len..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Prefix the `id` of a single tag, its `href` if it points at an id, and the ids of any
+/// `url(#...)` in its other attributes
+fn prefix_tag_ids(tag: &str, prefix: &str) -> String {
+    let mut output = String::with_capacity(tag.len() + prefix.len());
+    let mut rest = tag;
+
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals]
+            .trim_end()
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or_default();
+        let after_equals = &rest[equals + 1..];
+        let value_offset = equals + 1 + after_equals.len() - after_equals.trim_start().len();
+        let quote = match rest[value_offset..].chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => {
+                output.push_str(&rest[..=equals]);
+                rest = &rest[equals + 1..];
+                continue;
+            }
+        };
+        let value_start = value_offset + 1;
+        let value_end = rest[value_start..]
+            .find(quote)
+            .map_or(rest.len(), |end| value_start + end);
+        let value = &rest[value_start..value_end];
+
+        output.push_str(&rest[..value_start]);
+        match name {
+            "id" => {
+                output.push_str(prefix);
+                output.push_str(value);
+            }
+            "href" | "xlink:href" if value.starts_with('#') => {
+                output.push('#');
+                output.push_str(prefix);
+                output.push_str(&value[1..]);
+            }
+            _ => output.push_str(&value.replace("url(#", &format!("url(#{prefix}"))),
+        }
+        rest = &rest[value_end..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Shrink an SVG without changing how it renders: drop comments and
+/// whitespace-only text between tags, and collapse whitespace inside tags
+fn minify_svg(svg: &str) -> String {
+    let mut output = String::with_capacity(svg.len());
+    let mut rest = svg;
+
+    while let Some(tag_start) = rest.find('<') {
+        let text = &rest[..tag_start];
+        if !text.trim().is_empty() {
+            output.push_str(text);
+        }
+        rest = &rest[tag_start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").map_or(rest.len(), |end| end + 3);
+            output.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        // Copy the tag, collapsing whitespace outside of attribute values
+        let mut quote = None;
+        let mut tag_len = rest.len();
+        for (i, c) in rest.char_indices() {
+            match (quote, c) {
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), _) => {}
+                (None, '"' | '\'') => quote = Some(c),
+                (None, c) if c.is_whitespace() => {
+                    if !output.ends_with(' ') {
+                        output.push(' ');
+                    }
+                    continue;
+                }
+                (None, '/' | '>') if output.ends_with(' ') => {
+                    output.pop();
+                }
+                _ => {}
+            }
+            output.push(c);
+            if quote.is_none() && c == '>' {
+                tag_len = i + 1;
+                break;
+            }
+        }
+        rest = &rest[tag_len..];
+    }
+
+    if !rest.trim().is_empty() {
+        output.push_str(rest);
+    }
+    output
+}
+
+/// Whether `version` is a Typst package version, `major.minor.patch`
+fn is_package_version(version: &str) -> bool {
+    let parts: Vec<&str> = version.split('.').collect();
+    parts.len() == 3
+        && parts
+            .iter()
+            .all(|part| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// Whether `name` is a valid package namespace or name, e.g. `preview` or `mitex`
+fn is_package_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+/// Quote a value as a Typst string literal
+fn typst_string(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+/// Gather the distinct glyph ids of every font used in a frame, in order of first use
+fn collect_glyphs(frame: &Frame, used: &mut Vec<(Font, Vec<u16>)>) {
+    for (_, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => collect_glyphs(&group.frame, used),
+            FrameItem::Text(text) => {
+                let index = match used.iter().position(|(font, _)| *font == text.font) {
+                    Some(index) => index,
+                    None => {
+                        used.push((text.font.clone(), Vec::new()));
+                        used.len() - 1
+                    }
+                };
+                let glyph_ids = &mut used[index].1;
+                for glyph in &text.glyphs {
+                    if !glyph_ids.contains(&glyph.id) {
+                        glyph_ids.push(glyph.id);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Locate the first baseline in a frame, measured from its top edge
+fn find_baseline(frame: &Frame) -> Option<Abs> {
+    for (pos, item) in frame.items() {
+        if let FrameItem::Group(group) = item {
+            if group.frame.has_baseline() {
+                return Some(pos.y + group.frame.baseline());
+            }
+            if let Some(baseline) = find_baseline(&group.frame) {
+                return Some(pos.y + baseline);
+            }
+        }
+    }
+    None
+}
+
+/// NFC-normalize a formula and, for LaTeX, apply [`LATEX_NORMALIZATIONS`]. Typst math
+/// reads the Unicode symbols as they are.
+fn normalize_formula(formula: &str, input_syntax: InputSyntax) -> String {
+    let normalized = formula.nfc();
+    if input_syntax != InputSyntax::Latex {
+        return normalized.collect();
+    }
+
+    let mut output = String::with_capacity(formula.len());
+    for c in normalized {
+        match LATEX_NORMALIZATIONS.iter().find(|(from, _)| *from == c) {
+            Some((_, to)) => output.push_str(to),
+            None => output.push(c),
+        }
+    }
+    output
+}
+
+/// Total number of glyphs in the text items of `frame` and its groups
+fn count_glyphs(frame: &Frame) -> usize {
+    frame
+        .items()
+        .map(|(_, item)| match item {
+            FrameItem::Text(text) => text.glyphs.len(),
+            FrameItem::Group(group) => count_glyphs(&group.frame),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Flatten premultiplied RGBA pixels onto an opaque background and encode them as JPEG
+fn encode_jpeg(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    background: Color,
+    quality: u8,
+) -> Result<Vec<u8>, image::ImageError> {
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+    for pixel in rgba.chunks_exact(4) {
+        // The pixmap is premultiplied, so compositing is `src + bg * (1 - alpha)`
+        let inverse_alpha = 255 - pixel[3] as u32;
+        for (channel, bg) in pixel[..3]
+            .iter()
+            .zip([background.r, background.g, background.b])
+        {
+            rgb.push((*channel as u32 + bg as u32 * inverse_alpha / 255) as u8);
+        }
+    }
+
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, quality.clamp(1, 100)).write_image(
+        &rgb,
+        width,
+        height,
+        ExtendedColorType::Rgb8,
+    )?;
+    Ok(data)
+}
+
+/// Convert premultiplied RGBA pixels to straight alpha
+fn demultiply(rgba: &[u8]) -> Vec<u8> {
+    let mut straight = Vec::with_capacity(rgba.len());
+    for pixel in rgba.chunks_exact(4) {
+        let alpha = pixel[3] as u32;
+        for channel in &pixel[..3] {
+            let value = if alpha == 0 {
+                0
+            } else {
+                (*channel as u32 * 255 + alpha / 2) / alpha
+            };
+            straight.push(value.min(255) as u8);
+        }
+        straight.push(pixel[3]);
+    }
+    straight
+}
+
+/// Encode premultiplied RGBA pixels as lossless WebP
+fn encode_webp(rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>, image::ImageError> {
+    let straight = demultiply(rgba);
+    let mut data = Vec::new();
+    WebPEncoder::new_lossless(&mut data).write_image(
+        &straight,
+        width,
+        height,
+        ExtendedColorType::Rgba8,
+    )?;
+    Ok(data)
+}
+
+/// Encode premultiplied RGBA pixels as AVIF, keeping the alpha channel
+fn encode_avif(
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    speed: u8,
+) -> Result<Vec<u8>, ravif::Error> {
+    let pixels: Vec<RGBA8> = demultiply(rgba)
+        .chunks_exact(4)
+        .map(|pixel| RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+        .collect();
+    let encoded = AvifEncoder::new()
+        .with_quality(quality.min(100) as f32)
+        .with_alpha_quality(quality.min(100) as f32)
+        .with_speed(speed.clamp(1, 10))
+        .encode_rgba(Img::new(&pixels[..], width as usize, height as usize))?;
+    Ok(encoded.avif_file)
+}
+
+/// Implements the Default trait for RenderEngine.
+impl Default for RenderEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Class prefix used by [`FormulaRenderResult::to_html`]
+pub const DEFAULT_CLASS_PREFIX: &str = "gladst";
+
+/// How [`FormulaRenderResult::to_html_with_align`] lines a formula up with the
+/// surrounding text
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum VerticalAlign {
+    /// Center the image on the middle of the lowercase letters
+    #[default]
+    Middle,
+    /// Put the formula's baseline on the text baseline, using [`FormulaRenderResult::depth_em`]
+    Baseline,
+    /// Align the bottom of the image with the bottom of the line
+    Bottom,
+    /// Raise the bottom of the image this many em above the text baseline (negative lowers it)
+    Custom(f64),
+}
+
+impl VerticalAlign {
+    /// The CSS `vertical-align` value for an image extending `depth_em` below the baseline
+    fn to_css(self, depth_em: f64) -> String {
+        match self {
+            VerticalAlign::Middle => "middle".to_string(),
+            VerticalAlign::Baseline => format!("{:.4}em", -depth_em),
+            VerticalAlign::Bottom => "bottom".to_string(),
+            VerticalAlign::Custom(offset_em) => format!("{offset_em:.4}em"),
+        }
+    }
+}
+
+/// x-height of New Computer Modern in em, which converts sizes for [`SizingMode::Ex`]
+const X_HEIGHT_EM: f64 = 0.431;
+
+/// Units in which [`FormulaRenderResult::to_html_with_sizing`] sizes a formula
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SizingMode {
+    /// Width and height in em, scaling with the surrounding font size
+    #[default]
+    Em,
+    /// Width and height in ex, scaling with the x-height of the surrounding font, which
+    /// matches text set in a font with a different x-height more closely
+    Ex,
+    /// No width or height, so the image is shown at its intrinsic size (points for
+    /// SVG) and can be sized by CSS instead; only the alignment is set
+    Intrinsic,
+}
+
+impl FormulaRenderResult {
+    /// Build an `<img>` tag with the image embedded as a base64 data URL
+    pub fn to_html(&self) -> String {
+        self.to_html_with_class(DEFAULT_CLASS_PREFIX)
+    }
+
+    /// Like [`to_html`](Self::to_html), with the class rendered as `"{prefix} math"`
+    /// or `"{prefix} displaymath"`
+    pub fn to_html_with_class(&self, prefix: &str) -> String {
+        self.img_tag(&self.data_url(), prefix)
+    }
+
+    /// Like [`to_html`](Self::to_html), with the image aligned by `align` instead of
+    /// on the baseline (inline) or centered (display)
+    pub fn to_html_with_align(&self, align: VerticalAlign) -> String {
+        self.img_tag_aligned(
+            &self.data_url(),
+            DEFAULT_CLASS_PREFIX,
+            &align.to_css(self.depth_em),
+            &self.formula,
+        )
+    }
+
+    /// Like [`to_html`](Self::to_html), with the image sized in the units of `sizing`
+    pub fn to_html_with_sizing(&self, sizing: SizingMode) -> String {
+        let style = match sizing {
+            SizingMode::Em => self.size_style(&self.vertical_align()),
+            SizingMode::Ex => {
+                let vertical_align = if self.is_inline {
+                    format!("{:.4}ex", -self.depth_em / X_HEIGHT_EM)
+                } else {
+                    "middle".to_string()
+                };
+                format!(
+                    "width: {:.4}ex; height: {:.4}ex; vertical-align: {};",
+                    self.x_em / X_HEIGHT_EM,
+                    self.y_em / X_HEIGHT_EM,
+                    vertical_align
+                )
+            }
+            SizingMode::Intrinsic => {
+                let vertical_align = if self.is_inline {
+                    // The intrinsic size is in points, so the depth is too
+                    let pt_per_em = if self.y_em > 0.0 {
+                        self.y_pt / self.y_em
+                    } else {
+                        0.0
+                    };
+                    format!("{:.4}pt", -self.depth_em * pt_per_em)
+                } else {
+                    "middle".to_string()
+                };
+                format!("vertical-align: {};", vertical_align)
+            }
+        };
+        self.img_tag_styled(
+            &self.data_url(),
+            DEFAULT_CLASS_PREFIX,
+            &style,
+            &self.formula,
+        )
+    }
+
+    /// Like [`to_html`](Self::to_html), with `alt` as the image's alt text instead of
+    /// the formula source, e.g. a spoken description for screen readers
+    pub fn to_html_with_alt(&self, alt: &str) -> String {
+        self.img_tag_with_alt(&self.data_url(), DEFAULT_CLASS_PREFIX, alt)
+    }
+
+    /// Build an `<img>` tag pointing at an image stored elsewhere
+    pub fn to_html_with_src(&self, src: &str) -> String {
+        self.img_tag(src, DEFAULT_CLASS_PREFIX)
+    }
+
+    /// Embed SVG markup directly in a `<span>` sized like [`img_tag`](Self::img_tag), so
+    /// the SVG can be styled with CSS (e.g. `.gladst path { fill: currentColor }`) and
+    /// skips the base64 overhead. Other formats fall back to an `<img>` with a data URL.
+    pub fn to_inline_svg(&self) -> String {
+        self.to_inline_svg_with_class(DEFAULT_CLASS_PREFIX)
+    }
+
+    /// Like [`to_inline_svg`](Self::to_inline_svg), with the class rendered as
+    /// `"{prefix} math"` or `"{prefix} displaymath"`
+    pub fn to_inline_svg_with_class(&self, prefix: &str) -> String {
+        if self.format != RenderFormat::Svg {
+            return self.to_html_with_class(prefix);
+        }
+
+        // The span sets the size; the SVG fills it and scales with the font size
+        let markup = String::from_utf8_lossy(&self.data);
+        let svg = markup
+            .find("<svg")
+            .map_or(&*markup, |start| &markup[start..]);
+        let svg = set_svg_root_attribute(svg, "width", "100%");
+        let svg = set_svg_root_attribute(&svg, "height", "100%");
+
+        format!(
+            r#"<span class="{class_prefix} {env}" role="img" aria-label="{formula}"{source} style="display: inline-block; width: {x_em:.4}em; height: {y_em:.4}em; vertical-align: {vertical_align};">{svg}</span>"#,
+            class_prefix = encode_double_quoted_attribute(prefix),
+            env = self.env_class(),
+            formula = encode_double_quoted_attribute(&self.formula),
+            source = self.source_attributes(),
+            x_em = self.x_em,
+            y_em = self.y_em,
+            vertical_align = self.vertical_align(),
+        )
+    }
+
+    /// The same image displayed `factor` times as large: the em sizes used by the HTML
+    /// methods are multiplied, the image itself is unchanged. Useful to fine-tune the
+    /// size of SVGs, which some browsers show slightly off, without rendering again.
+    pub fn scaled(mut self, factor: f64) -> Self {
+        self.x_em *= factor;
+        self.y_em *= factor;
+        self.depth_em *= factor;
+        self
+    }
+
+    /// The same image with every SVG id, and every reference to one, prefixed with
+    /// `prefix`. Ids are unique per formula, so inline SVGs of different formulas can share
+    /// a page; a formula shown inline more than once should give each copy its own prefix,
+    /// e.g. from its position in the document. Other formats are returned unchanged.
+    pub fn with_svg_id_prefix(mut self, prefix: &str) -> Self {
+        if self.format == RenderFormat::Svg {
+            let svg = prefix_svg_ids(&String::from_utf8_lossy(&self.data), prefix);
+            self.data = svg.into_bytes();
+        }
+        self
+    }
+
+    /// The same result with `source`, the original text of the formula element, kept in
+    /// the HTML it is shown by; see [`Self::source`]
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// The same result with its formula and [`Self::render_hash`] kept in the HTML it is
+    /// shown by, so that a document can be rendered again incrementally; see [`Self::tracked`]
+    pub fn with_tracking(mut self) -> Self {
+        self.tracked = true;
+        self
+    }
+
+    /// The image as a base64 `data:` URL
+    pub fn data_url(&self) -> String {
+        let b64 = general_purpose::STANDARD.encode(&self.data);
+        format!("data:{};base64,{}", self.format.mime_type(), b64)
+    }
+
+    /// Build an `<img>` tag with the given `src` and class prefix
+    pub fn img_tag(&self, src: &str, class_prefix: &str) -> String {
+        self.img_tag_with_alt(src, class_prefix, &self.formula)
+    }
+
+    /// Like [`img_tag`](Self::img_tag), with `alt` as the alt text instead of the formula
+    pub fn img_tag_with_alt(&self, src: &str, class_prefix: &str, alt: &str) -> String {
+        self.img_tag_aligned(src, class_prefix, &self.vertical_align(), alt)
+    }
+
+    fn img_tag_aligned(
+        &self,
+        src: &str,
+        class_prefix: &str,
+        vertical_align: &str,
+        alt: &str,
+    ) -> String {
+        self.img_tag_styled(src, class_prefix, &self.size_style(vertical_align), alt)
+    }
+
+    fn img_tag_styled(&self, src: &str, class_prefix: &str, style: &str, alt: &str) -> String {
+        format!(
+            r#"<img class="{class_prefix} {env}" style="{style}" src="{src}" alt="{alt}"{source}/>"#,
+            class_prefix = encode_double_quoted_attribute(class_prefix),
+            env = self.env_class(),
+            source = self.source_attributes(),
+            style = style,
+            src = encode_double_quoted_attribute(src),
+            alt = encode_double_quoted_attribute(alt),
+        )
+    }
+
+    /// Inline style sizing the image in em
+    fn size_style(&self, vertical_align: &str) -> String {
+        format!(
+            "width: {:.4}em; height: {:.4}em; vertical-align: {};",
+            self.x_em, self.y_em, vertical_align
+        )
+    }
+
+    /// `data-gladst-*` attributes of a tracked result or one with a kept source, see
+    /// [`Self::tracked`] and [`Self::source`]
+    fn source_attributes(&self) -> String {
+        let mut attributes = String::new();
+        if self.tracked {
+            attributes.push_str(&format!(
+                r#" data-gladst-formula="{}" data-gladst-hash="{:016x}""#,
+                encode_double_quoted_attribute(&self.formula),
+                self.render_hash
+            ));
+        }
+        if let Some(source) = &self.source {
+            attributes.push_str(&format!(
+                r#" data-gladst-source="{}" data-gladst-env="{}""#,
+                encode_double_quoted_attribute(source),
+                self.env_class()
+            ));
+        }
+        attributes
+    }
+
+    fn env_class(&self) -> &'static str {
+        if self.is_inline {
+            "math"
+        } else {
+            "displaymath"
+        }
+    }
+
+    /// Inline formulas sit on the text baseline; display formulas are centered
+    fn vertical_align(&self) -> String {
+        if self.is_inline {
+            format!("{:.4}em", -self.depth_em)
+        } else {
+            "middle".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expected output of [`embedded_fonts_render_identical_png`]; running the tests with
+    /// `GLADEST_BLESS=1` writes it anew
+    const GOLDEN_PNG: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/embedded.png");
+    /// Open Sans compressed as WOFF2, licensed as in `testdata/OpenSans-LICENSE.txt`
+    const OPEN_SANS_WOFF2: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/testdata/OpenSans-Regular.woff2"
+    );
+    /// Vendored packages for offline LaTeX input, laid out as
+    /// `preview/mitex/<DEFAULT_MITEX_VERSION>`
+    const PACKAGES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/packages");
+    /// The math font of the Markdown demo
+    const LETE_SANS_MATH: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../markdown-compile-demo/LeteSansMath.otf"
+    );
+
+    /// Bundled fonts and Typst input, so tests need neither network access nor installed fonts
+    fn config() -> FontConfigBuilder {
+        FontConfig::embedded()
+            .into_builder()
+            .input_syntax(InputSyntax::Typst)
+    }
+
+    fn engine(font_config: FontConfig) -> RenderEngine {
+        RenderEngine::with_font_config(font_config).with_cache_capacity(0)
+    }
+
+    fn render(engine: &RenderEngine, formula: &str, format: RenderFormat) -> FormulaRenderResult {
+        engine.render_formula(formula, true, format, None).unwrap()
+    }
+
+    /// Families of the fonts the glyphs of an inline formula are taken from
+    fn font_families(engine: &RenderEngine, formula: &str) -> Vec<String> {
+        let page = engine
+            .render_page(engine.formula_content(formula, true), 72.0, false)
+            .unwrap();
+        let mut used = Vec::new();
+        collect_glyphs(&page.doc.pages[0].frame, &mut used);
+        used.iter()
+            .map(|(font, _)| font.info().family.clone())
+            .collect()
+    }
+
+    /// Every value of the attribute `name` in `markup`
+    fn attribute_values<'a>(markup: &'a str, name: &str) -> Vec<&'a str> {
+        let needle = format!(" {name}=\"");
+        markup
+            .match_indices(&needle)
+            .map(|(start, _)| {
+                let value = &markup[start + needle.len()..];
+                &value[..value.find('"').unwrap()]
+            })
+            .collect()
+    }
+
+    /// Resident set size of this process in bytes
+    #[cfg(target_os = "linux")]
+    fn resident_bytes() -> usize {
+        let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+        let pages: usize = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+        pages * 4096
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 0.01,
+            "{actual} is not within 1% of {expected}"
+        );
+    }
+
+    #[test]
+    fn font_size_scales_points_not_em() {
+        let formula = "sum_(k=1)^n k^2";
+        let small = render(
+            &engine(config().font_size_pt(10.0).build()),
+            formula,
+            RenderFormat::Svg,
+        );
+        let large = render(
+            &engine(config().font_size_pt(20.0).build()),
+            formula,
+            RenderFormat::Svg,
+        );
+
+        // The em of the larger size is twice as long, so a point is half as many em
+        assert_close(large.x_em / large.x_pt, small.x_em / small.x_pt / 2.0);
+        assert_close(large.y_em / large.y_pt, small.y_em / small.y_pt / 2.0);
+        // The formula is drawn twice as large, which leaves its size in em unchanged
+        assert_close(large.x_pt, small.x_pt * 2.0);
+        assert_close(large.y_pt, small.y_pt * 2.0);
+        assert_close(large.x_em, small.x_em);
+        assert_close(large.y_em, small.y_em);
+    }
+
+    #[test]
+    fn embedded_fonts_render_identical_png() {
+        let font_config = config().build();
+        assert!(!font_config.include_system_fonts);
+        let png = engine(font_config)
+            .render_formula(
+                "integral_0^1 x^2 dif x = 1/3",
+                false,
+                RenderFormat::Png,
+                Some(150.0),
+            )
+            .unwrap()
+            .data;
+
+        if std::env::var_os("GLADEST_BLESS").is_some() {
+            std::fs::create_dir_all(Path::new(GOLDEN_PNG).parent().unwrap()).unwrap();
+            std::fs::write(GOLDEN_PNG, &png).unwrap();
+        }
+        let golden = std::fs::read(GOLDEN_PNG).unwrap_or_else(|error| {
+            panic!("{GOLDEN_PNG}: {error}; run the tests with GLADEST_BLESS=1 to create it")
+        });
+        assert!(png == golden, "rendered PNG differs from {GOLDEN_PNG}");
+    }
+
+    #[test]
+    fn embedded_fonts_reject_fonts_outside_the_set() {
+        let engine = engine(config().build());
+        let render_with_math_font = |font| {
+            engine.render_formula_with_fonts("x", true, RenderFormat::Svg, None, None, Some(font))
+        };
+        assert!(render_with_math_font(FALLBACK_MATH_FONT).is_ok());
+        // Installed or not, system fonts are not searched
+        let error = render_with_math_font("Fira Math").unwrap_err();
+        assert!(
+            matches!(&error, RenderError::FontNotFound(font) if font == "Fira Math"),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn fallback_renders_are_cached_for_the_requested_font() {
+        let engine =
+            RenderEngine::with_font_config(config().retry_with_fallback_font(true).build());
+        let render = || {
+            engine
+                .render_formula_with_fonts(
+                    "x",
+                    true,
+                    RenderFormat::Svg,
+                    None,
+                    None,
+                    Some("Fira Math"),
+                )
+                .unwrap()
+        };
+        let first = render();
+        assert!(first.used_fallback);
+        let cache = engine.cache.as_ref().unwrap();
+        assert!(cache.lock().unwrap().contains(&first.render_hash));
+        assert_eq!(render().data, first.data);
+    }
+
+    #[test]
+    fn woff2_font_files_are_decompressed() {
+        let data = load_font_file(OPEN_SANS_WOFF2).unwrap();
+        assert!(matches!(data.get(..4), Some(b"\x00\x01\x00\x00" | b"OTTO")));
+        let names = read_font_names(&data, 0).unwrap();
+        assert_eq!(names.family_name.as_deref(), Some("Open Sans"));
+
+        let font_config = config()
+            .body_font(FontSource::File(OPEN_SANS_WOFF2.to_string(), None))
+            .build();
+        let engine = engine(font_config);
+        assert!(
+            engine
+                .available_fonts()
+                .iter()
+                .any(|family| family == "Open Sans")
+        );
+        let result = engine
+            .render_formula_with_fonts("x", true, RenderFormat::Png, None, Some("Open Sans"), None)
+            .unwrap();
+        assert!(!result.data.is_empty());
+        // Naming a font the engine did not load fails instead of substituting another
+        let error = engine
+            .render_formula_with_fonts("x", true, RenderFormat::Png, None, Some("Fira Sans"), None)
+            .unwrap_err();
+        assert!(
+            matches!(&error, RenderError::FontNotFound(font) if font == "Fira Sans"),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn file_fonts_supply_the_glyphs() {
+        let font_config = config()
+            .math_font(FontSource::File(LETE_SANS_MATH.to_string(), None))
+            .build();
+        let families = font_families(&engine(font_config), "x + y = z");
+        assert!(!families.is_empty());
+        assert!(
+            families.iter().all(|family| family == "Lete Sans Math"),
+            "glyphs taken from {families:?}"
+        );
+    }
+
+    #[test]
+    fn dropped_engines_release_font_files() {
+        const ENGINES: usize = 100;
+        let font_config = config()
+            .math_font(FontSource::File(LETE_SANS_MATH.to_string(), None))
+            .build();
+        // No rendering: Typst's memoization keeps fonts of past renders alive on purpose
+        let build_and_drop = || drop(engine(font_config.clone()));
+
+        // Let the allocator settle before measuring
+        for _ in 0..5 {
+            build_and_drop();
+        }
+        #[cfg(target_os = "linux")]
+        let before = resident_bytes();
+        for _ in 0..ENGINES {
+            build_and_drop();
+        }
+        // Leaking each engine's copy of the font would grow the process by `ENGINES` fonts
+        #[cfg(target_os = "linux")]
+        {
+            let font_len = std::fs::metadata(LETE_SANS_MATH).unwrap().len() as usize;
+            let growth = resident_bytes().saturating_sub(before);
+            assert!(
+                growth < ENGINES * font_len / 2,
+                "resident memory grew by {growth} bytes over {ENGINES} engines"
+            );
+        }
+    }
+
+    #[test]
+    fn prefixes_only_ids_in_attributes() {
+        let svg = r##"<svg><text>id="a" url(#b)</text><!-- id="c" --><g id="d" fill="url(#e)"><use xlink:href="#d"/><a href="https://example.com"/></g></svg>"##;
+        assert_eq!(
+            prefix_svg_ids(svg, "p-"),
+            r##"<svg><text>id="a" url(#b)</text><!-- id="c" --><g id="p-d" fill="url(#p-e)"><use xlink:href="#p-d"/><a href="https://example.com"/></g></svg>"##
+        );
+    }
+
+    #[test]
+    fn inline_svgs_share_a_document_without_id_collisions() {
+        let engine = engine(config().build());
+        let first = render(&engine, "sqrt(x^2 + 1)", RenderFormat::Svg);
+        let second = render(&engine, "frac(a, b)", RenderFormat::Svg);
+        // The same formula shown twice needs a prefix of its own for the second copy
+        let repeated = first.clone().with_svg_id_prefix("copy-");
+        let svgs: Vec<String> = [&first, &second, &repeated]
+            .iter()
+            .map(|result| result.to_inline_svg())
+            .collect();
+        let document = svgs.join("\n");
+
+        let ids = attribute_values(&document, "id");
+        assert!(!ids.is_empty());
+        let distinct: HashSet<&str> = ids.iter().copied().collect();
+        assert_eq!(distinct.len(), ids.len(), "duplicate ids in {document}");
+
+        // Every reference resolves within its own SVG
+        for svg in &svgs {
+            let own_ids = attribute_values(svg, "id");
+            let references = attribute_values(svg, "xlink:href")
+                .into_iter()
+                .chain(attribute_values(svg, "href"))
+                .filter_map(|href| href.strip_prefix('#'));
+            for reference in references {
+                assert!(own_ids.contains(&reference), "dangling #{reference}");
+            }
+        }
+    }
+
+    #[test]
+    fn minified_svg_is_smaller_and_still_parses() {
+        let formula = "mat(1, 2; 3, 4) vec(x, y)";
+        let plain = render(&engine(config().build()), formula, RenderFormat::Svg);
+        let minified = render(
+            &engine(config().minify_svg(true).build()),
+            formula,
+            RenderFormat::Svg,
+        );
+        let (plain_len, minified_len) = (plain.data.len(), minified.data.len());
+        println!(
+            "minify_svg: {plain_len} -> {minified_len} bytes ({:.1}% smaller)",
+            100.0 * plain_len.saturating_sub(minified_len) as f64 / plain_len as f64
+        );
+        assert!(minified_len < plain_len);
+
+        let parse = |data: &[u8]| {
+            usvg::Tree::from_data(data, &usvg::Options::default()).expect("valid SVG")
+        };
+        assert_eq!(parse(&minified.data).size(), parse(&plain.data).size());
+    }
+
+    #[test]
+    fn renders_offline_without_package_cache() {
+        // Typst input needs no packages, so no resolver may touch the network or the cache
+        let font_config = config().offline(true).package_dir(None).build();
+        let result = render(&engine(font_config), "x^2 + 1", RenderFormat::Svg);
+        assert!(!result.data.is_empty());
+    }
+
+    #[test]
+    fn renders_latex_offline_from_vendored_packages() {
+        let mitex = Path::new(PACKAGES).join(format!("preview/mitex/{DEFAULT_MITEX_VERSION}"));
+        assert!(
+            mitex.join("typst.toml").is_file(),
+            "vendor @preview/mitex:{DEFAULT_MITEX_VERSION} into {}",
+            mitex.display()
+        );
+        let font_config = config()
+            .input_syntax(InputSyntax::Latex)
+            .offline(true)
+            .package_dir(Some(PACKAGES.to_string()))
+            .build();
+        let result = render(
+            &engine(font_config),
+            r"\frac{a}{b} + \sqrt{x^2 + 1}",
+            RenderFormat::Svg,
+        );
+        assert!(!result.data.is_empty());
+    }
 }
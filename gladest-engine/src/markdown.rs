@@ -0,0 +1,88 @@
+//! Rendering of the math in a Markdown document.
+//!
+//! Math spans are turned into formula elements while the Markdown is converted to HTML,
+//! which is then rendered like any other document by the [`html`](crate::html) module.
+
+use html_escape::encode_text;
+use pulldown_cmark::{Event, Options, Parser};
+
+use crate::{
+    RenderEngine,
+    html::{
+        self, DEFAULT_SELECTOR, HtmlFormulaError, HtmlHooks, HtmlOptions, HtmlOutput,
+        parse_selector,
+    },
+};
+
+/// Convert Markdown to HTML, writing `$...$` as `<eq>$...$</eq>` and `$$...$$` as
+/// `<eq>$$...$$</eq>`, each with its line in the Markdown as a `data-gladst-line`
+/// attribute. Dollars escaped as `\$` or inside code spans and blocks stay literal text.
+pub fn markdown_to_html(markdown: &str) -> String {
+    let parser_options =
+        Options::ENABLE_MATH | Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let line_breaks: Vec<usize> = markdown
+        .match_indices('\n')
+        .map(|(offset, _)| offset)
+        .collect();
+    let line = |offset: usize| line_breaks.partition_point(|&line_break| line_break < offset) + 1;
+    let events = Parser::new_ext(markdown, parser_options)
+        .into_offset_iter()
+        .map(|(event, range)| match event {
+            Event::InlineMath(math) => Event::InlineHtml(
+                format!(
+                    r#"<eq data-gladst-line="{}">${}$</eq>"#,
+                    line(range.start),
+                    encode_text(&math)
+                )
+                .into(),
+            ),
+            Event::DisplayMath(math) => Event::InlineHtml(
+                format!(
+                    r#"<eq data-gladst-line="{}">$${}$$</eq>"#,
+                    line(range.start),
+                    encode_text(&math)
+                )
+                .into(),
+            ),
+            event => event,
+        });
+
+    let mut output = String::with_capacity(markdown.len() * 3 / 2);
+    pulldown_cmark::html::push_html(&mut output, events);
+    output
+}
+
+/// `options` with the selector of the formula elements written by [`markdown_to_html`].
+/// Raw HTML in the Markdown is only searched for `<eq>` elements.
+pub fn html_options(options: &HtmlOptions) -> HtmlOptions {
+    HtmlOptions {
+        selector: parse_selector(DEFAULT_SELECTOR).expect("default selector is valid"),
+        ..options.clone()
+    }
+}
+
+/// Convert a Markdown document to HTML with every math span replaced by an image,
+/// see [`html::render_html`]
+pub fn render_markdown(
+    markdown: &str,
+    engine: &RenderEngine,
+    options: &HtmlOptions,
+) -> (String, Vec<HtmlFormulaError>) {
+    let output = render_markdown_with(markdown, engine, options, &());
+    (output.html, output.errors)
+}
+
+/// Like [`render_markdown`], calling `hooks` around each formula
+pub fn render_markdown_with<H: HtmlHooks>(
+    markdown: &str,
+    engine: &RenderEngine,
+    options: &HtmlOptions,
+    hooks: &H,
+) -> HtmlOutput<H::Error> {
+    html::render_html_with(
+        &markdown_to_html(markdown),
+        engine,
+        &html_options(options),
+        hooks,
+    )
+}
@@ -0,0 +1,166 @@
+//! Packing of rendered formulas into a single sprite sheet image.
+//!
+//! A page with hundreds of formulas then loads one image instead of hundreds; each
+//! formula is shown through CSS background offsets into the sheet.
+
+use html_escape::encode_double_quoted_attribute;
+use tiny_skia::{Pixmap, PixmapPaint, Transform};
+
+use crate::{FormulaRenderResult, RenderError, RenderFormat, Result};
+
+/// Transparent pixels between neighbouring sprites, so a scaled-down sprite does not
+/// pick up the edge of its neighbour
+const SPRITE_GAP: u32 = 2;
+
+/// Position of one image in a [`SpriteSheet`], in pixels
+#[derive(Debug, Clone)]
+pub struct Sprite {
+    /// Name the image was packed under; its CSS class is `{class_prefix}-{key}`
+    pub key: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Images packed into one PNG
+#[derive(Debug, Clone)]
+pub struct SpriteSheet {
+    pub png: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// Every packed image, in the order they were given
+    pub sprites: Vec<Sprite>,
+}
+
+impl SpriteSheet {
+    /// Pack images, each with a unique key, into rows filled tallest first. Rows are
+    /// about as wide as the sheet ends up tall, so the sheet stays roughly square.
+    pub fn pack(images: &[(String, Pixmap)]) -> Result<Self> {
+        let area: f64 = images
+            .iter()
+            .map(|(_, image)| {
+                (image.width() + SPRITE_GAP) as f64 * (image.height() + SPRITE_GAP) as f64
+            })
+            .sum();
+        let widest = images.iter().map(|(_, image)| image.width()).max();
+        let row_width = widest.unwrap_or(0).max(area.sqrt().ceil() as u32);
+
+        let mut order: Vec<usize> = (0..images.len()).collect();
+        order.sort_by_key(|&index| std::cmp::Reverse(images[index].1.height()));
+
+        let mut positions = vec![(0, 0); images.len()];
+        let (mut x, mut y, mut row_height, mut width) = (0, 0, 0, 0);
+        for index in order {
+            let image = &images[index].1;
+            if x > 0 && x + image.width() > row_width {
+                x = 0;
+                y += row_height + SPRITE_GAP;
+                row_height = 0;
+            }
+            positions[index] = (x, y);
+            width = width.max(x + image.width());
+            row_height = row_height.max(image.height());
+            x += image.width() + SPRITE_GAP;
+        }
+        let height = y + row_height;
+
+        if width == 0 || height == 0 {
+            return Err(RenderError::EmptyRender("sprite sheet".to_string()));
+        }
+        let mut sheet = Pixmap::new(width, height).ok_or_else(|| RenderError::TooLarge {
+            formula: "sprite sheet".to_string(),
+            width,
+            height,
+            // tiny-skia's limit on the byte length of a row
+            max_pixels: i32::MAX as u64 / 4,
+        })?;
+
+        let sprites = images
+            .iter()
+            .zip(positions)
+            .map(|((key, image), (x, y))| {
+                sheet.draw_pixmap(
+                    x as i32,
+                    y as i32,
+                    image.as_ref(),
+                    &PixmapPaint::default(),
+                    Transform::identity(),
+                    None,
+                );
+                Sprite {
+                    key: key.clone(),
+                    x,
+                    y,
+                    width: image.width(),
+                    height: image.height(),
+                }
+            })
+            .collect();
+
+        let png = sheet
+            .encode_png()
+            .map_err(|error| RenderError::encode(RenderFormat::Png, "sprite sheet", error))?;
+
+        Ok(Self {
+            png,
+            width,
+            height,
+            sprites,
+        })
+    }
+
+    /// CSS showing the sheet at `url` in elements of class `{class_prefix}-sprite`, and
+    /// each sprite in elements that also have the class `{class_prefix}-{key}`. Offsets
+    /// and sizes are percentages, so the elements can be sized in em like `<img>` tags.
+    pub fn css(&self, url: &str, class_prefix: &str) -> String {
+        let url = url.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut css = format!(
+            ".{class_prefix}-sprite {{ background-image: url(\"{url}\"); background-repeat: no-repeat; }}\n"
+        );
+
+        for sprite in &self.sprites {
+            css.push_str(&format!(
+                ".{class_prefix}-{key} {{ background-size: {size_x:.4}% {size_y:.4}%; background-position: {position_x:.4}% {position_y:.4}%; }}\n",
+                key = sprite.key,
+                size_x = self.width as f64 / sprite.width as f64 * 100.0,
+                size_y = self.height as f64 / sprite.height as f64 * 100.0,
+                position_x = percent_offset(sprite.x, sprite.width, self.width),
+                position_y = percent_offset(sprite.y, sprite.height, self.height),
+            ));
+        }
+
+        css
+    }
+}
+
+/// A CSS background position in percent, which aligns that fraction of the image with
+/// the same fraction of the element
+fn percent_offset(offset: u32, size: u32, sheet_size: u32) -> f64 {
+    if sheet_size == size {
+        0.0
+    } else {
+        offset as f64 / (sheet_size - size) as f64 * 100.0
+    }
+}
+
+/// A `<span>` showing `result` as the sprite `key` of a sheet styled by
+/// [`SpriteSheet::css`], sized and aligned like [`FormulaRenderResult::img_tag`]
+pub fn sprite_tag(
+    result: &FormulaRenderResult,
+    key: &str,
+    class_prefix: &str,
+    alt: &str,
+) -> String {
+    format!(
+        r#"<span class="{class_prefix} {env} {class_prefix}-sprite {class_prefix}-{key}" role="img" aria-label="{alt}"{source} style="display: inline-block; width: {x_em:.4}em; height: {y_em:.4}em; vertical-align: {vertical_align};"></span>"#,
+        class_prefix = encode_double_quoted_attribute(class_prefix),
+        env = result.env_class(),
+        key = encode_double_quoted_attribute(key),
+        alt = encode_double_quoted_attribute(alt),
+        source = result.source_attributes(),
+        x_em = result.x_em,
+        y_em = result.y_em,
+        vertical_align = result.vertical_align(),
+    )
+}
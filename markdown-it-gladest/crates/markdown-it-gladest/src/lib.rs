@@ -1,6 +1,12 @@
-use std::sync::Mutex;
-
-use gladest_engine::{FontConfig, FontSource, RenderEngine, RenderFormat};
+use std::{sync::Mutex, time::Duration};
+
+use gladest_engine::{
+    expand_tilde,
+    html::{self, HtmlOptions},
+    is_inline_delimiter, read_font_names, strip_delimiters, Color, FontConfig, FontSource,
+    FormulaRenderResult, InputSyntax, Margins, RenderEngine, RenderError, RenderFormat, TextDir,
+    DEFAULT_CLASS_PREFIX, DEFAULT_MAX_PIXELS, DEFAULT_MITEX_VERSION,
+};
 use html_escape::encode_text;
 use once_cell::sync::Lazy;
 
@@ -13,21 +19,63 @@ struct EngineWithConfig {
 
 static RENDER_ENGINE: Lazy<Mutex<Option<EngineWithConfig>>> = Lazy::new(|| Mutex::new(None));
 
-/// Expands tilde in file paths (similar to main.rs)
-fn expand_tilde(path: &str) -> String {
-    if path.starts_with("~/") {
-        if let Ok(home) = std::env::var("HOME") {
-            path.replacen("~", &home, 1)
-        } else {
-            path.to_string()
+/// Engines replaced by a config change, least recently used first. Switching back to one
+/// of their configs reuses it together with its render cache.
+static RETAINED_ENGINES: Lazy<Mutex<Vec<EngineWithConfig>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Most engines kept in [`RETAINED_ENGINES`]; each holds its own font data
+const MAX_RETAINED_ENGINES: usize = 3;
+
+/// Parse a single `{ type: "system"|"file", value: string }` font source.
+/// `label` names the font in error messages (e.g. "Body font").
+fn parse_font_source(
+    cx: &mut FunctionContext,
+    font_obj: Handle<JsObject>,
+    label: &str,
+) -> NeonResult<FontSource> {
+    let font_type = font_obj
+        .get::<JsString, _, _>(cx, "type")
+        .map(|s| s.value(cx))
+        .unwrap_or_default();
+    let font_value = font_obj
+        .get::<JsString, _, _>(cx, "value")
+        .map(|s| s.value(cx))
+        .unwrap_or_default();
+
+    match font_type.as_str() {
+        "system" => Ok(FontSource::System(font_value)),
+        "file" => {
+            let expanded_path = expand_tilde(&font_value);
+            if !std::path::Path::new(&expanded_path).exists() {
+                return cx.throw_error(format!("{} file does not exist: {}", label, expanded_path));
+            }
+            Ok(FontSource::File(expanded_path, None))
         }
-    } else if path == "~" {
-        std::env::var("HOME").unwrap_or_else(|_| path.to_string())
-    } else {
-        path.to_string()
+        _ => cx.throw_error(format!(
+            "Invalid {} type: {}",
+            label.to_lowercase(),
+            font_type
+        )),
     }
 }
 
+/// Parse an optional array of font sources used as fallbacks
+fn parse_font_fallbacks(
+    cx: &mut FunctionContext,
+    fonts_obj: Handle<JsObject>,
+    key: &str,
+    label: &str,
+) -> NeonResult<Vec<FontSource>> {
+    let mut fallbacks = Vec::new();
+    if let Ok(array) = fonts_obj.get::<JsArray, _, _>(cx, key) {
+        for font_val in array.to_vec(cx)? {
+            let font_obj = font_val.downcast_or_throw::<JsObject, _>(cx)?;
+            fallbacks.push(parse_font_source(cx, font_obj, label)?);
+        }
+    }
+    Ok(fallbacks)
+}
+
 /// Parse font configuration from JavaScript object
 fn parse_font_config(
     cx: &mut FunctionContext,
@@ -39,81 +87,234 @@ fn parse_font_config(
 
     // Parse body font
     if let Ok(body_font_obj) = fonts_obj.get::<JsObject, _, _>(cx, "bodyFont") {
-        let font_type = body_font_obj
-            .get::<JsString, _, _>(cx, "type")
-            .map(|s| s.value(cx))
-            .unwrap_or_default();
-        let font_value = body_font_obj
-            .get::<JsString, _, _>(cx, "value")
-            .map(|s| s.value(cx))
-            .unwrap_or_default();
-
-        match font_type.as_str() {
-            "system" => {
-                body_font = FontSource::System(font_value);
-                has_system_font = true;
-            }
-            "file" => {
-                let expanded_path = expand_tilde(&font_value);
-                if !std::path::Path::new(&expanded_path).exists() {
-                    return cx
-                        .throw_error(format!("Body font file does not exist: {}", expanded_path));
-                }
-                body_font = FontSource::File(expanded_path);
-            }
-            _ => {
-                return cx.throw_error(format!("Invalid body font type: {}", font_type));
-            }
-        }
+        body_font = parse_font_source(cx, body_font_obj, "Body font")?;
+        has_system_font |= matches!(body_font, FontSource::System(_));
     }
 
     // Parse math font
     if let Ok(math_font_obj) = fonts_obj.get::<JsObject, _, _>(cx, "mathFont") {
-        let font_type = math_font_obj
-            .get::<JsString, _, _>(cx, "type")
-            .map(|s| s.value(cx))
-            .unwrap_or_default();
-        let font_value = math_font_obj
-            .get::<JsString, _, _>(cx, "value")
-            .map(|s| s.value(cx))
-            .unwrap_or_default();
-
-        match font_type.as_str() {
-            "system" => {
-                math_font = FontSource::System(font_value);
-                has_system_font = true;
-            }
-            "file" => {
-                let expanded_path = expand_tilde(&font_value);
-                if !std::path::Path::new(&expanded_path).exists() {
-                    return cx
-                        .throw_error(format!("Math font file does not exist: {}", expanded_path));
-                }
-                math_font = FontSource::File(expanded_path);
+        math_font = parse_font_source(cx, math_font_obj, "Math font")?;
+        has_system_font |= matches!(math_font, FontSource::System(_));
+    }
+
+    // Parse fallback chains
+    let body_font_fallbacks =
+        parse_font_fallbacks(cx, fonts_obj, "bodyFontFallbacks", "Body fallback font")?;
+    let math_font_fallbacks =
+        parse_font_fallbacks(cx, fonts_obj, "mathFontFallbacks", "Math fallback font")?;
+
+    has_system_font |= body_font_fallbacks
+        .iter()
+        .chain(&math_font_fallbacks)
+        .any(|source| matches!(source, FontSource::System(_)));
+
+    // Parse text color
+    let mut text_color = None;
+    if let Ok(color_val) = fonts_obj.get::<JsString, _, _>(cx, "textColor") {
+        let color_str = color_val.value(cx);
+        match color_str.parse::<Color>() {
+            Ok(color) => text_color = Some(color),
+            Err(e) => return cx.throw_error(e.to_string()),
+        }
+    }
+
+    // Parse background color
+    let mut background = None;
+    if let Ok(color_val) = fonts_obj.get::<JsString, _, _>(cx, "background") {
+        let color_str = color_val.value(cx);
+        match color_str.parse::<Color>() {
+            Ok(color) => background = Some(color),
+            Err(e) => return cx.throw_error(format!("Invalid background: {}", e)),
+        }
+    }
+
+    // Parse input syntax
+    let mut input_syntax = InputSyntax::Latex;
+    if let Ok(syntax_val) = fonts_obj.get::<JsString, _, _>(cx, "inputSyntax") {
+        let syntax_str = syntax_val.value(cx);
+        match syntax_str.as_str() {
+            "latex" => input_syntax = InputSyntax::Latex,
+            "typst" => input_syntax = InputSyntax::Typst,
+            _ => {
+                return cx.throw_error(format!("Invalid input syntax: {}", syntax_str));
             }
+        }
+    }
+
+    // Parse text direction
+    let mut text_dir = TextDir::Ltr;
+    if let Ok(dir_val) = fonts_obj.get::<JsString, _, _>(cx, "textDir") {
+        let dir_str = dir_val.value(cx);
+        match dir_str.as_str() {
+            "ltr" => text_dir = TextDir::Ltr,
+            "rtl" => text_dir = TextDir::Rtl,
             _ => {
-                return cx.throw_error(format!("Invalid math font type: {}", font_type));
+                return cx.throw_error(format!("Invalid text direction: {}", dir_str));
             }
         }
     }
 
+    let normalize_input = fonts_obj
+        .get::<JsBoolean, _, _>(cx, "normalizeInput")
+        .map(|b| b.value(cx))
+        .unwrap_or(false);
+
+    // Parse preamble
+    let preamble = fonts_obj
+        .get::<JsString, _, _>(cx, "preamble")
+        .map(|s| s.value(cx))
+        .ok();
+
+    // Parse font size
+    let mut font_size_pt = FontConfig::default().font_size_pt;
+    if let Ok(size_val) = fonts_obj.get::<JsNumber, _, _>(cx, "fontSize") {
+        let size = size_val.value(cx);
+        if size > 0.0 {
+            font_size_pt = size;
+        }
+    }
+
+    // Parse margins
+    let mut margin_em = Margins::default();
+    if let Ok(margins_obj) = fonts_obj.get::<JsObject, _, _>(cx, "margins") {
+        let mut side = |name: &str| -> Option<f64> {
+            margins_obj
+                .get::<JsNumber, _, _>(cx, name)
+                .map(|n| n.value(cx))
+                .ok()
+                .filter(|v| *v >= 0.0)
+        };
+        margin_em = Margins {
+            top: side("top"),
+            bottom: side("bottom"),
+            left: side("left"),
+            right: side("right"),
+        };
+    }
+
+    // Parse the width formulas are broken into lines at
+    let max_width_em = fonts_obj
+        .get::<JsNumber, _, _>(cx, "maxWidthEm")
+        .map(|n| n.value(cx))
+        .ok()
+        .filter(|v| *v > 0.0);
+
+    // Parse trim flag
+    let trim = fonts_obj
+        .get::<JsBoolean, _, _>(cx, "trim")
+        .map(|b| b.value(cx))
+        .unwrap_or(false);
+
+    // Parse SVG minification flag
+    let minify_svg = fonts_obj
+        .get::<JsBoolean, _, _>(cx, "minifySvg")
+        .map(|b| b.value(cx))
+        .unwrap_or(false);
+
+    // Parse the raster size limit
+    let max_pixels = fonts_obj
+        .get::<JsNumber, _, _>(cx, "maxPixels")
+        .map(|n| n.value(cx))
+        .ok()
+        .filter(|v| *v > 0.0)
+        .map_or(DEFAULT_MAX_PIXELS, |v| v as u64);
+
+    let retry_with_fallback_font = fonts_obj
+        .get::<JsBoolean, _, _>(cx, "retryWithFallbackFont")
+        .map(|b| b.value(cx))
+        .unwrap_or(false);
+    let allow_empty_render = fonts_obj
+        .get::<JsBoolean, _, _>(cx, "allowEmptyRender")
+        .map(|b| b.value(cx))
+        .unwrap_or(false);
+
+    // Parse the per-formula compile timeout
+    let formula_timeout = fonts_obj
+        .get::<JsNumber, _, _>(cx, "formulaTimeoutMs")
+        .map(|n| n.value(cx))
+        .ok()
+        .filter(|v| *v > 0.0)
+        .map(|v| Duration::from_millis(v as u64));
+
+    // Parse offline package settings
+    let offline = fonts_obj
+        .get::<JsBoolean, _, _>(cx, "offline")
+        .map(|b| b.value(cx))
+        .unwrap_or(false);
+    let package_dir = fonts_obj
+        .get::<JsString, _, _>(cx, "packageDir")
+        .map(|s| s.value(cx))
+        .ok();
+    let mitex_version = fonts_obj
+        .get::<JsString, _, _>(cx, "mitexVersion")
+        .map(|s| s.value(cx))
+        .unwrap_or_else(|_| DEFAULT_MITEX_VERSION.to_string());
+
+    // Parse further packages to import
+    let mut extra_imports = Vec::new();
+    if let Ok(array) = fonts_obj.get::<JsArray, _, _>(cx, "extraImports") {
+        for spec in array.to_vec(cx)? {
+            extra_imports.push(spec.downcast_or_throw::<JsString, _>(cx)?.value(cx));
+        }
+    }
+
     // Automatically determine include_system_fonts based on whether any system fonts are used
     let include_system_fonts = has_system_font;
 
-    Ok(FontConfig {
-        body_font,
-        math_font,
-        include_system_fonts,
-    })
+    let font_config = FontConfig::builder()
+        .body_font(body_font)
+        .math_font(math_font)
+        .body_font_fallbacks(body_font_fallbacks)
+        .math_font_fallbacks(math_font_fallbacks)
+        .include_system_fonts(include_system_fonts)
+        .text_color(text_color)
+        .background(background)
+        .input_syntax(input_syntax)
+        .text_dir(text_dir)
+        .normalize_input(normalize_input)
+        .preamble(preamble)
+        .font_size_pt(font_size_pt)
+        .margin_em(margin_em)
+        .max_width_em(max_width_em)
+        .trim(trim)
+        .minify_svg(minify_svg)
+        .max_pixels(max_pixels)
+        .retry_with_fallback_font(retry_with_fallback_font)
+        .allow_empty_render(allow_empty_render)
+        .formula_timeout(formula_timeout)
+        .offline(offline)
+        .package_dir(package_dir)
+        .mitex_version(mitex_version)
+        .extra_imports(extra_imports)
+        .build();
+    if let Err(e) = font_config.check_mitex_version() {
+        return cx.throw_error(e.to_string());
+    }
+    if let Err(e) = font_config.check_extra_imports() {
+        return cx.throw_error(e.to_string());
+    }
+    if let Err(e) = font_config.check_font_files() {
+        return cx.throw_error(e.to_string());
+    }
+    Ok(font_config)
+}
+
+/// Per-call rendering options parsed from the JS options object
+struct RenderOptions {
+    format: RenderFormat,
+    ppi: Option<f32>,
+    font_config: Option<FontConfig>,
+    class_prefix: String,
 }
 
 fn get_options(
     cx: &mut FunctionContext,
     options_arg: Handle<JsValue>,
-) -> NeonResult<(RenderFormat, Option<f32>, Option<FontConfig>)> {
+) -> NeonResult<RenderOptions> {
     let mut format = RenderFormat::Svg;
     let mut ppi = None;
     let mut font_config = None;
+    let mut class_prefix = DEFAULT_CLASS_PREFIX.to_string();
 
     if let Ok(options_obj) = options_arg.downcast::<JsObject, _>(cx) {
         // Get format
@@ -123,6 +324,14 @@ fn get_options(
                 format = RenderFormat::Png;
             } else if format_str.eq_ignore_ascii_case("svg") {
                 format = RenderFormat::Svg;
+            } else if format_str.eq_ignore_ascii_case("jpeg")
+                || format_str.eq_ignore_ascii_case("jpg")
+            {
+                format = RenderFormat::Jpeg;
+            } else if format_str.eq_ignore_ascii_case("webp") {
+                format = RenderFormat::WebP;
+            } else if format_str.eq_ignore_ascii_case("avif") {
+                format = RenderFormat::Avif;
             }
             // Ignore invalid values, keep default
         }
@@ -139,9 +348,19 @@ fn get_options(
         if let Ok(fonts_obj) = options_obj.get::<JsObject, _, _>(cx, "fonts") {
             font_config = Some(parse_font_config(cx, fonts_obj)?);
         }
+
+        // Get class prefix
+        if let Ok(prefix_val) = options_obj.get::<JsString, _, _>(cx, "classPrefix") {
+            class_prefix = prefix_val.value(cx);
+        }
     }
 
-    Ok((format, ppi, font_config))
+    Ok(RenderOptions {
+        format,
+        ppi,
+        font_config,
+        class_prefix,
+    })
 }
 
 /// Get or create render engine with the appropriate font configuration
@@ -150,36 +369,152 @@ fn get_or_create_engine(
 ) -> anyhow::Result<&'static Mutex<Option<EngineWithConfig>>> {
     let mut engine_guard = RENDER_ENGINE.lock().unwrap();
 
-    if let Some(current) = engine_guard.as_ref() {
-        let configs_match = match (&current.config, &font_config) {
-            (None, None) => true,
-            (Some(a), Some(b)) => a == b,
-            _ => false,
+    let is_current = engine_guard
+        .as_ref()
+        .is_some_and(|current| current.config == font_config);
+    if !is_current {
+        switch_engine(&mut engine_guard, font_config);
+    }
+
+    drop(engine_guard);
+    Ok(&RENDER_ENGINE)
+}
+
+/// Make the engine for `font_config` the current one, retaining the engine it replaces.
+/// A retained engine with the same config is reused, otherwise a new one is built.
+fn switch_engine(current: &mut Option<EngineWithConfig>, font_config: Option<FontConfig>) {
+    let mut retained = RETAINED_ENGINES.lock().unwrap();
+
+    let reused = retained
+        .iter()
+        .position(|engine| engine.config == font_config)
+        .map(|position| retained.remove(position));
+    let next = reused.unwrap_or_else(|| {
+        let engine = match font_config.clone() {
+            Some(config) => RenderEngine::with_font_config(config),
+            None => RenderEngine::new(),
         };
+        EngineWithConfig {
+            engine,
+            config: font_config,
+        }
+    });
 
-        if configs_match {
-            drop(engine_guard);
-            return Ok(&RENDER_ENGINE);
+    if let Some(previous) = current.replace(next) {
+        retained.push(previous);
+        if retained.len() > MAX_RETAINED_ENGINES {
+            retained.remove(0);
         }
     }
+}
+
+/// Pick the bare formula and inline/display mode from a formula and its delimiter
+/// ("$", "$$", "\\(" or "\\["). A formula still wrapped in delimiters is unwrapped,
+/// and those delimiters decide the mode.
+fn resolve_formula(formula: String, delimiter: &str) -> (String, bool) {
+    match strip_delimiters(&formula) {
+        Some((inner, is_inline)) => (inner.to_string(), is_inline),
+        None => (formula, is_inline_delimiter(delimiter)),
+    }
+}
 
-    let engine = if let Some(config) = font_config.clone() {
-        RenderEngine::with_font_config(config)
-    } else {
-        RenderEngine::new()
-    };
+/// Wrap an error in the HTML span shown in place of a formula
+fn error_span(title: &str, message: &str) -> String {
+    format!(
+        r#"<span class="gladst-error" title="{}">{}</span>"#,
+        encode_text(title),
+        message
+    )
+}
 
-    *engine_guard = Some(EngineWithConfig {
-        engine,
-        config: font_config,
-    });
+fn engine_not_initialized_html(formula: &str) -> String {
+    format!(
+        r#"<span class="gladst-error" title="Engine not initialized">Gladst Error: Engine not initialized. Formula: {}</span>"#,
+        encode_text(formula)
+    )
+}
 
-    drop(engine_guard);
-    Ok(&RENDER_ENGINE)
+fn engine_error_html(formula: &str, error: &anyhow::Error) -> String {
+    let error_message = format!(
+        "Gladst Error: Failed to create render engine. Formula: {}",
+        encode_text(formula)
+    );
+    error_span(&error.to_string(), &error_message)
+}
+
+/// Short description of what went wrong, shown to JS callers
+fn error_summary(error: &RenderError) -> &'static str {
+    match error {
+        RenderError::Compile { .. } => "Failed to compile formula",
+        RenderError::FontNotFound(_) => "Font not found",
+        RenderError::PackageUnavailable(_) => "Failed to load the mitex package",
+        RenderError::EmptyFormula => "Formula is empty",
+        RenderError::EmptyRender(_) => "Formula rendered to an empty image",
+        RenderError::Timeout { .. } => "Formula compilation timed out",
+        RenderError::TooLarge { .. } => "Formula image too large",
+        RenderError::Encode { .. } => "Failed to encode the rendered image",
+        RenderError::Write { .. } => "Failed to write the rendered image",
+        RenderError::InvalidFont(_) => "Failed to read font data",
+        RenderError::InvalidColor(_) => "Invalid color",
+        RenderError::InvalidSelector(_) => "Invalid CSS selector",
+        RenderError::InvalidPackageVersion(_) => "Invalid package version",
+        RenderError::InvalidPackageSpec(_) => "Invalid package",
+    }
+}
+
+/// Turn a render result into HTML, falling back to an error span
+fn render_result_html(
+    result: Result<FormulaRenderResult, RenderError>,
+    formula: &str,
+    class_prefix: &str,
+) -> String {
+    match result {
+        Ok(render_result) => render_result.to_html_with_class(class_prefix),
+        Err(e) => {
+            // The engine already logs compile failures as errors
+            log::debug!("Error rendering formula: {:?}", e);
+            // Return an error message string to JS, maybe styled
+            let error_message = format!(
+                "Gladst Error: {}. Check console. Formula: {}",
+                error_summary(&e),
+                encode_text(formula)
+            );
+            error_span(&e.to_string(), &error_message)
+        }
+    }
+}
+
+/// Render one formula with the shared engine, returning HTML or an error span
+fn render_html(formula: &str, is_inline: bool, options: RenderOptions) -> String {
+    let RenderOptions {
+        format,
+        ppi,
+        font_config,
+        class_prefix,
+    } = options;
+
+    let result = match get_or_create_engine(font_config) {
+        Ok(engine_ref) => {
+            let engine_guard = engine_ref.lock().unwrap();
+            if let Some(ref engine_with_config) = *engine_guard {
+                engine_with_config
+                    .engine
+                    .render_formula(formula, is_inline, format, ppi)
+            } else {
+                return engine_not_initialized_html(formula);
+            }
+        }
+        Err(e) => {
+            log::error!("Error creating render engine: {:?}", e);
+            return engine_error_html(formula, &e);
+        }
+    };
+
+    render_result_html(result, formula, &class_prefix)
 }
 
 // Neon function to render a single formula
-// Args: formula (String), delimiter (String: "$$" or "$"), options (Object: { format?: "svg"|"png", ppi?: number, fonts?: FontConfig })
+// Args: formula (String), delimiter (String: "$$", "$", "\\[" or "\\("), options (Object: { format?: "svg"|"png"|"jpeg"|"webp"|"avif", ppi?: number, fonts?: FontConfig, classPrefix?: string })
 // Returns: String (HTML <img> tag or error message)
 fn render_latex(mut cx: FunctionContext) -> JsResult<JsString> {
     // 1. Get arguments
@@ -190,65 +525,314 @@ fn render_latex(mut cx: FunctionContext) -> JsResult<JsString> {
         .unwrap_or_else(|| cx.undefined().upcast()); // Handle missing options
 
     // 2. Parse options
-    let (format, ppi, font_config) = get_options(&mut cx, options_arg)?;
+    let options = get_options(&mut cx, options_arg)?;
 
     // 3. Determine environment class based on delimiter
-    let is_inline = delimiter != "$$";
+    let (formula, is_inline) = resolve_formula(formula, &delimiter);
 
-    // 4. Get or create engine and render
-    let result = match get_or_create_engine(font_config) {
+    // 4. Render and format output
+    let html = render_html(&formula, is_inline, options);
+    Ok(cx.string(html))
+}
+
+// Neon function to render a single formula off the main thread
+// Args: same as renderLatex
+// Returns: Promise<String> (HTML <img> tag or error message)
+fn render_latex_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let formula = cx.argument::<JsString>(0)?.value(&mut cx);
+    let delimiter = cx.argument::<JsString>(1)?.value(&mut cx);
+    let options_arg = cx
+        .argument_opt(2)
+        .unwrap_or_else(|| cx.undefined().upcast());
+
+    // Options are read on the main thread, JS values can't leave it
+    let options = get_options(&mut cx, options_arg)?;
+    let (formula, is_inline) = resolve_formula(formula, &delimiter);
+
+    let promise = cx
+        .task(move || render_html(&formula, is_inline, options))
+        .promise(|mut cx, html| Ok(cx.string(html)));
+
+    Ok(promise)
+}
+
+// Neon function to render a single formula without building HTML
+// Args: same as renderLatex
+// Returns: Object { data: Buffer, format: "svg"|"png"|"jpeg"|"webp"|"avif", xEm, yEm, depthEm: number, isInline: boolean }
+// Throws: JS error if the formula fails to render
+fn render_latex_raw(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let formula = cx.argument::<JsString>(0)?.value(&mut cx);
+    let delimiter = cx.argument::<JsString>(1)?.value(&mut cx);
+    let options_arg = cx
+        .argument_opt(2)
+        .unwrap_or_else(|| cx.undefined().upcast());
+
+    let RenderOptions {
+        format,
+        ppi,
+        font_config,
+        ..
+    } = get_options(&mut cx, options_arg)?;
+    let (formula, is_inline) = resolve_formula(formula, &delimiter);
+
+    let engine_ref = match get_or_create_engine(font_config) {
+        Ok(engine_ref) => engine_ref,
+        Err(e) => return cx.throw_error(format!("Failed to create render engine: {}", e)),
+    };
+    let result = match engine_ref.lock().unwrap().as_ref() {
+        Some(engine_with_config) => engine_with_config
+            .engine
+            .render_formula(&formula, is_inline, format, ppi),
+        None => return cx.throw_error("Engine not initialized"),
+    };
+    let render_result = match result {
+        Ok(render_result) => render_result,
+        Err(e) => return cx.throw_error(format!("{}: {}", error_summary(&e), e)),
+    };
+
+    let format_name = match render_result.format {
+        RenderFormat::Svg => "svg",
+        RenderFormat::Png => "png",
+        RenderFormat::Jpeg => "jpeg",
+        RenderFormat::WebP => "webp",
+        RenderFormat::Avif => "avif",
+    };
+
+    let obj = cx.empty_object();
+    let data = JsBuffer::from_slice(&mut cx, &render_result.data)?;
+    obj.set(&mut cx, "data", data)?;
+    let format_value = cx.string(format_name);
+    obj.set(&mut cx, "format", format_value)?;
+    let x_pt = cx.number(render_result.x_pt);
+    obj.set(&mut cx, "xPt", x_pt)?;
+    let y_pt = cx.number(render_result.y_pt);
+    obj.set(&mut cx, "yPt", y_pt)?;
+    let x_em = cx.number(render_result.x_em);
+    obj.set(&mut cx, "xEm", x_em)?;
+    let y_em = cx.number(render_result.y_em);
+    obj.set(&mut cx, "yEm", y_em)?;
+    let depth_em = cx.number(render_result.depth_em);
+    obj.set(&mut cx, "depthEm", depth_em)?;
+    let is_inline = cx.boolean(render_result.is_inline);
+    obj.set(&mut cx, "isInline", is_inline)?;
+    let used_fallback = cx.boolean(render_result.used_fallback);
+    obj.set(&mut cx, "usedFallback", used_fallback)?;
+    let glyph_count = cx.number(render_result.glyph_count as f64);
+    obj.set(&mut cx, "glyphCount", glyph_count)?;
+    let warnings = cx.empty_array();
+    for (i, warning) in render_result.warnings.iter().enumerate() {
+        let message = cx.string(&warning.message);
+        warnings.set(&mut cx, i as u32, message)?;
+    }
+    obj.set(&mut cx, "warnings", warnings)?;
+
+    Ok(obj)
+}
+
+/// Read the `(formula, is_inline)` pairs of a batch from its JS array
+fn parse_batch(
+    cx: &mut FunctionContext,
+    items: Handle<JsArray>,
+) -> NeonResult<Vec<(String, bool)>> {
+    let items = items.to_vec(cx)?;
+    let mut formulas = Vec::with_capacity(items.len());
+    for item in items {
+        let item = item.downcast_or_throw::<JsObject, _>(cx)?;
+        let formula = item.get::<JsString, _, _>(cx, "formula")?.value(cx);
+        let delimiter = item.get::<JsString, _, _>(cx, "delimiter")?.value(cx);
+        formulas.push(resolve_formula(formula, &delimiter));
+    }
+    Ok(formulas)
+}
+
+/// Render a batch with the shared engine, returning HTML or an error span per formula
+/// in input order. The formulas are rendered in parallel across the cores.
+fn render_batch_html(formulas: &[(String, bool)], options: RenderOptions) -> Vec<String> {
+    let RenderOptions {
+        format,
+        ppi,
+        font_config,
+        class_prefix,
+    } = options;
+
+    // The engine lock is held once for the whole batch; the engine is `Sync`, so the
+    // worker threads share it through the guard
+    match get_or_create_engine(font_config) {
         Ok(engine_ref) => {
             let engine_guard = engine_ref.lock().unwrap();
-            if let Some(ref engine_with_config) = *engine_guard {
-                engine_with_config
+            match engine_guard.as_ref() {
+                Some(engine_with_config) => engine_with_config
                     .engine
-                    .render_formula(&formula, is_inline, format, ppi)
-            } else {
-                return Ok(cx.string(format!(
-                    r#"<span class="gladst-error" title="Engine not initialized">Gladst Error: Engine not initialized. Formula: {}</span>"#,
-                    encode_text(&formula)
-                )));
+                    .render_batch(formulas, format, ppi)
+                    .into_iter()
+                    .zip(formulas)
+                    .map(|(result, (formula, _))| {
+                        render_result_html(result, formula, &class_prefix)
+                    })
+                    .collect(),
+                None => formulas
+                    .iter()
+                    .map(|(formula, _)| engine_not_initialized_html(formula))
+                    .collect(),
             }
         }
         Err(e) => {
-            eprintln!("Error creating render engine: {:?}", e);
-            let error_message = format!(
-                "Gladst Error: Failed to create render engine. Formula: {}",
-                encode_text(&formula)
-            );
-            return Ok(cx.string(format!(
-                r#"<span class="gladst-error" title="{}">{}</span>"#,
-                encode_text(&e.to_string()),
-                error_message
-            )));
+            log::error!("Error creating render engine: {:?}", e);
+            formulas
+                .iter()
+                .map(|(formula, _)| engine_error_html(formula, &e))
+                .collect()
         }
-    };
+    }
+}
 
-    // 5. Handle result and format output
-    match result {
-        Ok(render_result) => {
-            let html = render_result.to_html();
-            Ok(cx.string(html))
+/// A JS array of strings
+fn string_array<'a, C: Context<'a>>(cx: &mut C, strings: Vec<String>) -> JsResult<'a, JsArray> {
+    let array = cx.empty_array();
+    for (i, string) in strings.into_iter().enumerate() {
+        let value = cx.string(string);
+        array.set(cx, i as u32, value)?;
+    }
+    Ok(array)
+}
+
+// Neon function to render many formulas with a single engine lookup
+// Args: formulas (Array<{ formula: String, delimiter: String }>), options (same as renderLatex)
+// Returns: Array<String> (HTML <img> tag or error message per formula, in input order)
+fn render_latex_batch(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let items = cx.argument::<JsArray>(0)?;
+    let options_arg = cx
+        .argument_opt(1)
+        .unwrap_or_else(|| cx.undefined().upcast());
+
+    let options = get_options(&mut cx, options_arg)?;
+    let formulas = parse_batch(&mut cx, items)?;
+
+    let outputs = render_batch_html(&formulas, options);
+    string_array(&mut cx, outputs)
+}
+
+// Neon function to render many formulas off the main thread
+// Args: same as renderLatexBatch
+// Returns: Promise<Array<String>> (HTML <img> tag or error message per formula, in input order)
+fn render_latex_batch_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let items = cx.argument::<JsArray>(0)?;
+    let options_arg = cx
+        .argument_opt(1)
+        .unwrap_or_else(|| cx.undefined().upcast());
+
+    // Options and formulas are read on the main thread, JS values can't leave it
+    let options = get_options(&mut cx, options_arg)?;
+    let formulas = parse_batch(&mut cx, items)?;
+
+    let promise = cx
+        .task(move || render_batch_html(&formulas, options))
+        .promise(|mut cx, outputs| string_array(&mut cx, outputs));
+
+    Ok(promise)
+}
+
+// Neon function to render every formula element of an HTML document in one call
+// Args: html (String), options (same as renderLatex, plus selector?: string, displaySelector?: string, inlineSelector?: string, defaultEnv?: "inline"|"display", numberEquations?: boolean, keepSource?: boolean)
+// Returns: String (the document with each formula replaced by an <img> tag or an error marker)
+fn render_html_document(mut cx: FunctionContext) -> JsResult<JsString> {
+    let document = cx.argument::<JsString>(0)?.value(&mut cx);
+    let options_arg = cx
+        .argument_opt(1)
+        .unwrap_or_else(|| cx.undefined().upcast());
+
+    let RenderOptions {
+        format,
+        ppi,
+        font_config,
+        class_prefix,
+    } = get_options(&mut cx, options_arg)?;
+
+    let mut html_options = HtmlOptions {
+        format,
+        class_prefix,
+        ..HtmlOptions::default()
+    };
+    if let Some(ppi) = ppi {
+        html_options.ppi = ppi;
+    }
+    if let Ok(options_obj) = options_arg.downcast::<JsObject, _>(&mut cx) {
+        if let Ok(selector) = options_obj.get::<JsString, _, _>(&mut cx, "selector") {
+            let selector = selector.value(&mut cx);
+            match html::parse_selector(&selector) {
+                Ok(selector) => html_options.selector = selector,
+                Err(e) => return cx.throw_error(e.to_string()),
+            }
         }
-        Err(e) => {
-            // Log the error on the Rust side for debugging
-            eprintln!("Error rendering formula: {:?}", e);
-            // Return an error message string to JS, maybe styled
-            let error_message = format!(
-                "Gladst Error: Failed to render formula. Check console. Formula: {}",
-                encode_text(&formula)
-            );
-            Ok(cx.string(format!(
-                r#"<span class="gladst-error" title="{}">{}</span>"#,
-                encode_text(&e.to_string()),
-                error_message
-            )))
+        if let Ok(selector) = options_obj.get::<JsString, _, _>(&mut cx, "displaySelector") {
+            let selector = selector.value(&mut cx);
+            match html::parse_selector(&selector) {
+                Ok(selector) => html_options.display_selector = selector,
+                Err(e) => return cx.throw_error(e.to_string()),
+            }
+        }
+        if let Ok(selector) = options_obj.get::<JsString, _, _>(&mut cx, "inlineSelector") {
+            let selector = selector.value(&mut cx);
+            match html::parse_selector(&selector) {
+                Ok(selector) => html_options.inline_selector = selector,
+                Err(e) => return cx.throw_error(e.to_string()),
+            }
+        }
+        if let Ok(env) = options_obj.get::<JsString, _, _>(&mut cx, "defaultEnv") {
+            html_options.default_inline = match env.value(&mut cx).as_str() {
+                "inline" => true,
+                "display" => false,
+                other => {
+                    return cx.throw_error(format!(
+                        "Invalid defaultEnv {:?}, expected \"inline\" or \"display\"",
+                        other
+                    ));
+                }
+            };
+        }
+        html_options.number_equations = options_obj
+            .get::<JsBoolean, _, _>(&mut cx, "numberEquations")
+            .map(|b| b.value(&mut cx))
+            .unwrap_or(false);
+        html_options.keep_source = options_obj
+            .get::<JsBoolean, _, _>(&mut cx, "keepSource")
+            .map(|b| b.value(&mut cx))
+            .unwrap_or(false);
+    }
+
+    let engine_ref = match get_or_create_engine(font_config) {
+        Ok(engine_ref) => engine_ref,
+        Err(e) => return cx.throw_error(format!("Failed to create render engine: {}", e)),
+    };
+    let engine_guard = engine_ref.lock().unwrap();
+    let Some(engine_with_config) = engine_guard.as_ref() else {
+        return cx.throw_error("Engine not initialized");
+    };
+
+    let (output, errors) = html::render_html(&document, &engine_with_config.engine, &html_options);
+    for error in &errors {
+        // The engine already logs compile failures as errors and the document marks the
+        // failed formulas
+        match error.line {
+            Some(line) => log::debug!(
+                "Error rendering formula #{} (line {}): {:?}",
+                error.index + 1,
+                line,
+                error.error
+            ),
+            None => log::debug!(
+                "Error rendering formula #{}: {:?}",
+                error.index + 1,
+                error.error
+            ),
         }
     }
+
+    Ok(cx.string(output))
 }
 
 // Neon function to set global font configuration
-// Args: fontConfig (Object: { bodyFont?: {type: "system"|"file", value: string}, mathFont?: {type: "system"|"file", value: string} })
+// Args: fontConfig (Object: { bodyFont?: {type: "system"|"file", value: string}, mathFont?: {type: "system"|"file", value: string}, bodyFontFallbacks?: Array<{type, value}>, mathFontFallbacks?: Array<{type, value}>, textColor?: string, background?: string, inputSyntax?: "latex"|"typst", textDir?: "ltr"|"rtl", normalizeInput?: boolean, preamble?: string, fontSize?: number, margins?: {top?, bottom?, left?, right?: number}, maxWidthEm?: number, trim?: boolean, minifySvg?: boolean, maxPixels?: number, retryWithFallbackFont?: boolean, allowEmptyRender?: boolean, formulaTimeoutMs?: number, offline?: boolean, packageDir?: string, mitexVersion?: string, extraImports?: string[] })
 // Returns: Boolean (success)
 fn set_font_config(mut cx: FunctionContext) -> JsResult<JsBoolean> {
     let fonts_arg = cx.argument::<JsValue>(0)?;
@@ -258,20 +842,13 @@ fn set_font_config(mut cx: FunctionContext) -> JsResult<JsBoolean> {
             Ok(font_config) => {
                 let mut engine_guard = RENDER_ENGINE.lock().unwrap();
 
-                let needs_update = match engine_guard.as_ref() {
-                    Some(current) => match &current.config {
-                        Some(current_config) => current_config != &font_config,
-                        None => true,
-                    },
-                    None => true,
-                };
+                let font_config = Some(font_config);
+                let needs_update = !engine_guard
+                    .as_ref()
+                    .is_some_and(|current| current.config == font_config);
 
                 if needs_update {
-                    let engine = RenderEngine::with_font_config(font_config.clone());
-                    *engine_guard = Some(EngineWithConfig {
-                        engine,
-                        config: Some(font_config),
-                    });
+                    switch_engine(&mut engine_guard, font_config);
                 }
 
                 Ok(cx.boolean(true))
@@ -283,9 +860,118 @@ fn set_font_config(mut cx: FunctionContext) -> JsResult<JsBoolean> {
     }
 }
 
+// Neon function to build the engine and load the mitex package ahead of the first render
+// Args: options (Object, same as renderLatex)
+// Returns: Boolean, true if a trivial formula rendered with these options
+fn warmup(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let options_arg = cx
+        .argument_opt(0)
+        .unwrap_or_else(|| cx.undefined().upcast());
+    let RenderOptions {
+        format,
+        ppi,
+        font_config,
+        ..
+    } = get_options(&mut cx, options_arg)?;
+
+    // The engine is reused when the config matches, and the render is cached afterwards,
+    // so warming up again is cheap
+    let rendered = match get_or_create_engine(font_config) {
+        Ok(engine_ref) => match engine_ref.lock().unwrap().as_ref() {
+            Some(engine_with_config) => match engine_with_config
+                .engine
+                .render_formula("x", true, format, ppi)
+            {
+                Ok(_) => true,
+                Err(e) => {
+                    log::warn!("Error warming up render engine: {:?}", e);
+                    false
+                }
+            },
+            None => false,
+        },
+        Err(e) => {
+            log::error!("Error creating render engine: {:?}", e);
+            false
+        }
+    };
+
+    Ok(cx.boolean(rendered))
+}
+
+// Neon function to drop the shared engine, the engines retained for earlier configs and
+// the font data they hold
+// Args: none
+// Returns: Boolean, true if an engine was dropped; the next render builds a new one
+fn dispose_engine(mut cx: FunctionContext) -> JsResult<JsBoolean> {
+    let engine = RENDER_ENGINE.lock().unwrap().take();
+    let retained = std::mem::take(&mut *RETAINED_ENGINES.lock().unwrap());
+    Ok(cx.boolean(engine.is_some() || !retained.is_empty()))
+}
+
+// Neon function to read the name table of a font file
+// Args: path (String), index (Number, optional face index in a collection, default 0)
+// Returns: Object with familyName, subfamilyName, fullName, postscriptName,
+//          typographicFamilyName and typographicSubfamilyName (missing names are omitted)
+fn read_font_names_js(mut cx: FunctionContext) -> JsResult<JsObject> {
+    let path = expand_tilde(&cx.argument::<JsString>(0)?.value(&mut cx));
+    let index = match cx.argument_opt(1) {
+        Some(value) if !value.is_a::<JsUndefined, _>(&mut cx) => {
+            let index = value
+                .downcast_or_throw::<JsNumber, _>(&mut cx)?
+                .value(&mut cx);
+            if index < 0.0 || index.fract() != 0.0 {
+                return cx.throw_range_error("Font index must be a non-negative integer");
+            }
+            index as usize
+        }
+        _ => 0,
+    };
+
+    let font_data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) => return cx.throw_error(format!("Failed to read font file {}: {}", path, e)),
+    };
+    let font_names = match read_font_names(&font_data, index) {
+        Ok(names) => names,
+        Err(e) => return cx.throw_error(format!("Failed to parse font file {}: {}", path, e)),
+    };
+
+    let result = cx.empty_object();
+    let names = [
+        ("familyName", font_names.family_name),
+        ("subfamilyName", font_names.subfamily_name),
+        ("fullName", font_names.full_name),
+        ("postscriptName", font_names.postscript_name),
+        ("typographicFamilyName", font_names.typographic_family_name),
+        (
+            "typographicSubfamilyName",
+            font_names.typographic_subfamily_name,
+        ),
+    ];
+    for (key, name) in names {
+        let Some(name) = name else { continue };
+        let value = cx.string(name);
+        result.set(&mut cx, key, value)?;
+    }
+
+    Ok(result)
+}
+
 #[neon::main]
 fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    // Warnings and errors go to stderr; RUST_LOG selects other levels
+    let _ = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"))
+        .try_init();
     cx.export_function("renderLatex", render_latex)?;
+    cx.export_function("renderLatexAsync", render_latex_async)?;
+    cx.export_function("renderLatexRaw", render_latex_raw)?;
+    cx.export_function("renderLatexBatch", render_latex_batch)?;
+    cx.export_function("renderLatexBatchAsync", render_latex_batch_async)?;
+    cx.export_function("renderHtml", render_html_document)?;
     cx.export_function("setFontConfig", set_font_config)?;
+    cx.export_function("warmup", warmup)?;
+    cx.export_function("disposeEngine", dispose_engine)?;
+    cx.export_function("readFontNames", read_font_names_js)?;
     Ok(())
 }
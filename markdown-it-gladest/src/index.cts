@@ -2,6 +2,7 @@ import type MarkdownIt from "markdown-it";
 import type {
   Options as MarkdownItOptions,
   StateBlock,
+  StateCore,
   StateInline,
   Token,
   Renderer,
@@ -20,6 +21,20 @@ interface FontSource {
   file?: string;
 }
 
+/**
+ * Page margins around a formula in em units
+ */
+interface Margins {
+  /** @default 0.455 */
+  top?: number;
+  /** @default 0.455 */
+  bottom?: number;
+  /** @default 0 */
+  left?: number;
+  /** @default 0 */
+  right?: number;
+}
+
 /**
  * Font configuration for rendering
  */
@@ -28,6 +43,89 @@ interface FontConfig {
   bodyFont?: FontSource;
   /** Math font configuration */
   mathFont?: FontSource;
+  /** Fonts tried in order for glyphs the body font lacks */
+  bodyFontFallbacks?: FontSource[];
+  /** Fonts tried in order for glyphs the math font lacks */
+  mathFontFallbacks?: FontSource[];
+  /** Formula text color as a hex code or Typst color name (e.g. "#e0e0e0") */
+  textColor?: string;
+  /**
+   * Solid background color as a hex code or Typst color name (e.g. "#ffffff"),
+   * useful for PNG in mail clients that handle transparency poorly
+   * @default transparent
+   */
+  background?: string;
+  /**
+   * Syntax of the formulas: LaTeX (converted via mitex) or native Typst math
+   * @default 'latex'
+   */
+  inputSyntax?: "latex" | "typst";
+  /**
+   * Text direction in formulas, e.g. `rtl` for Arabic or Hebrew labels
+   * @default 'ltr'
+   */
+  textDir?: "ltr" | "rtl";
+  /**
+   * NFC-normalize formulas. For LaTeX, also replace − and – with `-`, × ÷ · ± ≤ ≥ ≠
+   * with `\times` `\div` `\cdot` `\pm` `\leq` `\geq` `\neq`, and no-break spaces
+   * with spaces.
+   */
+  normalizeInput?: boolean;
+  /** Shared macro definitions (e.g. `\newcommand`) prepended to every formula */
+  preamble?: string;
+  /**
+   * Font size in points; rendered dimensions are reported relative to it
+   * @default 10
+   */
+  fontSize?: number;
+  /** Padding around formulas in em; unset sides keep the default spacing */
+  margins?: Margins;
+  /** Break formulas wider than this many em into lines at their operators */
+  maxWidthEm?: number;
+  /** Crop formulas to their visible ink, removing surrounding whitespace */
+  trim?: boolean;
+  /** Minify SVG output by dropping comments and redundant whitespace */
+  minifySvg?: boolean;
+  /**
+   * Largest image, in pixels, a formula may render to; bigger formulas fail
+   * instead of allocating it
+   * @default 100000000
+   */
+  maxPixels?: number;
+  /**
+   * Retry a formula that fails because of the math font (e.g. one without math
+   * tables) with the bundled New Computer Modern Math instead of failing
+   */
+  retryWithFallbackFont?: boolean;
+  /**
+   * Leave formulas that render to a zero-size image unrendered instead of failing
+   * them
+   */
+  allowEmptyRender?: boolean;
+  /**
+   * Fail a formula that takes longer than this many milliseconds to compile. The
+   * compilation cannot be interrupted and keeps running in the background, but
+   * rendering moves on.
+   */
+  formulaTimeoutMs?: number;
+  /**
+   * Never download packages; mitex must be in `packageDir` or the local Typst package cache
+   */
+  offline?: boolean;
+  /** Directory with Typst packages laid out as `<namespace>/<name>/<version>` */
+  packageDir?: string;
+  /**
+   * Version of the mitex package used for LaTeX input, as `major.minor.patch`
+   * @default '0.2.5'
+   */
+  mitexVersion?: string;
+  /**
+   * Further Typst packages to import, as `@namespace/name:version`, e.g.
+   * `@preview/whalogen:0.3.0` for chemical formulas. Their functions can be called
+   * from formulas in Typst syntax. They are resolved like mitex, so they must be
+   * downloadable or in `packageDir`.
+   */
+  extraImports?: string[];
 }
 
 /**
@@ -38,10 +136,10 @@ interface GladstPluginOptions {
    * Output format for the rendered formula.
    * @default 'svg'
    */
-  format?: "svg" | "png";
+  format?: "svg" | "png" | "jpeg" | "webp" | "avif";
 
   /**
-   * Pixels per inch (PPI) for PNG rendering.
+   * Pixels per inch (PPI) for PNG, JPEG and WebP rendering.
    * If not specified or null, the default PPI of the Rust engine is used.
    */
   ppi?: number | null;
@@ -50,14 +148,57 @@ interface GladstPluginOptions {
    * Font configuration for rendering
    */
   fonts?: FontConfig;
+
+  /**
+   * Class prefix of the generated <img> tags, rendered as
+   * `"<prefix> math"` or `"<prefix> displaymath"`.
+   * @default 'gladst'
+   */
+  classPrefix?: string;
+}
+
+/**
+ * Options of `renderHtml` in addition to the plugin options
+ */
+interface RenderHtmlOptions {
+  /**
+   * CSS selector of the elements holding formulas
+   * @default 'eq'
+   */
+  selector?: string;
+  /**
+   * CSS selector of formula elements that hold display math
+   * @default '[env=displaymath]'
+   */
+  displaySelector?: string;
+  /**
+   * CSS selector of formula elements that hold inline math, unless they also
+   * match `displaySelector`
+   * @default '[env=math]'
+   */
+  inlineSelector?: string;
+  /**
+   * How formula elements matching neither selector render, e.g. an `<eq>`
+   * without an `env` attribute or with an unknown one
+   * @default 'inline'
+   */
+  defaultEnv?: "inline" | "display";
+  /** Number display formulas (1), (2), ... in document order */
+  numberEquations?: boolean;
+  /**
+   * Keep each formula's original text and env in `data-gladst-source` and
+   * `data-gladst-env` attributes of its image
+   */
+  keepSource?: boolean;
 }
 
 /**
  * Internal representation of parsed options passed to Rust.
  */
 interface InternalRustOptions {
-  format: "svg" | "png";
+  format: "svg" | "png" | "jpeg" | "webp" | "avif";
   ppi: number | null;
+  classPrefix?: string;
   fonts?: {
     bodyFont?: {
       type: "system" | "file";
@@ -67,16 +208,81 @@ interface InternalRustOptions {
       type: "system" | "file";
       value: string;
     };
+    bodyFontFallbacks?: Array<{
+      type: "system" | "file";
+      value: string;
+    }>;
+    mathFontFallbacks?: Array<{
+      type: "system" | "file";
+      value: string;
+    }>;
+    textColor?: string;
+    background?: string;
+    inputSyntax?: "latex" | "typst";
+    textDir?: "ltr" | "rtl";
+    normalizeInput?: boolean;
+    preamble?: string;
+    fontSize?: number;
+    margins?: Margins;
+    maxWidthEm?: number;
+    trim?: boolean;
+    minifySvg?: boolean;
+    maxPixels?: number;
+    retryWithFallbackFont?: boolean;
+    allowEmptyRender?: boolean;
+    formulaTimeoutMs?: number;
+    offline?: boolean;
+    packageDir?: string;
+    mitexVersion?: string;
+    extraImports?: string[];
     // Note: includeSystemFonts is automatically inferred by Rust, not passed from JS
   };
 }
 
+/**
+ * Names read from a font file's name table
+ */
+export interface FontNames {
+  familyName?: string;
+  subfamilyName?: string;
+  fullName?: string;
+  postscriptName?: string;
+  typographicFamilyName?: string;
+  typographicSubfamilyName?: string;
+}
+
+/**
+ * A rendered formula before it is turned into HTML
+ */
+export interface RawRenderResult {
+  /** Encoded image bytes */
+  data: Buffer;
+  format: "svg" | "png" | "jpeg" | "webp" | "avif";
+  /** Width in points */
+  xPt: number;
+  /** Height in points */
+  yPt: number;
+  /** Width in em */
+  xEm: number;
+  /** Height in em */
+  yEm: number;
+  /** Distance from the text baseline to the bottom of the image in em */
+  depthEm: number;
+  isInline: boolean;
+  /** Rendered with the fallback math font, see `retryWithFallbackFont` */
+  usedFallback: boolean;
+  /** Number of glyphs in the formula, a rough measure of its complexity */
+  glyphCount: number;
+  /** Non-fatal compiler warnings, e.g. about deprecated syntax */
+  warnings: string[];
+}
+
 // Use this declaration to assign types to the addon's exports.
 declare module "./load.cjs" {
   /**
    * Renders a LaTeX formula string into an HTML img tag (exported from Rust).
    * @param formula The LaTeX code (without delimiters).
-   * @param delimiter The delimiter used ("$$" or "$").
+   * @param delimiter The delimiter used ("$$", "$", "\\[" or "\\(").
    * @param options Rendering options (format, ppi, fonts).
    * @returns HTML string (<img> tag or error message/span).
    */
@@ -92,17 +298,110 @@ declare module "./load.cjs" {
    * @returns boolean indicating success
    */
   function setFontConfig(fontConfig: InternalRustOptions["fonts"]): boolean;
+
+  /**
+   * Builds the engine for the given options and renders a trivial formula.
+   * @param options Rendering options (format, ppi, fonts).
+   * @returns boolean indicating success
+   */
+  function warmup(options: InternalRustOptions): boolean;
+
+  /**
+   * Drops the shared engine and the engines retained for earlier configs.
+   * @returns boolean indicating whether an engine existed
+   */
+  function disposeEngine(): boolean;
+
+  /**
+   * Renders a LaTeX formula on a worker thread (exported from Rust).
+   * @param formula The LaTeX code (without delimiters).
+   * @param delimiter The delimiter used ("$$", "$", "\\[" or "\\(").
+   * @param options Rendering options (format, ppi, fonts).
+   * @returns Promise of the HTML string (<img> tag or error message/span).
+   */
+  function renderLatexAsync(
+    formula: string,
+    delimiter: string,
+    options: InternalRustOptions
+  ): Promise<string>;
+
+  /**
+   * Renders a LaTeX formula to image bytes and dimensions (exported from Rust).
+   * @param formula The LaTeX code (without delimiters).
+   * @param delimiter The delimiter used ("$$", "$", "\\[" or "\\(").
+   * @param options Rendering options (format, ppi, fonts).
+   * @returns The encoded image and its size; throws if rendering fails.
+   */
+  function renderLatexRaw(
+    formula: string,
+    delimiter: string,
+    options: InternalRustOptions
+  ): RawRenderResult;
+
+  /**
+   * Renders many LaTeX formulas in one call (exported from Rust).
+   * @param formulas Formulas with the delimiter each one used.
+   * @param options Rendering options (format, ppi, fonts).
+   * @returns HTML strings (<img> tag or error span), in input order.
+   */
+  function renderLatexBatch(
+    formulas: Array<{ formula: string; delimiter: string }>,
+    options: InternalRustOptions
+  ): string[];
+
+  /**
+   * Renders many LaTeX formulas in parallel on a worker thread (exported from Rust).
+   * @param formulas Formulas with the delimiter each one used.
+   * @param options Rendering options (format, ppi, fonts).
+   * @returns Promise of the HTML strings (<img> tag or error span), in input order.
+   */
+  function renderLatexBatchAsync(
+    formulas: Array<{ formula: string; delimiter: string }>,
+    options: InternalRustOptions
+  ): Promise<string[]>;
+
+  /**
+   * Renders every formula element of an HTML document (exported from Rust).
+   * @param html The document.
+   * @param options Rendering options plus the selectors of formula elements.
+   * @returns The document with each formula replaced by an <img> tag or error marker.
+   */
+  function renderHtml(
+    html: string,
+    options: InternalRustOptions & RenderHtmlOptions
+  ): string;
+
+  /**
+   * Reads the name table of a font file (exported from Rust).
+   * @param path Path to the font file.
+   * @param index Face index within a font collection.
+   * @returns The names found in the font.
+   */
+  function readFontNames(path: string, index?: number): FontNames;
 }
 
-// Block Rule for $$
-function gladstBlockRule(
+// Block Rule for $$ (and \[ ... \] through createBlockRule)
+function createBlockRule(startMarker: string, endMarker: string) {
+  return (
+    state: StateBlock,
+    startLine: number,
+    endLine: number,
+    silent: boolean
+  ): boolean =>
+    blockRule(state, startLine, endLine, silent, startMarker, endMarker);
+}
+
+const gladstBlockRule = createBlockRule("$$", "$$");
+const gladstBracketBlockRule = createBlockRule("\\[", "\\]");
+
+function blockRule(
   state: StateBlock,
   startLine: number,
   endLine: number,
-  silent: boolean
+  silent: boolean,
+  startMarker: string,
+  endMarker: string
 ): boolean {
-  const startMarker = "$$";
-  const endMarker = "$$";
   const pos = state.bMarks[startLine] + state.tShift[startLine];
   const max = state.eMarks[startLine];
 
@@ -278,6 +577,48 @@ function gladstInlineRule(state: StateInline, silent: boolean): boolean {
   return true;
 }
 
+// Inline Rule for \( ... \) and \[ ... \] inside a paragraph. It runs before
+// markdown-it's escape rule, which would otherwise eat the backslashes.
+function gladstParenInlineRule(state: StateInline, silent: boolean): boolean {
+  const pos = state.pos;
+
+  if (state.src.charCodeAt(pos) !== 0x5c /* \ */) {
+    return false;
+  }
+
+  const startMarker = state.src.slice(pos, pos + 2);
+  let endMarker: string;
+  if (startMarker === "\\(") {
+    endMarker = "\\)";
+  } else if (startMarker === "\\[") {
+    endMarker = "\\]";
+  } else {
+    return false;
+  }
+
+  const endPos = state.src.indexOf(endMarker, pos + startMarker.length);
+  if (endPos === -1 || endPos + endMarker.length > state.posMax) {
+    return false; // Closing marker not found
+  }
+
+  const content = state.src.slice(pos + startMarker.length, endPos).trim();
+  if (!content) {
+    return false; // Reject empty formulas like '\( \)'
+  }
+
+  if (silent) {
+    return true;
+  }
+
+  // The markup tells Rust whether this is inline or display math
+  const token = state.push("gladst_inline_math", "span", 0);
+  token.markup = startMarker;
+  token.content = content;
+
+  state.pos = endPos + endMarker.length;
+  return true;
+}
+
 /**
  * Validates and normalizes font configuration
  */
@@ -312,17 +653,132 @@ function normalizeFontConfig(
     }
   }
 
+  // Process fallback chains
+  const normalizeFallbacks = (sources: FontSource[], label: string) =>
+    sources.map((source) => {
+      if (source.system && source.file) {
+        throw new Error(`Cannot specify both system and file for ${label}`);
+      }
+      if (source.system) {
+        return { type: "system" as const, value: source.system };
+      }
+      if (source.file) {
+        return { type: "file" as const, value: source.file };
+      }
+      throw new Error(`Missing system or file for ${label}`);
+    });
+
+  if (fonts.bodyFontFallbacks?.length) {
+    result.bodyFontFallbacks = normalizeFallbacks(
+      fonts.bodyFontFallbacks,
+      "body fallback font"
+    );
+  }
+  if (fonts.mathFontFallbacks?.length) {
+    result.mathFontFallbacks = normalizeFallbacks(
+      fonts.mathFontFallbacks,
+      "math fallback font"
+    );
+  }
+
+  if (fonts.textColor) {
+    result.textColor = fonts.textColor;
+  }
+
+  if (fonts.background) {
+    result.background = fonts.background;
+  }
+
+  if (fonts.inputSyntax) {
+    result.inputSyntax = fonts.inputSyntax;
+  }
+
+  if (fonts.textDir) {
+    result.textDir = fonts.textDir;
+  }
+
+  if (fonts.normalizeInput) {
+    result.normalizeInput = true;
+  }
+
+  if (fonts.preamble) {
+    result.preamble = fonts.preamble;
+  }
+
+  if (typeof fonts.fontSize === "number" && fonts.fontSize > 0) {
+    result.fontSize = fonts.fontSize;
+  }
+
+  if (fonts.margins) {
+    result.margins = fonts.margins;
+  }
+
+  if (typeof fonts.maxWidthEm === "number" && fonts.maxWidthEm > 0) {
+    result.maxWidthEm = fonts.maxWidthEm;
+  }
+
+  if (fonts.trim) {
+    result.trim = true;
+  }
+
+  if (fonts.minifySvg) {
+    result.minifySvg = true;
+  }
+
+  if (typeof fonts.maxPixels === "number" && fonts.maxPixels > 0) {
+    result.maxPixels = fonts.maxPixels;
+  }
+
+  if (fonts.retryWithFallbackFont) {
+    result.retryWithFallbackFont = true;
+  }
+
+  if (fonts.allowEmptyRender) {
+    result.allowEmptyRender = true;
+  }
+
+  if (typeof fonts.formulaTimeoutMs === "number" && fonts.formulaTimeoutMs > 0) {
+    result.formulaTimeoutMs = fonts.formulaTimeoutMs;
+  }
+
+  if (fonts.offline) {
+    result.offline = true;
+  }
+
+  if (fonts.packageDir) {
+    result.packageDir = fonts.packageDir;
+  }
+
+  if (fonts.mitexVersion) {
+    result.mitexVersion = fonts.mitexVersion;
+  }
+
+  if (fonts.extraImports?.length) {
+    result.extraImports = fonts.extraImports;
+  }
+
   return Object.keys(result).length > 0 ? result : undefined;
 }
 
-function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
-  // Prepare options to pass to the Rust function
-  const internalOptions: InternalRustOptions = {
-    format: options?.format === "png" ? "png" : "svg", // Default to svg
+// Prepare options to pass to the Rust functions
+function toInternalOptions(options?: GladstPluginOptions): InternalRustOptions {
+  return {
+    format:
+      options?.format === "png" ||
+      options?.format === "jpeg" ||
+      options?.format === "webp" ||
+      options?.format === "avif"
+        ? options.format
+        : "svg", // Default to svg
     ppi:
       typeof options?.ppi === "number" && options.ppi > 0 ? options.ppi : 300,
     fonts: normalizeFontConfig(options?.fonts),
+    classPrefix: options?.classPrefix || undefined,
   };
+}
+
+function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
+  const internalOptions = toInternalOptions(options);
 
   // Set global font configuration if provided
   if (internalOptions.fonts) {
@@ -343,7 +799,8 @@ function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
   function renderFormula(
     formula: string,
     delimiter: string,
-    isBlock: boolean
+    isBlock: boolean,
+    prerendered?: string
   ): string {
     if (!formula || !delimiter) {
       const type = isBlock ? "Block" : "Inline";
@@ -357,7 +814,8 @@ function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
     }
 
     try {
-      const htmlOutput = addon.renderLatex(formula, delimiter, internalOptions);
+      const htmlOutput =
+        prerendered ?? addon.renderLatex(formula, delimiter, internalOptions);
       // Wrap the output from Rust (assuming it's just the core like <img> or error span)
       const wrapperTag = isBlock ? "div" : "span";
       const wrapperClass = `gladst-${isBlock ? "block" : "inline"}`;
@@ -387,6 +845,46 @@ function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
     }
   }
 
+  // Render every formula of a document in one native call; the renderers
+  // below pick up the results from token.meta
+  function gladstBatchRule(state: StateCore): void {
+    const mathTokens: Token[] = [];
+    for (const token of state.tokens) {
+      if (token.type === "gladst_block_math") {
+        mathTokens.push(token);
+      } else if (token.type === "inline" && token.children) {
+        for (const child of token.children) {
+          if (child.type === "gladst_inline_math") {
+            mathTokens.push(child);
+          }
+        }
+      }
+    }
+
+    const pending = mathTokens.filter((token) => token.content && token.markup);
+    if (pending.length === 0) {
+      return;
+    }
+
+    try {
+      const outputs = addon.renderLatexBatch(
+        pending.map((token) => ({
+          formula: token.content,
+          delimiter: token.markup,
+        })),
+        internalOptions
+      );
+      pending.forEach((token, i) => {
+        token.meta = { ...token.meta, gladstHtml: outputs[i] };
+      });
+    } catch (error: unknown) {
+      // Leave the tokens alone, they are rendered one by one instead
+      console.error("[markdown-it-gladst] Batch rendering failed:", error);
+    }
+  }
+
+  md.core.ruler.push("gladst_batch", gladstBatchRule);
+
   // Register the block rule for $$
   md.block.ruler.before("fence", "gladst_block", gladstBlockRule, {
     alt: ["paragraph", "reference", "blockquote", "list", "hr", "html_block"],
@@ -396,6 +894,21 @@ function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
   // Run after 'escape' rule but before emphasis, links etc.
   md.inline.ruler.after("escape", "gladst_inline", gladstInlineRule);
 
+  // Register the rules for \[ ... \] blocks and \( ... \) / \[ ... \] inline
+  md.block.ruler.after(
+    "gladst_block",
+    "gladst_bracket_block",
+    gladstBracketBlockRule,
+    {
+      alt: ["paragraph", "reference", "blockquote", "list", "hr", "html_block"],
+    }
+  );
+  md.inline.ruler.before(
+    "escape",
+    "gladst_inline_paren",
+    gladstParenInlineRule
+  );
+
   // Renderer for block math ($$)
   md.renderer.rules.gladst_block_math = (
     tokens: Token[],
@@ -405,7 +918,12 @@ function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
     _self: Renderer
   ): string => {
     const token = tokens[idx];
-    return renderFormula(token.content, token.markup, true); // true for isBlock
+    return renderFormula(
+      token.content,
+      token.markup,
+      true, // true for isBlock
+      token.meta?.gladstHtml
+    );
   };
 
   // Renderer for inline math ($)
@@ -417,8 +935,117 @@ function gladstPlugin(md: MarkdownIt, options?: GladstPluginOptions): void {
     _self: Renderer
   ): string => {
     const token = tokens[idx];
-    return renderFormula(token.content, token.markup, false); // false for isBlock
+    return renderFormula(
+      token.content,
+      token.markup,
+      false, // false for isBlock
+      token.meta?.gladstHtml
+    );
   };
 }
 
+/**
+ * Renders a single formula without blocking the event loop. The result is
+ * the bare <img> tag (or error span), without the plugin's wrapper element.
+ * @param formula The LaTeX code (without delimiters).
+ * @param delimiter "$$" or "\\[" for display math, "$" or "\\(" for inline math.
+ * @param options Same options as the plugin.
+ */
+export function renderLatexAsync(
+  formula: string,
+  delimiter: "$" | "$$" | "\\(" | "\\[",
+  options?: GladstPluginOptions
+): Promise<string> {
+  return addon.renderLatexAsync(formula, delimiter, toInternalOptions(options));
+}
+
+/**
+ * Renders many formulas without blocking the event loop. The formulas are
+ * rendered in parallel across the CPU cores; the results are the bare <img>
+ * tags (or error spans), in input order.
+ * @param formulas Formulas with the delimiter each one used.
+ * @param options Same options as the plugin.
+ */
+export function renderLatexBatchAsync(
+  formulas: Array<{
+    formula: string;
+    delimiter: "$" | "$$" | "\\(" | "\\[";
+  }>,
+  options?: GladstPluginOptions
+): Promise<string[]> {
+  return addon.renderLatexBatchAsync(formulas, toInternalOptions(options));
+}
+
+/**
+ * Renders a single formula to image bytes plus its size in em, for callers
+ * that build their own markup or write images to disk.
+ * @param formula The LaTeX code (without delimiters).
+ * @param delimiter "$$" or "\\[" for display math, "$" or "\\(" for inline math.
+ * @param options Same options as the plugin.
+ * @throws If the formula fails to render.
+ */
+export function renderLatexRaw(
+  formula: string,
+  delimiter: "$" | "$$" | "\\(" | "\\[",
+  options?: GladstPluginOptions
+): RawRenderResult {
+  return addon.renderLatexRaw(formula, delimiter, toInternalOptions(options));
+}
+
+/**
+ * Renders every formula element of an HTML document in one call, e.g. the
+ * `<eq>` elements of a `.htex` file, replacing each with an <img> tag.
+ * Formulas that fail are replaced by an error marker and logged.
+ * @param html The document.
+ * @param options Same options as the plugin, plus the formula selectors.
+ * @throws If a selector is invalid or the engine can't be created.
+ */
+export function renderHtml(
+  html: string,
+  options?: GladstPluginOptions & RenderHtmlOptions
+): string {
+  return addon.renderHtml(html, {
+    ...toInternalOptions(options),
+    selector: options?.selector,
+    displaySelector: options?.displaySelector,
+    inlineSelector: options?.inlineSelector,
+    defaultEnv: options?.defaultEnv,
+    numberEquations: options?.numberEquations,
+    keepSource: options?.keepSource,
+  });
+}
+
+/**
+ * Builds the render engine and loads the mitex package ahead of time, so the
+ * first real render doesn't pay for it. Calling it again with the same
+ * options is cheap.
+ * @param options Same options as the plugin.
+ * @returns Whether a trivial formula rendered with these options.
+ */
+export function warmup(options?: GladstPluginOptions): boolean {
+  return addon.warmup(toInternalOptions(options));
+}
+
+/**
+ * Frees the render engine, the engines kept for recently used font
+ * configurations and the fonts they loaded, e.g. after switching to another
+ * font configuration for good. The next render rebuilds the engine.
+ * @returns Whether there was an engine to free.
+ */
+export function disposeEngine(): boolean {
+  return addon.disposeEngine();
+}
+
+/**
+ * Reads the names of a font file, e.g. to check which family name Typst
+ * will use to look it up.
+ * @param path Path to the font file (`~` is expanded).
+ * @param index Face index within a font collection.
+ * @default index 0
+ * @throws If the file can't be read or parsed as a font.
+ */
+export function readFontNames(path: string, index?: number): FontNames {
+  return addon.readFontNames(path, index);
+}
+
 export default gladstPlugin;
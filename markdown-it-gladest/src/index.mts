@@ -1,3 +1,13 @@
 // This module is the ESM entry point for the library.
 
-export { default } from './index.cjs';
+export {
+  default,
+  disposeEngine,
+  readFontNames,
+  renderHtml,
+  renderLatexAsync,
+  renderLatexBatchAsync,
+  renderLatexRaw,
+  warmup,
+} from './index.cjs';
+export type { FontNames, RawRenderResult } from './index.cjs';
@@ -0,0 +1,107 @@
+//! Build cache that lets re-runs skip files whose input and settings are unchanged.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::modified_time;
+
+/// Cache file kept in the output directory (or the working directory without `--output`)
+pub const CACHE_FILE_NAME: &str = ".gladest-cache.json";
+
+/// Inputs rendered by earlier runs, keyed by their canonical path
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct BuildCache {
+    files: HashMap<PathBuf, CacheEntry>,
+    /// Hash of the settings of the current run
+    #[serde(skip)]
+    config_hash: String,
+    /// Files skipped in the current run
+    #[serde(skip)]
+    skipped: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CacheEntry {
+    /// Modification time of the input when it was rendered
+    modified: Option<SystemTime>,
+    /// Hash of the input content
+    content_hash: String,
+    /// Hash of the settings it was rendered with
+    config_hash: String,
+}
+
+/// Hex SHA-256 of some text
+pub fn hash_text(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+impl BuildCache {
+    /// An empty cache for a run with the given settings
+    pub fn new(config_hash: String) -> Self {
+        Self {
+            config_hash,
+            ..Self::default()
+        }
+    }
+
+    /// Read the cache at `path`; a missing or unreadable cache is treated as empty,
+    /// which only costs a full rebuild
+    pub fn load(path: &Path, config_hash: String) -> Self {
+        let cache: Self = fs::read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default();
+        Self {
+            config_hash,
+            ..cache
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize build cache")?;
+        fs::write(path, json).with_context(|| format!("Failed to write build cache: {:?}", path))
+    }
+
+    /// Whether `input` was rendered to `output` with the current settings and hasn't changed
+    /// since. The content is only hashed when the modification time differs.
+    pub fn is_fresh(&self, input: &Path, content: &str, output: &Path) -> bool {
+        let Some(entry) = self.files.get(&cache_key(input)) else {
+            return false;
+        };
+        entry.config_hash == self.config_hash
+            && output.exists()
+            && ((entry.modified.is_some() && entry.modified == modified_time(input))
+                || entry.content_hash == hash_text(content))
+    }
+
+    /// Remember that `input` now holds `content` and was rendered with the current settings
+    pub fn record(&mut self, input: &Path, content: &str) {
+        let entry = CacheEntry {
+            modified: modified_time(input),
+            content_hash: hash_text(content),
+            config_hash: self.config_hash.clone(),
+        };
+        self.files.insert(cache_key(input), entry);
+    }
+
+    pub fn mark_skipped(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Number of files skipped in the current run
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+fn cache_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
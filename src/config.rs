@@ -0,0 +1,277 @@
+//! Loading of `gladest.toml` configuration files.
+//!
+//! Settings are resolved with the precedence CLI flags > config file > defaults.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use clap::{ArgMatches, parser::ValueSource};
+use serde::Deserialize;
+
+use gladest_engine::expand_tilde;
+
+use crate::{Args, Direction, Format, Syntax};
+
+/// Config file picked up from the working directory when `--config` is not given
+pub const CONFIG_FILE_NAME: &str = "gladest.toml";
+
+/// Render settings read from a config file; every field is optional
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    ppi: Option<u32>,
+    format: Option<Format>,
+    syntax: Option<Syntax>,
+    text_dir: Option<Direction>,
+    font_size: Option<f64>,
+    margin_top: Option<f64>,
+    margin_bottom: Option<f64>,
+    margin_left: Option<f64>,
+    margin_right: Option<f64>,
+    max_width: Option<f64>,
+    trim: Option<bool>,
+    minify_svg: Option<bool>,
+    normalize_input: Option<bool>,
+    svg_scale: Option<f64>,
+    max_pixels: Option<u64>,
+    formula_timeout_ms: Option<u64>,
+    offline: Option<bool>,
+    package_dir: Option<PathBuf>,
+    mitex_version: Option<String>,
+    /// Typst packages as `@namespace/name:version`
+    extra_imports: Vec<String>,
+    jpeg_quality: Option<u8>,
+    avif_quality: Option<u8>,
+    avif_speed: Option<u8>,
+    text_color: Option<String>,
+    background: Option<String>,
+    preamble_file: Option<PathBuf>,
+    fonts: FontsConfig,
+}
+
+/// The `[fonts]` table
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FontsConfig {
+    body: Option<FontEntry>,
+    math: Option<FontEntry>,
+    /// File paths or system font names, tried in order
+    body_fallbacks: Vec<String>,
+    /// File paths or system font names, tried in order
+    math_fallbacks: Vec<String>,
+}
+
+/// A font given as `{ file = "..." }` or `{ name = "..." }`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+enum FontEntry {
+    File(PathBuf),
+    Name(String),
+}
+
+/// Read the config file at `path`, or `gladest.toml` in the working directory if it exists.
+/// Returns the parsed file together with the path it was read from.
+pub fn load(path: Option<&Path>) -> Result<Option<(ConfigFile, PathBuf)>> {
+    let path = match path {
+        Some(path) => PathBuf::from(expand_tilde(&path.to_string_lossy())),
+        None => {
+            let path = PathBuf::from(CONFIG_FILE_NAME);
+            if !path.is_file() {
+                return Ok(None);
+            }
+            path
+        }
+    };
+
+    let source = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file: {:?}", path))?;
+    let config: ConfigFile = toml::from_str(&source)
+        .with_context(|| format!("Failed to parse config file: {:?}", path))?;
+
+    Ok(Some((config, path)))
+}
+
+impl ConfigFile {
+    /// Fill in every setting of `args` that was not given on the command line.
+    /// Relative paths are resolved against `base_dir`, the config file's directory.
+    pub fn apply(self, args: &mut Args, matches: &ArgMatches, base_dir: &Path) -> Result<()> {
+        for (key, quality, min) in [
+            ("jpeg-quality", self.jpeg_quality, 1),
+            ("avif-quality", self.avif_quality, 0),
+        ] {
+            if let Some(quality) = quality.filter(|quality| !(min..=100).contains(quality)) {
+                anyhow::bail!("{} must be between {} and 100, got {}", key, min, quality);
+            }
+        }
+        if let Some(speed) = self.avif_speed.filter(|speed| !(1..=10).contains(speed)) {
+            anyhow::bail!("avif-speed must be between 1 and 10, got {}", speed);
+        }
+        if self.formula_timeout_ms == Some(0) {
+            anyhow::bail!("formula-timeout-ms must be at least 1");
+        }
+
+        merge(matches, "ppi", &mut args.ppi, self.ppi);
+        merge(matches, "format", &mut args.format, self.format);
+        merge(matches, "syntax", &mut args.syntax, self.syntax);
+        merge(matches, "text_dir", &mut args.text_dir, self.text_dir);
+        merge(matches, "font_size", &mut args.font_size, self.font_size);
+        merge(
+            matches,
+            "margin_top",
+            &mut args.margin_top,
+            self.margin_top.map(Some),
+        );
+        merge(
+            matches,
+            "margin_bottom",
+            &mut args.margin_bottom,
+            self.margin_bottom.map(Some),
+        );
+        merge(
+            matches,
+            "margin_left",
+            &mut args.margin_left,
+            self.margin_left.map(Some),
+        );
+        merge(
+            matches,
+            "margin_right",
+            &mut args.margin_right,
+            self.margin_right.map(Some),
+        );
+        merge(
+            matches,
+            "max_width",
+            &mut args.max_width,
+            self.max_width.map(Some),
+        );
+        merge(matches, "trim", &mut args.trim, self.trim);
+        merge(matches, "minify_svg", &mut args.minify_svg, self.minify_svg);
+        merge(
+            matches,
+            "normalize_input",
+            &mut args.normalize_input,
+            self.normalize_input,
+        );
+        merge(matches, "svg_scale", &mut args.svg_scale, self.svg_scale);
+        merge(matches, "max_pixels", &mut args.max_pixels, self.max_pixels);
+        merge(
+            matches,
+            "formula_timeout_ms",
+            &mut args.formula_timeout_ms,
+            self.formula_timeout_ms.map(Some),
+        );
+        merge(matches, "offline", &mut args.offline, self.offline);
+        merge(
+            matches,
+            "mitex_version",
+            &mut args.mitex_version,
+            self.mitex_version,
+        );
+        merge(
+            matches,
+            "jpeg_quality",
+            &mut args.jpeg_quality,
+            self.jpeg_quality,
+        );
+        merge(
+            matches,
+            "avif_quality",
+            &mut args.avif_quality,
+            self.avif_quality,
+        );
+        merge(matches, "avif_speed", &mut args.avif_speed, self.avif_speed);
+        merge(
+            matches,
+            "text_color",
+            &mut args.text_color,
+            self.text_color.map(Some),
+        );
+        merge(
+            matches,
+            "background",
+            &mut args.background,
+            self.background.map(Some),
+        );
+
+        let preamble_file = self
+            .preamble_file
+            .map(|path| Some(PathBuf::from(resolve_path(base_dir, &path))));
+        merge(
+            matches,
+            "preamble_file",
+            &mut args.preamble_file,
+            preamble_file,
+        );
+        let package_dir = self
+            .package_dir
+            .map(|path| Some(resolve_path(base_dir, &path)));
+        merge(matches, "package_dir", &mut args.package_dir, package_dir);
+
+        // A font named on the command line replaces the configured one, whichever kind it is
+        if args.body_font_file.is_none() && args.body_font_name.is_none() {
+            match self.fonts.body {
+                Some(FontEntry::File(path)) => {
+                    args.body_font_file = Some(resolve_path(base_dir, &path))
+                }
+                Some(FontEntry::Name(name)) => args.body_font_name = Some(name),
+                None => {}
+            }
+        }
+        if args.math_font_file.is_none() && args.math_font_name.is_none() {
+            match self.fonts.math {
+                Some(FontEntry::File(path)) => {
+                    args.math_font_file = Some(resolve_path(base_dir, &path))
+                }
+                Some(FontEntry::Name(name)) => args.math_font_name = Some(name),
+                None => {}
+            }
+        }
+
+        if args.extra_imports.is_empty() {
+            args.extra_imports = self.extra_imports;
+        }
+
+        if args.body_font_fallbacks.is_empty() {
+            args.body_font_fallbacks = resolve_fallbacks(base_dir, self.fonts.body_fallbacks);
+        }
+        if args.math_font_fallbacks.is_empty() {
+            args.math_font_fallbacks = resolve_fallbacks(base_dir, self.fonts.math_fallbacks);
+        }
+
+        Ok(())
+    }
+}
+
+/// Overwrite `target` with the config value unless the argument came from the command line
+fn merge<T>(matches: &ArgMatches, id: &str, target: &mut T, value: Option<T>) {
+    let Some(value) = value else { return };
+    if matches.value_source(id) != Some(ValueSource::CommandLine) {
+        *target = value;
+    }
+}
+
+/// Expand `~` and make a relative path relative to the config file's directory
+fn resolve_path(base_dir: &Path, path: &Path) -> String {
+    let expanded = PathBuf::from(expand_tilde(&path.to_string_lossy()));
+    base_dir.join(expanded).to_string_lossy().into_owned()
+}
+
+/// Resolve fallbacks that name font files next to the config file; other entries are
+/// kept as they are and later treated as file paths or system font names
+fn resolve_fallbacks(base_dir: &Path, fallbacks: Vec<String>) -> Vec<String> {
+    fallbacks
+        .into_iter()
+        .map(|value| {
+            let resolved = resolve_path(base_dir, Path::new(&value));
+            if Path::new(&resolved).is_file() {
+                resolved
+            } else {
+                value
+            }
+        })
+        .collect()
+}
@@ -1,42 +1,177 @@
 use std::{
-    env, fs,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    fs,
+    io::Write,
+    num::NonZeroUsize,
+    path::{Component, Path, PathBuf},
+    sync::{
+        Arc, Mutex, mpsc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+mod cache;
+mod config;
+
 use anyhow::{Context, Result};
-use clap::{Parser, arg, command};
-use clap_derive::{Parser, ValueEnum};
+use chrono::Local;
+use clap::{CommandFactory, FromArgMatches, arg, command};
+use clap_derive::{Parser, Subcommand, ValueEnum};
 use glob::glob;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use notify::{Event, RecursiveMode, Watcher};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use scraper::{Html, Selector};
-
-use gladest_engine::{FontConfig, FontSource, RenderEngine, RenderFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use cache::{BuildCache, CACHE_FILE_NAME, hash_text};
+use gladest_engine::{
+    Color, DEFAULT_CLASS_PREFIX, DEFAULT_MAX_PIXELS, DEFAULT_MITEX_VERSION, EncodeOptions,
+    FALLBACK_MATH_FONT, FontConfig, FontSource, FormulaRenderResult, InputSyntax, Margins, Pixmap,
+    RenderEngine, RenderError, RenderFormat, TextDir, expand_tilde,
+    html::{self, HtmlFormula, HtmlHooks, HtmlOptions, Selector},
+    markdown,
+    sprite::{self, SpriteSheet},
+};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    /// Input file path or glob pattern (e.g., "doc.htex", "*.html", "docs/**/*.htex")
-    input: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input file path, directory or glob pattern (e.g., "doc.htex", "docs/", "docs/**/*.htex")
+    #[arg(required_unless_present = "list_fonts")]
+    input: Option<String>,
+
+    /// Print the family names of every font available to the renderer and exit
+    #[arg(long)]
+    list_fonts: bool,
+
+    /// Config file with render and font settings (defaults to ./gladest.toml if present)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Only process files with these extensions (comma-separated, e.g. "htex,html")
+    #[arg(long = "ext", value_delimiter = ',')]
+    extensions: Vec<String>,
 
     /// Output directory (only used for .htex inputs)
     #[arg(short, long)]
     output: Option<PathBuf>,
 
+    /// Output file name for .htex inputs, relative to the output directory; placeholders
+    /// {stem}, {ext} and {parent} (the input's directory) are replaced
+    #[arg(long, value_name = "TEMPLATE", default_value = DEFAULT_NAME_TEMPLATE)]
+    name_template: String,
+
     /// Pixels per inch for rasterization
     #[arg(short, long, default_value_t = 1200)]
     ppi: u32,
 
-    /// Output format (png or svg)
+    /// Font size in points, which is also the size of one em in the output
+    #[arg(long, default_value_t = 10.0)]
+    font_size: f64,
+
+    /// Space above the formula in em (default 0.455)
+    #[arg(long)]
+    margin_top: Option<f64>,
+
+    /// Space below the formula in em (default 0.455)
+    #[arg(long)]
+    margin_bottom: Option<f64>,
+
+    /// Space left of the formula in em (default 0)
+    #[arg(long)]
+    margin_left: Option<f64>,
+
+    /// Space right of the formula in em (default 0)
+    #[arg(long)]
+    margin_right: Option<f64>,
+
+    /// Break formulas wider than this many em into lines at their operators
+    #[arg(long, value_name = "EM")]
+    max_width: Option<f64>,
+
+    /// Crop formulas to their visible ink, removing surrounding whitespace
+    #[arg(long)]
+    trim: bool,
+
+    /// Display SVG images this many times as large as rendered, e.g. 1.05
+    #[arg(long, value_name = "FACTOR", default_value_t = 1.0)]
+    svg_scale: f64,
+
+    /// Minify SVG output by dropping comments and redundant whitespace
+    #[arg(long)]
+    minify_svg: bool,
+
+    /// NFC-normalize formulas and replace Unicode symbols mitex does not know (−, –, ×, ÷,
+    /// ·, ±, ≤, ≥, ≠ and no-break spaces) with their LaTeX equivalents
+    #[arg(long)]
+    normalize_input: bool,
+
+    /// Fail formulas whose image would have more pixels than this, instead of allocating it
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_PIXELS)]
+    max_pixels: u64,
+
+    /// Retry formulas that fail because of the math font with the bundled New Computer
+    /// Modern Math instead of reporting an error
+    #[arg(long)]
+    retry_fallback_font: bool,
+
+    /// Leave formulas that render to a zero-size image untouched instead of reporting
+    /// them as failures
+    #[arg(long)]
+    allow_empty_render: bool,
+
+    /// Fail formulas that take longer than this to compile; the batch moves on while the
+    /// abandoned compilation finishes in the background
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    formula_timeout_ms: Option<u64>,
+
+    /// Never download packages; mitex must be in --package-dir or the local Typst package cache
+    #[arg(long)]
+    offline: bool,
+
+    /// Directory with Typst packages laid out as <namespace>/<name>/<version>, searched first
+    #[arg(long, value_name = "DIR")]
+    package_dir: Option<String>,
+
+    /// Version of the mitex package used for LaTeX input
+    #[arg(long, value_name = "VERSION", default_value = DEFAULT_MITEX_VERSION)]
+    mitex_version: String,
+
+    /// Further Typst package to import, as @namespace/name:version (repeatable)
+    #[arg(long = "import", value_name = "PACKAGE")]
+    extra_imports: Vec<String>,
+
+    /// Output format (png, svg, jpeg, webp or avif)
     #[arg(short, long, default_value = "png", value_enum)]
     format: Format,
 
+    /// JPEG quality from 1 to 100 (only used for jpeg output)
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+
+    /// AVIF quality from 0 to 100 (only used for avif output)
+    #[arg(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(0..=100))]
+    avif_quality: u8,
+
+    /// AVIF encoder speed from 1 (smallest files) to 10 (fastest encoding)
+    #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(1..=10))]
+    avif_speed: u8,
+
     /// Body font file path
     #[arg(long, help = "Path to body font file (e.g., serif.ttf)")]
     body_font_file: Option<String>,
 
+    /// Face of the body font file to use when it is a collection (.ttc)
+    #[arg(long, value_name = "N", help = "Face index within a body font collection (.ttc), starting at 0")]
+    body_font_index: Option<usize>,
+
     /// Body font name (system font)
     #[arg(long, help = "System body font name (e.g., 'Times New Roman')")]
     body_font_name: Option<String>,
@@ -45,19 +180,445 @@ struct Args {
     #[arg(long, help = "Path to math font file (e.g., math.otf)")]
     math_font_file: Option<String>,
 
+    /// Face of the math font file to use when it is a collection (.ttc)
+    #[arg(long, value_name = "N", help = "Face index within a math font collection (.ttc), starting at 0")]
+    math_font_index: Option<usize>,
+
     /// Math font name (system font)
     #[arg(long, help = "System math font name (e.g., 'STIX Two Math')")]
     math_font_name: Option<String>,
 
-    /// Show verbose error output
+    /// Formula text color
+    #[arg(long, help = "Formula text color as a hex code or name (e.g., '#e0e0e0', 'white')")]
+    text_color: Option<String>,
+
+    /// Page color behind formulas
+    #[arg(long, help = "Solid background color as a hex code or name (e.g., '#ffffff'); transparent by default")]
+    background: Option<String>,
+
+    /// File with macro definitions prepended to every formula
+    #[arg(long, help = "Path to a file with shared LaTeX macro definitions (e.g., \\newcommand)")]
+    preamble_file: Option<PathBuf>,
+
+    /// Formula syntax (latex or typst)
+    #[arg(long, default_value = "latex", value_enum)]
+    syntax: Syntax,
+
+    /// Text direction in formulas (ltr or rtl), e.g. rtl for Arabic or Hebrew labels
+    #[arg(long, default_value = "ltr", value_enum)]
+    text_dir: Direction,
+
+    /// Fallback body fonts, tried in order (repeatable)
+    #[arg(long = "body-font-fallback", value_name = "FONT", help = "Fallback body font, as a file path or system font name (repeatable)")]
+    body_font_fallbacks: Vec<String>,
+
+    /// Fallback math fonts, tried in order (repeatable)
+    #[arg(long = "math-font-fallback", value_name = "FONT", help = "Fallback math font, as a file path or system font name (repeatable)")]
+    math_font_fallbacks: Vec<String>,
+
+    /// Only use the fonts bundled with gladst, never system fonts, so output is the same on every machine
+    #[arg(long)]
+    embedded_fonts: bool,
+
+    /// Show verbose error output and the engine's debug log (RUST_LOG overrides the log level)
     #[arg(short, long)]
     verbose: bool,
+
+    /// Only print errors (to stderr); no font summary, progress bars or status lines
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Re-render every file, ignoring the build cache of earlier runs
+    #[arg(long)]
+    force: bool,
+
+    /// Print plain text without emoji, box drawing or colors
+    #[arg(long)]
+    no_color: bool,
+
+    /// Write formula images into this directory (relative to the output HTML,
+    /// default "images") instead of embedding them as base64
+    #[arg(long, value_name = "DIR", num_args = 0..=1, default_missing_value = "images")]
+    external_images: Option<PathBuf>,
+
+    /// Write each document's formula images into a directory named after the document,
+    /// next to its output HTML (e.g. "out/intro/formula_<hash>.svg" for "out/intro.html")
+    #[arg(long, conflicts_with = "external_images")]
+    sidecar_images: bool,
+
+    /// Pack each document's PNG formulas into one sprite sheet next to its output HTML
+    /// (e.g. "out/intro.sprites.png" for "out/intro.html"), shown through CSS offsets
+    #[arg(long, conflicts_with_all = ["external_images", "sidecar_images"])]
+    sprite_sheet: bool,
+
+    /// Also write each external SVG image pre-compressed next to it ("<name>.svg.gz" or
+    /// "<name>.svg.br"), for web servers that serve such files in place of the original
+    #[arg(long, value_enum, default_value = "none")]
+    compress: Compression,
+
+    /// Add a <style> block styling the rendered formulas and error markers to the head
+    /// of every output document
+    #[arg(long)]
+    inject_css: bool,
+
+    /// Stylesheet injected by --inject-css in place of the built-in one
+    #[arg(long, value_name = "PATH", requires = "inject_css")]
+    css_file: Option<PathBuf>,
+
+    /// CSS selector of the elements holding formulas
+    #[arg(long, default_value = html::DEFAULT_SELECTOR)]
+    selector: String,
+
+    /// CSS selector of formula elements that hold display math
+    #[arg(long, default_value = html::DEFAULT_DISPLAY_SELECTOR)]
+    display_selector: String,
+
+    /// CSS selector of formula elements that hold inline math
+    #[arg(long, default_value = html::DEFAULT_INLINE_SELECTOR)]
+    inline_selector: String,
+
+    /// How formula elements render that match neither --display-selector nor
+    /// --inline-selector, e.g. an <eq> without an env attribute or with an unknown one
+    #[arg(long, value_enum, default_value = "inline")]
+    default_env: DefaultEnv,
+
+    /// Class prefix of the generated <img> tags ("<prefix> math" / "<prefix> displaymath")
+    #[arg(long, default_value = DEFAULT_CLASS_PREFIX)]
+    class_prefix: String,
+
+    /// Number display formulas (1), (2), ... in document order, restarting in each file
+    #[arg(long)]
+    number_equations: bool,
+
+    /// Keep each formula's original text and env in data-gladst-source and data-gladst-env
+    /// attributes of its image, so the elements can be restored later
+    #[arg(long)]
+    keep_source: bool,
+
+    /// Render and report errors without writing any files; exits non-zero on failures
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Leave the output (or the in-place input) untouched when any formula in a file
+    /// fails, instead of writing error markers; exits non-zero on failures
+    #[arg(long)]
+    keep_on_error: bool,
+
+    /// Print the N slowest formulas (default 10) and the time spent on each file
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "10")]
+    timings: Option<usize>,
+
+    /// Write the complete Typst source of every formula to DIR/<document>/formula-<n>.typ,
+    /// to debug a formula in the Typst web app
+    #[arg(long, value_name = "DIR")]
+    dump_typst: Option<PathBuf>,
+
+    /// Write a JSON report of failed files and formulas to this path
+    #[arg(long, value_name = "PATH")]
+    report_json: Option<PathBuf>,
+
+    /// Keep running and re-render input files when they change
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Render a document's formulas N at a time, freeing memory in between, to bound peak
+    /// memory on very large documents
+    #[arg(long, value_name = "N")]
+    chunk_size: Option<NonZeroUsize>,
+
+    /// Number of worker threads (defaults to all cores)
+    #[arg(short, long)]
+    jobs: Option<NonZeroUsize>,
 }
 
-#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 enum Format {
     Png,
     Svg,
+    Jpeg,
+    #[value(name = "webp")]
+    WebP,
+    Avif,
+}
+
+impl Format {
+    fn render_format(self) -> RenderFormat {
+        match self {
+            Format::Png => RenderFormat::Png,
+            Format::Svg => RenderFormat::Svg,
+            Format::Jpeg => RenderFormat::Jpeg,
+            Format::WebP => RenderFormat::WebP,
+            Format::Avif => RenderFormat::Avif,
+        }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Syntax {
+    Latex,
+    Typst,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    Ltr,
+    Rtl,
+}
+
+/// Pre-compressed copies written next to external SVG images
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Brotli,
+}
+
+impl Compression {
+    /// Extension appended to the compressed file's name
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Brotli => Some("br"),
+        }
+    }
+
+    /// Compress `data` at the highest level, as it is only done once per image
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 11, 22);
+                    encoder.write_all(data)?;
+                    encoder.flush()?;
+                }
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// Commands run instead of processing files
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the font and package setup given by the other options: render a test
+    /// formula, report fonts that were not found and list the available ones
+    Doctor,
+}
+
+/// Math environment of formula elements that don't name one
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DefaultEnv {
+    Inline,
+    Display,
+}
+
+/// Settings shared by every file processed in one run
+struct ProcessOptions<'a> {
+    output_dir: Option<&'a Path>,
+    /// Output file name of inputs that are not modified in place, see `--name-template`
+    name_template: &'a str,
+    engine: &'a RenderEngine,
+    /// Which elements hold formulas and how they are rendered
+    html: &'a HtmlOptions,
+    external_images: Option<&'a Path>,
+    /// Write images into a directory per document instead of embedding them
+    sidecar_images: bool,
+    /// Pack each document's PNG images into one sprite sheet
+    sprite_sheet: bool,
+    /// Pre-compressed copies written next to external SVG images
+    compress: Compression,
+    /// CSS added to the head of every document
+    stylesheet: Option<&'a str>,
+    /// Number of image files written, for the final summary
+    images_written: &'a AtomicUsize,
+    /// Render formulas and report errors without writing any files
+    dry_run: bool,
+    /// Skip writing a file's output if any of its formulas failed
+    keep_on_error: bool,
+    verbose: bool,
+    console: Console,
+    /// Render times collected for `--timings`
+    timings: Option<&'a Mutex<Timings>>,
+    /// Directory receiving each formula's Typst source
+    dump_typst: Option<&'a Path>,
+    /// Files rendered by earlier runs, to skip unchanged ones
+    build_cache: Option<&'a Mutex<BuildCache>>,
+}
+
+/// Render time of a single formula
+struct FormulaTiming {
+    formula_index: usize,
+    formula: String,
+    duration: Duration,
+}
+
+/// Render times gathered across all processed files
+#[derive(Default)]
+struct Timings {
+    formulas: Vec<(PathBuf, FormulaTiming)>,
+    /// Total time and number of formulas of each file
+    files: Vec<(PathBuf, Duration, usize)>,
+}
+
+impl Timings {
+    /// Print the `limit` slowest formulas followed by every file, slowest first
+    fn print(mut self, limit: usize, console: Console) {
+        self.formulas
+            .sort_by(|(_, a), (_, b)| b.duration.cmp(&a.duration));
+        self.files.sort_by(|(_, a, _), (_, b, _)| b.cmp(a));
+
+        println!();
+        println!("{}Slowest formulas:", console.icon("⏱️  "));
+        for (path, timing) in self.formulas.iter().take(limit) {
+            let preview: String = timing.formula.chars().take(60).collect();
+            let ellipsis = if preview.len() < timing.formula.len() { "..." } else { "" };
+            println!(
+                "   {:>10.1?}  {:?} #{}  {}{}",
+                timing.duration,
+                path,
+                timing.formula_index + 1,
+                preview.replace('\n', " "),
+                ellipsis
+            );
+        }
+
+        println!("{}Time per file:", console.icon("⏱️  "));
+        for (path, duration, formula_count) in &self.files {
+            println!("   {:>10.1?}  {:?} ({} formulas)", duration, path, formula_count);
+        }
+    }
+}
+
+/// How status and error messages are printed
+#[derive(Clone, Copy, Debug)]
+struct Console {
+    /// Print errors only
+    quiet: bool,
+    /// Leave out emoji and box drawing
+    plain: bool,
+}
+
+impl Console {
+    /// The emoji prefix of a message, or nothing in plain mode
+    fn icon<'a>(&self, icon: &'a str) -> &'a str {
+        if self.plain { "" } else { icon }
+    }
+
+    /// Horizontal separator around error listings
+    fn rule(&self) -> String {
+        let line = if self.plain { "-" } else { "━" };
+        line.repeat(80)
+    }
+
+    /// Print a status message to stdout unless quiet
+    fn status(&self, icon: &str, message: impl Display) {
+        if !self.quiet {
+            println!("{}{}", self.icon(icon), message);
+        }
+    }
+
+    /// Print an error or warning to stderr
+    fn error(&self, icon: &str, message: impl Display) {
+        eprintln!("{}{}", self.icon(icon), message);
+    }
+
+    /// A progress bar with the given template, hidden when quiet
+    fn progress_bar(&self, len: u64, template: &str) -> Result<ProgressBar> {
+        if self.quiet {
+            return Ok(ProgressBar::hidden());
+        }
+        let pb = ProgressBar::new(len);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(template)
+                .context("Failed to set progress style")?
+                .progress_chars("#>-"),
+        );
+        Ok(pb)
+    }
+}
+
+/// Where the formula images of one document are written instead of being embedded
+struct ImageOutput {
+    /// Directory the images are written to
+    dir: PathBuf,
+    /// Path the HTML refers to `dir` by
+    src_prefix: PathBuf,
+    /// Start of every image's file name, followed by its content hash
+    file_prefix: &'static str,
+}
+
+/// Where the sprite sheet of one document is written
+struct SpriteOutput {
+    path: PathBuf,
+    /// Path the HTML refers to the sheet by
+    src: String,
+}
+
+/// Result of processing one input file
+struct ProcessedFile {
+    output_path: PathBuf,
+    /// Whether the output file was written
+    written: bool,
+    /// Whether the file was skipped because neither it nor the settings changed
+    skipped: bool,
+    formula_errors: Vec<FormulaError>,
+}
+
+/// Machine-readable summary written by `--report-json`
+#[derive(Serialize)]
+struct JsonReport {
+    files_processed: usize,
+    failed_files: usize,
+    failed_formulas: usize,
+    /// Only files that failed or contain formula errors
+    files: Vec<FileReport>,
+}
+
+#[derive(Serialize)]
+struct FileReport {
+    path: PathBuf,
+    /// Set when the file as a whole could not be processed
+    error: Option<String>,
+    formula_errors: Vec<FormulaReport>,
+}
+
+#[derive(Serialize)]
+struct FormulaReport {
+    formula_index: usize,
+    line: Option<usize>,
+    formula: String,
+    error: String,
+}
+
+impl FileReport {
+    fn new(path: &Path, error: Option<&anyhow::Error>, formula_errors: &[FormulaError]) -> Self {
+        FileReport {
+            path: path.to_path_buf(),
+            error: error.map(extract_detailed_error),
+            formula_errors: formula_errors
+                .iter()
+                .map(|formula_error| FormulaReport {
+                    formula_index: formula_error.formula_index,
+                    line: formula_error.line,
+                    formula: formula_error.formula.clone(),
+                    error: extract_detailed_error(&formula_error.error),
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -65,24 +626,30 @@ struct FormulaError {
     formula: String,
     error: anyhow::Error,
     formula_index: usize,
+    /// Line of the formula element in the input file
+    line: Option<usize>,
 }
 
-fn expand_tilde(path: &str) -> String {
-    if path.starts_with("~/") {
-        if let Ok(home) = env::var("HOME") {
-            path.replacen("~", &home, 1)
-        } else {
-            path.to_string()
-        }
-    } else if path == "~" {
-        env::var("HOME").unwrap_or_else(|_| path.to_string())
+/// Interpret a fallback font argument as a font file if it exists on disk,
+/// otherwise as a system font name
+fn parse_fallback_font(value: &str) -> FontSource {
+    let expanded_path = expand_tilde(value);
+    if Path::new(&expanded_path).is_file() {
+        FontSource::File(expanded_path, None)
     } else {
-        path.to_string()
+        FontSource::System(value.to_string())
     }
 }
 
 /// Create font configuration from command line arguments
 fn create_font_config(args: &Args) -> Result<FontConfig> {
+    if args.body_font_index.is_some() && args.body_font_file.is_none() {
+        anyhow::bail!("--body-font-index requires --body-font-file");
+    }
+    if args.math_font_index.is_some() && args.math_font_file.is_none() {
+        anyhow::bail!("--math-font-index requires --math-font-file");
+    }
+
     let body_font = match (&args.body_font_file, &args.body_font_name) {
         (Some(file), None) => {
             let expanded_path = expand_tilde(file);
@@ -94,7 +661,7 @@ fn create_font_config(args: &Args) -> Result<FontConfig> {
                     expanded_path
                 ));
             }
-            FontSource::File(expanded_path)
+            FontSource::File(expanded_path, args.body_font_index)
         }
         (None, Some(name)) => FontSource::System(name.clone()),
         (Some(_), Some(_)) => {
@@ -116,7 +683,7 @@ fn create_font_config(args: &Args) -> Result<FontConfig> {
                     expanded_path
                 ));
             }
-            FontSource::File(expanded_path)
+            FontSource::File(expanded_path, args.math_font_index)
         }
         (None, Some(name)) => FontSource::System(name.clone()),
         (Some(_), Some(_)) => {
@@ -127,11 +694,108 @@ fn create_font_config(args: &Args) -> Result<FontConfig> {
         (None, None) => FontSource::System("Fira Math".to_string()), // Default
     };
 
-    Ok(FontConfig {
-        body_font,
-        math_font,
-        include_system_fonts: args.body_font_name.is_some() || args.math_font_name.is_some(),
-    })
+    let text_color = args
+        .text_color
+        .as_deref()
+        .map(str::parse::<Color>)
+        .transpose()
+        .context("Invalid text color")?;
+
+    let background = args
+        .background
+        .as_deref()
+        .map(str::parse::<Color>)
+        .transpose()
+        .context("Invalid background color")?;
+
+    let preamble = args
+        .preamble_file
+        .as_ref()
+        .map(|path| {
+            fs::read_to_string(expand_tilde(&path.to_string_lossy()))
+                .with_context(|| format!("Failed to read preamble file: {:?}", path))
+        })
+        .transpose()?;
+
+    let body_font_fallbacks: Vec<FontSource> = args
+        .body_font_fallbacks
+        .iter()
+        .map(|value| parse_fallback_font(value))
+        .collect();
+    let math_font_fallbacks: Vec<FontSource> = args
+        .math_font_fallbacks
+        .iter()
+        .map(|value| parse_fallback_font(value))
+        .collect();
+    let has_system_fallback = body_font_fallbacks
+        .iter()
+        .chain(&math_font_fallbacks)
+        .any(|source| matches!(source, FontSource::System(_)));
+
+    let fonts = if args.embedded_fonts {
+        let has_font_args = args.body_font_file.is_some()
+            || args.body_font_name.is_some()
+            || args.math_font_file.is_some()
+            || args.math_font_name.is_some()
+            || !args.body_font_fallbacks.is_empty()
+            || !args.math_font_fallbacks.is_empty();
+        if has_font_args {
+            return Err(anyhow::anyhow!(
+                "--embedded-fonts cannot be combined with other font settings"
+            ));
+        }
+        FontConfig::embedded()
+    } else {
+        FontConfig::builder()
+            .body_font(body_font)
+            .math_font(math_font)
+            .body_font_fallbacks(body_font_fallbacks)
+            .math_font_fallbacks(math_font_fallbacks)
+            .include_system_fonts(
+                args.body_font_name.is_some()
+                    || args.math_font_name.is_some()
+                    || has_system_fallback,
+            )
+            .build()
+    };
+
+    let font_config = fonts
+        .into_builder()
+        .text_color(text_color)
+        .background(background)
+        .input_syntax(match args.syntax {
+            Syntax::Latex => InputSyntax::Latex,
+            Syntax::Typst => InputSyntax::Typst,
+        })
+        .text_dir(match args.text_dir {
+            Direction::Ltr => TextDir::Ltr,
+            Direction::Rtl => TextDir::Rtl,
+        })
+        .preamble(preamble)
+        .font_size_pt(args.font_size)
+        .margin_em(Margins {
+            top: args.margin_top,
+            bottom: args.margin_bottom,
+            left: args.margin_left,
+            right: args.margin_right,
+        })
+        .max_width_em(args.max_width)
+        .trim(args.trim)
+        .minify_svg(args.minify_svg)
+        .normalize_input(args.normalize_input)
+        .max_pixels(args.max_pixels)
+        .retry_with_fallback_font(args.retry_fallback_font)
+        .allow_empty_render(args.allow_empty_render)
+        .formula_timeout(args.formula_timeout_ms.map(Duration::from_millis))
+        .offline(args.offline)
+        .package_dir(args.package_dir.clone())
+        .mitex_version(args.mitex_version.clone())
+        .extra_imports(args.extra_imports.clone())
+        .build();
+    font_config.check_mitex_version()?;
+    font_config.check_extra_imports()?;
+    font_config.check_font_files()?;
+    Ok(font_config)
 }
 
 /// Extract detailed error information from anyhow::Error chain
@@ -154,7 +818,7 @@ fn extract_detailed_error(error: &anyhow::Error) -> String {
 }
 
 /// Format formula error for display
-fn format_formula_error(formula_error: &FormulaError, verbose: bool) -> String {
+fn format_formula_error(formula_error: &FormulaError, verbose: bool, console: Console) -> String {
     let formula_preview = if formula_error.formula.len() > 100 {
         format!("{}...", &formula_error.formula[..97])
     } else {
@@ -162,13 +826,20 @@ fn format_formula_error(formula_error: &FormulaError, verbose: bool) -> String {
     };
 
     let mut output = String::new();
+    let separator = if console.plain { "-" } else { "─" }.repeat(75);
+    let location = match formula_error.line {
+        Some(line) => format!(" (line {})", line),
+        None => String::new(),
+    };
     output.push_str(&format!(
-        "❌ Formula #{} failed to render:\n",
-        formula_error.formula_index + 1
+        "{}Formula #{}{} failed to render:\n",
+        console.icon("❌ "),
+        formula_error.formula_index + 1,
+        location
     ));
-    output.push_str("   ───────────────────────────────────────────────────────────────────────────\n");
+    output.push_str(&format!("   {}\n", separator));
     output.push_str(&format!("   {}\n", formula_preview.replace("\n", "\n   ")));
-    output.push_str("   ───────────────────────────────────────────────────────────────────────────\n");
+    output.push_str(&format!("   {}\n", separator));
     
     if verbose {
         output.push_str("   Error details:\n");
@@ -182,117 +853,405 @@ fn format_formula_error(formula_error: &FormulaError, verbose: bool) -> String {
     output
 }
 
-/// Renders formulas within HTML content and returns the modified HTML.
+/// Reports progress, timings and warnings of the formulas of one document, and writes
+/// their images to files when `images` is set
+struct CliHooks<'a> {
+    options: &'a ProcessOptions<'a>,
+    images: Option<&'a ImageOutput>,
+    pb_formulas: Option<&'a ProgressBar>,
+    timings: Mutex<Vec<FormulaTiming>>,
+    /// PNG images collected for the sprite sheet, keyed by a hash of their content;
+    /// sorted so that the sheet's layout is the same on every run
+    sprites: Option<Mutex<BTreeMap<String, Pixmap>>>,
+}
+
+impl HtmlHooks for CliHooks<'_> {
+    type Error = anyhow::Error;
+
+    fn formula_collected(&self, formula: &HtmlFormula) {
+        if let Some(pb) = self.pb_formulas {
+            pb.set_message(format!("collecting formulas: {} found", formula.index + 1));
+        }
+    }
+
+    fn formulas_found(&self, formulas: &[HtmlFormula]) {
+        if let Some(pb) = self.pb_formulas {
+            pb.set_message("");
+            pb.set_length(formulas.len() as u64);
+            pb.reset();
+        }
+    }
+
+    fn serializing(&self) {
+        if let Some(pb) = self.pb_formulas {
+            pb.set_message("writing document");
+        }
+    }
+
+    fn invalid_attribute(&self, index: usize, name: &str, value: &str) {
+        if self.options.verbose {
+            self.options.console.error(
+                "⚠️  ",
+                format_args!(
+                    "Ignoring invalid {} attribute {:?} on formula #{}; using the default",
+                    name,
+                    value,
+                    index + 1
+                ),
+            );
+        }
+    }
+
+    fn image_src(&self, result: &FormulaRenderResult) -> Result<String> {
+        match self.images {
+            Some(images) => write_external_image(result, images, self.options),
+            None => Ok(result.data_url()),
+        }
+    }
+
+    fn formula_html(
+        &self,
+        formula: &HtmlFormula,
+        result: &FormulaRenderResult,
+        class_prefix: &str,
+    ) -> Result<String> {
+        // Formulas switched to another format by their attributes keep their own image
+        let Some(sprites) = self
+            .sprites
+            .as_ref()
+            .filter(|_| result.format == RenderFormat::Png)
+        else {
+            let src = self.image_src(result)?;
+            return Ok(formula.img_tag(result, &src, class_prefix));
+        };
+
+        let hash = format!("{:x}", Sha256::digest(&result.data));
+        let key = format!("f{}", &hash[..16]);
+        if !sprites.lock().unwrap().contains_key(&key) {
+            let pixmap = Pixmap::decode_png(&result.data)
+                .context("Failed to decode formula image for the sprite sheet")?;
+            sprites.lock().unwrap().insert(key.clone(), pixmap);
+        }
+
+        let alt = formula.alt.as_deref().unwrap_or(&result.formula);
+        Ok(sprite::sprite_tag(result, &key, class_prefix, alt))
+    }
+
+    fn formula_rendered(
+        &self,
+        formula: &HtmlFormula,
+        result: Option<&FormulaRenderResult>,
+        duration: Duration,
+    ) {
+        if self.options.timings.is_some() {
+            self.timings.lock().unwrap().push(FormulaTiming {
+                formula_index: formula.index,
+                formula: formula.formula.clone(),
+                duration,
+            });
+        }
+        if result.is_some_and(|result| result.used_fallback) && self.options.verbose {
+            self.options.console.error(
+                "⚠️  ",
+                format_args!(
+                    "Formula #{} failed with the math font; rendered with {}",
+                    formula.index + 1,
+                    FALLBACK_MATH_FONT
+                ),
+            );
+        }
+        if let Some(result) = result.filter(|_| self.options.verbose) {
+            for warning in &result.warnings {
+                self.options.console.error(
+                    "⚠️  ",
+                    format_args!(
+                        "Formula #{}: warning: {}",
+                        formula.index + 1,
+                        warning.message
+                    ),
+                );
+            }
+        }
+        if let Some(pb) = self.pb_formulas {
+            pb.inc(1);
+        }
+    }
+}
+
+/// Renders formulas within HTML content and returns the modified HTML and
+/// the number of formulas found.
 /// Takes an optional ProgressBar ONLY for the single-file case to update formula progress.
+/// When `images` is set, images are written there and referenced by
+/// `<src_prefix>/<file_prefix><hash>.<ext>` instead of being embedded.
+/// When `dump_dir` is set, each formula's Typst source is written there.
+/// When `sprite_output` is set, PNG images are packed into a sprite sheet written there.
 fn render_formulas_in_html(
     html_content: &str,
-    ppi: f32,
-    format: Format,
-    font_config: &FontConfig,
+    html_options: &HtmlOptions,
+    options: &ProcessOptions,
+    images: Option<&ImageOutput>,
+    sprite_output: Option<&SpriteOutput>,
+    dump_dir: Option<&Path>,
     pb_formulas: Option<&ProgressBar>,
-) -> Result<(String, Vec<FormulaError>)> {
-    let document = Html::parse_document(html_content);
-    let selector = Selector::parse("eq").expect("Invalid selector 'eq'");
+) -> Result<(String, usize, Vec<FormulaError>, Vec<FormulaTiming>)> {
+    if let Some(dir) = dump_dir {
+        for formula in html::find_formulas(html_content, html_options) {
+            let source = match formula.number {
+                Some(number) => options
+                    .engine
+                    .generate_numbered_source_for(&formula.formula, number),
+                None => options
+                    .engine
+                    .generate_source_for(&formula.formula, formula.is_inline),
+            };
+            let path = dir.join(format!("formula-{}.typ", formula.index + 1));
+            fs::write(&path, source)
+                .with_context(|| format!("Failed to write Typst source: {:?}", path))?;
+        }
+    }
 
-    let mut processed_html_string = document.html();
-    let mut formula_tasks = Vec::new();
+    let hooks = CliHooks {
+        options,
+        images,
+        pb_formulas,
+        timings: Mutex::new(Vec::new()),
+        sprites: sprite_output.map(|_| Mutex::new(BTreeMap::new())),
+    };
+    if let Some(pb) = pb_formulas {
+        pb.set_message("parsing document");
+    }
+    let output = html::render_html_with(html_content, options.engine, html_options, &hooks);
+
+    let errors = output
+        .errors
+        .into_iter()
+        .map(|error| FormulaError {
+            formula: error.formula,
+            error: error.error,
+            formula_index: error.index,
+            line: error.line,
+        })
+        .collect();
+    let timings = hooks
+        .timings
+        .into_inner()
+        .map_err(|_| anyhow::anyhow!("Mutex for formula timings was poisoned"))?;
+
+    let mut processed_html = output.html;
+    let sprites = hooks
+        .sprites
+        .map(|sprites| sprites.into_inner().unwrap())
+        .filter(|sprites| !sprites.is_empty());
+    if let (Some(sprite_output), Some(sprites)) = (sprite_output, sprites) {
+        let sprites: Vec<(String, Pixmap)> = sprites.into_iter().collect();
+        let sheet = SpriteSheet::pack(&sprites).context("Failed to build the sprite sheet")?;
+        fs::write(&sprite_output.path, &sheet.png)
+            .with_context(|| format!("Failed to write sprite sheet: {:?}", sprite_output.path))?;
+        options.images_written.fetch_add(1, Ordering::Relaxed);
+        let css = sheet.css(&sprite_output.src, &html_options.class_prefix);
+        processed_html = insert_style(&processed_html, &css);
+    }
+    // A re-run over in-place output already has the stylesheet
+    if let Some(css) = options
+        .stylesheet
+        .filter(|css| !processed_html.contains(*css))
+    {
+        processed_html = insert_style(&processed_html, css);
+    }
 
-    for (formula_id_counter, element) in document.select(&selector).enumerate() {
-        let formula = element.text().collect::<String>();
-        let env = element
-            .value()
-            .attr("env")
-            .map(|s| s.to_string())
-            .unwrap_or_default();
-        let original_eq_html = element.html();
-        let formula_id = format!("__GLADST_FORMULA_PLACEHOLDER_{}__", formula_id_counter);
+    Ok((processed_html, output.formula_count, errors, timings))
+}
 
-        if let Some(pos) = processed_html_string.find(&original_eq_html) {
-            processed_html_string.replace_range(pos..pos + original_eq_html.len(), &formula_id);
-        }
+/// Add a `<style>` block with `css` to the end of the document's head
+fn insert_style(html: &str, css: &str) -> String {
+    let style = format!("<style>\n{css}</style>");
+    match html.find("</head>") {
+        Some(position) => format!("{}{}{}", &html[..position], style, &html[position..]),
+        None => format!("{style}{html}"),
+    }
+}
 
-        formula_tasks.push((formula_id, formula, env, formula_id_counter));
+/// Write a rendered formula to the image directory, named by a hash of its content so
+/// identical formulas share one file and re-runs leave existing files alone.
+/// Returns the `src` to reference it by.
+fn write_external_image(
+    result: &FormulaRenderResult,
+    images: &ImageOutput,
+    options: &ProcessOptions,
+) -> Result<String> {
+    let hash = Sha256::digest(&result.data);
+    let file_name = format!(
+        "{}{:x}.{}",
+        images.file_prefix,
+        hash,
+        result.format.extension()
+    );
+
+    let image_path = images.dir.join(&file_name);
+    if !image_path.exists() {
+        fs::write(&image_path, &result.data)
+            .with_context(|| format!("Failed to write formula image: {:?}", image_path))?;
+        options.images_written.fetch_add(1, Ordering::Relaxed);
+    }
+    // Raster formats are compressed already
+    if result.format == RenderFormat::Svg {
+        write_compressed_image(&result.data, &image_path, options)?;
     }
 
-    if formula_tasks.is_empty() {
-        return Ok((processed_html_string, Vec::new()));
+    let prefix = images.src_prefix.to_string_lossy().replace('\\', "/");
+    Ok(format!("{}/{}", prefix.trim_end_matches('/'), file_name))
+}
+
+/// Write the `--compress` copy of an image next to it unless it exists already
+fn write_compressed_image(data: &[u8], image_path: &Path, options: &ProcessOptions) -> Result<()> {
+    let Some(extension) = options.compress.extension() else {
+        return Ok(());
+    };
+    let mut compressed_path = image_path.as_os_str().to_owned();
+    compressed_path.push(".");
+    compressed_path.push(extension);
+    let compressed_path = PathBuf::from(compressed_path);
+    if compressed_path.exists() {
+        return Ok(());
     }
 
-    if let Some(pb) = pb_formulas {
-        pb.set_length(formula_tasks.len() as u64);
-        pb.reset();
+    let compressed = options
+        .compress
+        .compress(data)
+        .with_context(|| format!("Failed to compress formula image: {:?}", image_path))?;
+    fs::write(&compressed_path, &compressed)
+        .with_context(|| format!("Failed to write formula image: {:?}", compressed_path))?;
+    if options.verbose {
+        options.console.status(
+            "🗜️  ",
+            format_args!(
+                "Compressed {:?}: {} -> {} bytes ({:.1}%)",
+                compressed_path,
+                data.len(),
+                compressed.len(),
+                compressed.len() as f64 / data.len().max(1) as f64 * 100.0
+            ),
+        );
     }
+    Ok(())
+}
 
-    let processed_html_string_mutex = Arc::new(Mutex::new(processed_html_string));
-    let formula_errors = Arc::new(Mutex::new(Vec::<FormulaError>::new()));
+fn parse_selector(selector: &str, flag: &str) -> Result<Selector> {
+    html::parse_selector(selector).with_context(|| format!("Invalid {}", flag))
+}
 
-    // Create render engine once with the configured fonts
-    let renderer = RenderEngine::with_font_config(font_config.clone());
+/// Output file name of an input, unless `--name-template` says otherwise
+const DEFAULT_NAME_TEMPLATE: &str = "{stem}.html";
 
-    formula_tasks
-        .into_par_iter()
-        .for_each(|(formula_id, formula, env, formula_index)| {
-            let is_inline = match env.as_str() {
-                "displaymath" => false,
-                "math" | "" => true,
-                _ => true,
-            };
+/// Placeholders `--name-template` may contain
+const NAME_TEMPLATE_PLACEHOLDERS: &[&str] = &["stem", "ext", "parent"];
 
-            match renderer.render_formula(
-                &formula,
-                is_inline,
-                match format {
-                    Format::Png => RenderFormat::Png,
-                    Format::Svg => RenderFormat::Svg,
-                },
-                Some(ppi),
-            ) {
-                Ok(result) => {
-                    if !result.data.is_empty() {
-                        let replacement = result.to_html();
-
-                        let mut locked_string = processed_html_string_mutex.lock().unwrap();
-                        *locked_string = locked_string.replacen(&formula_id, &replacement, 1);
-                    }
-                }
-                Err(e) => {
-                    // Store the error for later reporting
-                    formula_errors.lock().unwrap().push(FormulaError {
-                        formula: formula.clone(),
-                        error: e,
-                        formula_index,
-                    });
-                    
-                    // Create error replacement in HTML
-                    let error_replacement = format!(
-                        r#"<span style="color: red; background-color: #ffe6e6; padding: 2px 4px; border-radius: 3px;" title="Formula render error - see logs for details">[Formula Error #{}: {}]</span>"#,
-                        formula_index + 1,
-                        if formula.len() > 20 { format!("{}...", &formula[..17]) } else { formula }
-                    );
-                    let mut locked_string = processed_html_string_mutex.lock().unwrap();
-                    *locked_string = locked_string.replacen(&formula_id, &error_replacement, 1);
-                }
-            }
+/// Check that an output name template only uses known placeholders and names a file
+fn validate_name_template(template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            anyhow::bail!("Invalid --name-template {:?}: unmatched '}}'", template);
+        }
+        let Some(end) = rest[start..].find('}') else {
+            anyhow::bail!("Invalid --name-template {:?}: unmatched '{{'", template);
+        };
+        let name = &rest[start + 1..start + end];
+        if !NAME_TEMPLATE_PLACEHOLDERS.contains(&name) {
+            anyhow::bail!(
+                "Invalid --name-template {:?}: unknown placeholder {{{}}}, expected one of {}",
+                template,
+                name,
+                NAME_TEMPLATE_PLACEHOLDERS
+                    .iter()
+                    .map(|placeholder| format!("{{{placeholder}}}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        rest = &rest[start + end + 1..];
+    }
+    if template.trim().is_empty() || template.ends_with(['/', '\\']) {
+        anyhow::bail!(
+            "Invalid --name-template {:?}: does not name a file",
+            template
+        );
+    }
+    Ok(())
+}
 
-            if let Some(pb) = pb_formulas {
-                pb.inc(1);
-            }
-        });
+/// Output path of `input_path` relative to the output directory, following a template
+/// checked by [`validate_name_template`]. `{parent}` keeps only the normal components
+/// of the input's directory, so the result never leaves the output directory.
+fn expand_name_template(template: &str, input_path: &Path) -> Result<PathBuf> {
+    let stem = input_path
+        .file_stem()
+        .ok_or_else(|| anyhow::anyhow!("Could not get file stem for {:?}", input_path))?;
+    let ext = input_path.extension().unwrap_or_default();
+    let parent: PathBuf = input_path
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
 
-    let final_html = Arc::try_unwrap(processed_html_string_mutex)
-        .map_err(|_| anyhow::anyhow!("Failed to unwrap Mutex for processed HTML string"))?
-        .into_inner()
-        .map_err(|_| anyhow::anyhow!("Mutex for processed HTML string was poisoned"))?;
+    let name = template
+        .replace("{stem}", &stem.to_string_lossy())
+        .replace("{ext}", &ext.to_string_lossy())
+        .replace("{parent}", &parent.to_string_lossy());
+    // An empty {parent} leaves a leading separator, which would make the path absolute
+    Ok(PathBuf::from(name.trim_start_matches(['/', '\\'])))
+}
 
-    let mut errors = Arc::try_unwrap(formula_errors)
-        .map_err(|_| anyhow::anyhow!("Failed to unwrap Mutex for formula errors"))?
-        .into_inner()
-        .map_err(|_| anyhow::anyhow!("Mutex for formula errors was poisoned"))?;
+/// File extensions picked up when walking a directory
+const DEFAULT_EXTENSIONS: &[&str] = &["htex", "html", "htm", "xhtml"];
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+}
+
+/// Resolve the input argument to a list of files. Directories are walked
+/// recursively, anything else is treated as a glob pattern.
+fn collect_input_paths(input: &str, extensions: &[String]) -> Result<Vec<PathBuf>> {
+    let input = expand_tilde(input);
+    let extensions: Vec<String> = extensions
+        .iter()
+        .map(|ext| ext.trim_start_matches('.').to_string())
+        .collect();
 
-    // Sort errors by formula index for consistent output
-    errors.sort_by_key(|e| e.formula_index);
+    if Path::new(&input).is_dir() {
+        let extensions = if extensions.is_empty() {
+            DEFAULT_EXTENSIONS.iter().map(|ext| ext.to_string()).collect()
+        } else {
+            extensions
+        };
+
+        let mut paths = Vec::new();
+        for entry in WalkDir::new(&input).follow_links(true) {
+            let entry =
+                entry.with_context(|| format!("Failed to walk directory: {}", input))?;
+            if entry.file_type().is_file() && has_extension(entry.path(), &extensions) {
+                paths.push(entry.into_path());
+            }
+        }
+        paths.sort();
+        return Ok(paths);
+    }
 
-    Ok((final_html, errors))
+    Ok(glob(&input)
+        .with_context(|| format!("Failed to read glob pattern: {}", input))?
+        .filter_map(Result::ok)
+        .filter(|path| extensions.is_empty() || has_extension(path, &extensions))
+        .collect())
+}
+
+/// Markdown inputs are converted to HTML, with their `$...$` and `$$...$$` math rendered
+fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
 }
 
 fn needs_inplace_modification(path: &Path) -> bool {
@@ -305,162 +1264,779 @@ fn needs_inplace_modification(path: &Path) -> bool {
 
 fn process_single_file(
     input_path: &Path,
-    output_dir_option: Option<&Path>,
-    ppi: f32,
-    format: Format,
-    font_config: &FontConfig,
+    options: &ProcessOptions,
     pb_formulas: Option<&ProgressBar>,
-    verbose: bool,
-) -> Result<()> {
+) -> Result<ProcessedFile> {
+    let verbose = options.verbose;
+    let console = options.console;
     let input_content = fs::read_to_string(input_path)
         .with_context(|| format!("Failed to read input file: {:?}", input_path))?;
 
-    let (processed_html, formula_errors) =
-        render_formulas_in_html(&input_content, ppi, format, font_config, pb_formulas)?;
+    let inplace = needs_inplace_modification(input_path);
+    let output_path = if inplace {
+        input_path.to_path_buf()
+    } else {
+        let output_base = options
+            .output_dir
+            .unwrap_or_else(|| input_path.parent().unwrap_or_else(|| Path::new(".")));
+        let output_path =
+            output_base.join(expand_name_template(options.name_template, input_path)?);
+        // The template may place the file in a subdirectory of the output directory
+        let output_dir = output_path.parent().unwrap_or(output_base);
+        if !options.dry_run {
+            fs::create_dir_all(output_dir)
+                .with_context(|| format!("Failed to create output directory: {:?}", output_dir))?;
+        }
+        output_path
+    };
+
+    if let Some(cache) = options.build_cache {
+        let mut cache = cache.lock().unwrap();
+        if cache.is_fresh(input_path, &input_content, &output_path) {
+            cache.mark_skipped();
+            return Ok(ProcessedFile {
+                output_path,
+                written: false,
+                skipped: true,
+                formula_errors: Vec::new(),
+            });
+        }
+    }
+
+    // External images live next to the HTML that references them
+    let output_parent = output_path.parent().unwrap_or_else(|| Path::new("."));
+    let images = match (options.external_images, options.sidecar_images) {
+        _ if options.dry_run => None,
+        (Some(dir), _) => Some(ImageOutput {
+            dir: output_parent.join(dir),
+            src_prefix: dir.to_path_buf(),
+            file_prefix: "",
+        }),
+        (None, true) => {
+            let doc_name = output_path
+                .file_stem()
+                .ok_or_else(|| anyhow::anyhow!("Could not get file stem for {:?}", output_path))?;
+            Some(ImageOutput {
+                dir: output_parent.join(doc_name),
+                src_prefix: PathBuf::from(doc_name),
+                file_prefix: "formula_",
+            })
+        }
+        (None, false) => None,
+    };
+    let sprite_output = match output_path.file_stem() {
+        Some(doc_name) if options.sprite_sheet && !options.dry_run => {
+            let file_name = format!("{}.sprites.png", doc_name.to_string_lossy());
+            Some(SpriteOutput {
+                path: output_parent.join(&file_name),
+                src: file_name,
+            })
+        }
+        _ => None,
+    };
+    if let Some(images) = &images {
+        fs::create_dir_all(&images.dir)
+            .with_context(|| format!("Failed to create image directory: {:?}", images.dir))?;
+    }
+
+    let dump_dir = match (options.dump_typst, input_path.file_stem()) {
+        (Some(dir), Some(stem)) => Some(dir.join(stem)),
+        _ => None,
+    };
+    if let Some(dir) = &dump_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create Typst source directory: {:?}", dir))?;
+    }
+
+    // Markdown math becomes `<eq>` elements, which are then rendered like any other document
+    let markdown_html = is_markdown(input_path).then(|| {
+        (
+            markdown::markdown_to_html(&input_content),
+            markdown::html_options(options.html),
+        )
+    });
+    let (html_content, html_options) = match &markdown_html {
+        Some((html, html_options)) => (html.as_str(), html_options),
+        None => (input_content.as_str(), options.html),
+    };
+
+    let started = Instant::now();
+    let (processed_html, formula_count, formula_errors, formula_timings) =
+        render_formulas_in_html(
+            html_content,
+            html_options,
+            options,
+            images.as_ref(),
+            sprite_output.as_ref(),
+            dump_dir.as_deref(),
+            pb_formulas,
+        )?;
+    if let Some(timings) = options.timings {
+        let mut timings = timings.lock().unwrap();
+        timings
+            .files
+            .push((input_path.to_path_buf(), started.elapsed(), formula_count));
+        timings.formulas.extend(
+            formula_timings
+                .into_iter()
+                .map(|timing| (input_path.to_path_buf(), timing)),
+        );
+    }
 
     // Report formula errors if any
     if !formula_errors.is_empty() {
-        println!("\n⚠️  Formula Rendering Errors in {:?}:", input_path);
-        println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+        eprintln!();
+        console.error("⚠️  ", format_args!("Formula Rendering Errors in {:?}:", input_path));
+        eprintln!("{}", console.rule());
         for formula_error in &formula_errors {
-            print!("{}", format_formula_error(formula_error, verbose));
+            eprint!("{}", format_formula_error(formula_error, verbose, console));
         }
-        println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
-        println!("📊 Summary: {} out of {} formulas failed to render", 
-                formula_errors.len(), 
-                processed_html.matches("__GLADST_FORMULA_PLACEHOLDER_").count() + formula_errors.len());
+        eprintln!("{}", console.rule());
+        console.error(
+            "📊 ",
+            format_args!(
+                "Summary: {} out of {} formulas failed to render",
+                formula_errors.len(),
+                formula_count
+            ),
+        );
         if !verbose {
-            println!("💡 Use --verbose flag to see detailed error information");
+            console.error("💡 ", "Use --verbose flag to see detailed error information");
         }
-        println!();
+        eprintln!();
     }
 
-    let inplace = needs_inplace_modification(input_path);
-    let output_path = if inplace {
-        input_path.to_path_buf()
-    } else {
-        let output_base = output_dir_option
-            .unwrap_or_else(|| input_path.parent().unwrap_or_else(|| Path::new(".")));
-        fs::create_dir_all(output_base)
-            .with_context(|| format!("Failed to create output directory: {:?}", output_base))?;
+    // With --keep-on-error a failed formula leaves the previous output (or the
+    // source of an in-place edit) as it was
+    let written = !options.dry_run && !(options.keep_on_error && !formula_errors.is_empty());
+    if written {
+        fs::write(&output_path, &processed_html)
+            .with_context(|| format!("Failed to write output file: {:?}", output_path))?;
+    }
 
-        let file_stem = input_path
-            .file_stem()
-            .ok_or_else(|| anyhow::anyhow!("Could not get file stem for {:?}", input_path))?;
-        output_base.join(file_stem).with_extension("html")
+    // Files with failed formulas are left out so the next run retries them
+    if let Some(cache) = options
+        .build_cache
+        .filter(|_| written && formula_errors.is_empty())
+    {
+        // An in-place edit is recorded with the rendered content, which the next run reads
+        let content = if inplace { &processed_html } else { &input_content };
+        cache.lock().unwrap().record(input_path, content);
+    }
+
+    Ok(ProcessedFile {
+        output_path,
+        written,
+        skipped: false,
+        formula_errors,
+    })
+}
+
+fn describe_font_source(source: &FontSource) -> String {
+    match source {
+        FontSource::System(name) => format!("{} (system)", name),
+        FontSource::File(path, None) => format!("{} (file)", path),
+        FontSource::File(path, Some(index)) => format!("{} (file, face {})", path, index),
+        FontSource::Data(..) => "embedded data".to_string(),
+    }
+}
+
+/// Print the fonts in use, warning about the system fonts in `missing` that were not found.
+/// The warnings go to stderr and are shown even when quiet.
+fn print_font_config(font_config: &FontConfig, missing: &[String], console: Console) {
+    let warn_missing = |role: &str, source: &FontSource| {
+        let FontSource::System(name) = source else {
+            return;
+        };
+        if missing.contains(name) {
+            console.error("  ⚠️  ", format_args!("{} '{}' not found, using fallback", role, name));
+        }
     };
+    let arrow = console.icon("↳ ");
 
-    fs::write(&output_path, processed_html)
-        .with_context(|| format!("Failed to write output file: {:?}", output_path))?;
+    console.status("🔤 ", "Font Configuration:");
+    console.status(
+        "  📝 ",
+        format_args!("Body Font: {}", describe_font_source(&font_config.body_font)),
+    );
+    warn_missing("Body Font", &font_config.body_font);
+    for fallback in &font_config.body_font_fallbacks {
+        console.status("     ", format_args!("{}Fallback: {}", arrow, describe_font_source(fallback)));
+        warn_missing("Body Font Fallback", fallback);
+    }
+    console.status(
+        "  🔢 ",
+        format_args!("Math Font: {}", describe_font_source(&font_config.math_font)),
+    );
+    warn_missing("Math Font", &font_config.math_font);
+    for fallback in &font_config.math_font_fallbacks {
+        console.status("     ", format_args!("{}Fallback: {}", arrow, describe_font_source(fallback)));
+        warn_missing("Math Font Fallback", fallback);
+    }
+    console.status("", "");
+}
 
-    Ok(())
+/// Formula rendered by `gladst doctor`, using fractions, scripts and large operators
+fn doctor_formula(syntax: InputSyntax) -> &'static str {
+    match syntax {
+        InputSyntax::Latex => r"\sum_{k=1}^{n} k^2 = \frac{n(n+1)(2n+1)}{6}",
+        InputSyntax::Typst => "sum_(k=1)^n k^2 = (n(n+1)(2n+1))/6",
+    }
 }
 
-fn print_font_config(font_config: &FontConfig) {
-    println!("🔤 Font Configuration:");
-    match &font_config.body_font {
-        FontSource::System(name) => println!("  📝 Body Font: {} (system)", name),
-        FontSource::File(path) => println!("  📝 Body Font: {} (file)", path),
-        FontSource::Data(_) => println!("  📝 Body Font: embedded data"),
+/// Check that `font_config` works: every named font is found, the packages resolve and
+/// a test formula renders. Fails with one actionable message per problem.
+fn run_doctor(font_config: &FontConfig, console: Console) -> Result<()> {
+    let mut problems = Vec::new();
+    let engine = RenderEngine::with_font_config(font_config.clone());
+
+    let missing = engine.validate_fonts();
+    print_font_config(font_config, &missing, console);
+    for name in &missing {
+        problems.push(format!(
+            "Font '{}' was not found. Install it, pick a family from the list above with \
+             --body-font-name/--math-font-name, or pass the font file with \
+             --body-font-file/--math-font-file",
+            name
+        ));
     }
-    match &font_config.math_font {
-        FontSource::System(name) => println!("  🔢 Math Font: {} (system)", name),
-        FontSource::File(path) => println!("  🔢 Math Font: {} (file)", path),
-        FontSource::Data(_) => println!("  🔢 Math Font: embedded data"),
+
+    // List system fonts even when only font files are configured, like --list-fonts
+    let families = RenderEngine::with_font_config(
+        font_config
+            .clone()
+            .into_builder()
+            .include_system_fonts(true)
+            .build(),
+    )
+    .available_fonts();
+    console.status(
+        "📚 ",
+        format_args!("{} font families available:", families.len()),
+    );
+    for family in &families {
+        console.status("     ", family);
     }
-    println!();
+    console.status("", "");
+
+    // A trivial formula tells package problems apart from problems with the test formula
+    match engine.render_formula("x", true, RenderFormat::Svg, None) {
+        Err(error @ RenderError::PackageUnavailable(_)) => problems.push(format!(
+            "{}. Check network access, or download the package once and use \
+             --package-dir or --offline with the local Typst package cache",
+            error
+        )),
+        Err(error) if font_config.preamble.is_some() => {
+            problems.push(format!("The preamble failed to compile: {}", error))
+        }
+        _ => {
+            if font_config.input_syntax == InputSyntax::Latex {
+                console.status(
+                    "📦 ",
+                    format_args!("mitex {} resolved", font_config.mitex_version),
+                );
+            }
+            for package in &font_config.extra_imports {
+                console.status("📦 ", format_args!("{} resolved", package));
+            }
+        }
+    }
+
+    let formula = doctor_formula(font_config.input_syntax);
+    match engine.render_formula(formula, false, RenderFormat::Svg, None) {
+        Ok(result) => {
+            console.status(
+                "🧪 ",
+                format_args!(
+                    "Test formula rendered ({:.2}em x {:.2}em, {} glyphs)",
+                    result.x_em, result.y_em, result.glyph_count
+                ),
+            );
+            if result.used_fallback {
+                problems.push(format!(
+                    "The math font failed and the test formula fell back to {}. Choose a \
+                     font with math tables, such as one listed above ending in \"Math\"",
+                    FALLBACK_MATH_FONT
+                ));
+            }
+            for warning in &result.warnings {
+                console.error("  ⚠️  ", format_args!("Warning: {}", warning.message));
+            }
+        }
+        Err(error) => problems.push(format!("The test formula failed to render: {}", error)),
+    }
+
+    if problems.is_empty() {
+        console.status("✅ ", "Everything looks fine");
+        return Ok(());
+    }
+    for problem in &problems {
+        console.error("❌ ", problem);
+    }
+    anyhow::bail!("gladst doctor found {} problem(s)", problems.len())
+}
+
+/// Send the engine's log to stderr. The summaries printed through [`Console`] already
+/// report every problem, so nothing is logged unless `--verbose` or `RUST_LOG` asks for it.
+fn init_logging(verbose: bool) {
+    let default_filter = if verbose {
+        "gladest_engine=debug"
+    } else {
+        "off"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .init();
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logging(args.verbose);
+    let console = Console {
+        quiet: args.quiet,
+        plain: args.no_color,
+    };
+    if args.no_color {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+
+    if let Some((config, path)) = config::load(args.config.as_deref())? {
+        console.status("⚙️  ", format_args!("Using config file: {:?}", path));
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        config
+            .apply(&mut args, &matches, base_dir)
+            .with_context(|| format!("Invalid config file: {:?}", path))?;
+    }
 
     // Create font configuration
+    if args.font_size <= 0.0 {
+        return Err(anyhow::anyhow!("Font size must be positive, got {}", args.font_size));
+    }
+
     let font_config = create_font_config(&args).context("Failed to create font configuration")?;
 
-    let paths: Vec<PathBuf> = glob(&expand_tilde(&args.input))
-        .with_context(|| format!("Failed to read glob pattern: {}", args.input))?
-        .filter_map(Result::ok)
-        .collect();
+    if let Some(Command::Doctor) = args.command {
+        return run_doctor(&font_config, console);
+    }
 
-    if paths.is_empty() {
-        println!("❌ No files found matching pattern: {}", args.input);
+    if args.list_fonts {
+        // List system fonts even when only font files are configured
+        let engine = RenderEngine::with_font_config(
+            font_config
+                .into_builder()
+                .include_system_fonts(true)
+                .build(),
+        );
+        for family in engine.available_fonts() {
+            println!("{}", family);
+        }
         return Ok(());
     }
+    let input = args.input.clone().context("An input path is required")?;
+
+    validate_name_template(&args.name_template)?;
+    if args.sprite_sheet && args.format != Format::Png {
+        anyhow::bail!("--sprite-sheet requires --format png");
+    }
+    if args.compress != Compression::None && args.external_images.is_none() && !args.sidecar_images
+    {
+        anyhow::bail!("--compress requires --external-images or --sidecar-images");
+    }
+    if let Some(width) = args
+        .max_width
+        .filter(|width| !(width.is_finite() && *width > 0.0))
+    {
+        anyhow::bail!("--max-width must be a positive number, got {}", width);
+    }
+    if !(args.svg_scale.is_finite() && args.svg_scale > 0.0) {
+        anyhow::bail!("--svg-scale must be a positive number, got {}", args.svg_scale);
+    }
+    let stylesheet = match &args.css_file {
+        Some(path) => Some(
+            fs::read_to_string(expand_tilde(&path.to_string_lossy()))
+                .with_context(|| format!("Failed to read CSS file: {:?}", path))?,
+        ),
+        None => args
+            .inject_css
+            .then(|| html::default_stylesheet(&args.class_prefix)),
+    };
+    let html_options = HtmlOptions {
+        selector: parse_selector(&args.selector, "--selector")?,
+        display_selector: parse_selector(&args.display_selector, "--display-selector")?,
+        inline_selector: parse_selector(&args.inline_selector, "--inline-selector")?,
+        default_inline: args.default_env == DefaultEnv::Inline,
+        format: args.format.render_format(),
+        ppi: args.ppi as f32,
+        class_prefix: args.class_prefix.clone(),
+        number_equations: args.number_equations,
+        svg_scale: args.svg_scale,
+        chunk_size: args.chunk_size,
+        // A sprite sheet is rewritten on every run and must hold every formula
+        rerender_unchanged: args.sprite_sheet,
+        keep_source: args.keep_source,
+    };
 
-    let ppi_f32 = args.ppi as f32;
-    let format = args.format;
-    let output_dir = args.output.as_deref();
-    let verbose = args.verbose;
+    let encode_options = EncodeOptions {
+        jpeg_quality: args.jpeg_quality,
+        avif_quality: args.avif_quality,
+        avif_speed: args.avif_speed,
+        ..EncodeOptions::default()
+    };
 
-    // Print font configuration
-    print_font_config(&font_config);
+    // Build the engine once; it is shared by every file and rebuild
+    let engine =
+        RenderEngine::with_font_config(font_config.clone()).with_encode_options(encode_options);
 
-    if paths.len() == 1 {
-        println!("📄 Processing single file: {:?}", paths[0]);
-        let formula_pb = ProgressBar::new(0);
-        formula_pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "    {spinner:.green} Formulas: [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
-                )
-                .context("Failed to set formula progress style for single file")?
-                .progress_chars("#>-"),
-        );
-        formula_pb.enable_steady_tick(Duration::from_millis(100));
+    if font_config.preamble.is_some() {
+        engine
+            .check_preamble()
+            .context("Failed to compile the preamble")?;
+    }
 
-        process_single_file(
-            &paths[0],
-            output_dir,
-            ppi_f32,
-            format,
-            &font_config,
-            Some(&formula_pb),
-            verbose,
-        )?;
+    let paths = collect_input_paths(&input, &args.extensions)?;
 
-        formula_pb.finish_and_clear();
+    if paths.is_empty() {
+        console.error("❌ ", format_args!("No files found matching pattern: {}", input));
+        if !args.watch {
+            return Ok(());
+        }
+    }
 
-        println!("✅ Processing complete");
+    let timings = Mutex::new(Timings::default());
+    let cache_path = args
+        .output
+        .as_deref()
+        .unwrap_or(Path::new("."))
+        .join(CACHE_FILE_NAME);
+    // Everything that changes the output of a file, except the file itself
+    let config_hash = hash_text(&format!(
+        "{:?}",
+        (
+            &font_config,
+            encode_options,
+            args.format,
+            args.ppi,
+            &args.class_prefix,
+            &args.name_template,
+            &args.selector,
+            &args.display_selector,
+            &args.inline_selector,
+            args.default_env,
+            args.number_equations,
+            args.keep_source,
+            args.svg_scale,
+            &args.external_images,
+            args.sidecar_images,
+            args.sprite_sheet,
+            args.compress,
+            &stylesheet,
+        )
+    ));
+    let build_cache = Mutex::new(if args.force {
+        BuildCache::new(config_hash)
     } else {
-        println!("📁 Processing {} files found by glob pattern...", paths.len());
-        run_batch(&paths, output_dir, ppi_f32, format, &font_config, verbose)?;
-        println!("✅ Batch processing complete");
+        BuildCache::load(&cache_path, config_hash)
+    });
+    let images_written = AtomicUsize::new(0);
+    let options = ProcessOptions {
+        output_dir: args.output.as_deref(),
+        name_template: &args.name_template,
+        engine: &engine,
+        html: &html_options,
+        external_images: args.external_images.as_deref(),
+        sidecar_images: args.sidecar_images,
+        sprite_sheet: args.sprite_sheet,
+        compress: args.compress,
+        stylesheet: stylesheet.as_deref(),
+        images_written: &images_written,
+        dry_run: args.dry_run,
+        keep_on_error: args.keep_on_error,
+        verbose: args.verbose,
+        console,
+        timings: args.timings.map(|_| &timings),
+        dump_typst: args.dump_typst.as_deref(),
+        // Skipped files would leave no Typst source to inspect
+        build_cache: (!args.dry_run && args.dump_typst.is_none()).then_some(&build_cache),
+    };
+
+    // Print font configuration
+    print_font_config(&font_config, &engine.validate_fonts(), console);
+
+    let process = || -> Result<()> {
+        let mut reports = Vec::new();
+        let mut file_error = None;
+
+        if paths.len() == 1 {
+            console.status("📄 ", format_args!("Processing single file: {:?}", paths[0]));
+            let formula_pb = console.progress_bar(
+                0,
+                "    {spinner:.green} Formulas: [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
+            )?;
+            formula_pb.enable_steady_tick(Duration::from_millis(100));
+
+            let result = process_single_file(&paths[0], &options, Some(&formula_pb));
+
+            formula_pb.finish_and_clear();
+
+            match result {
+                Ok(processed) => {
+                    if !processed.formula_errors.is_empty() {
+                        reports.push(FileReport::new(&paths[0], None, &processed.formula_errors));
+                    }
+                    console.status("✅ ", "Processing complete");
+                }
+                Err(e) => {
+                    reports.push(FileReport::new(&paths[0], Some(&e), &[]));
+                    file_error = Some(e);
+                }
+            }
+        } else if paths.len() > 1 {
+            console.status("📁 ", format_args!("Processing {} files...", paths.len()));
+            reports = run_batch(&paths, &options)?;
+            console.status("✅ ", "Batch processing complete");
+        }
+
+        if !args.dry_run {
+            let build_cache = build_cache.lock().unwrap();
+            if build_cache.skipped() > 0 {
+                console.status(
+                    "⏭️  ",
+                    format_args!(
+                        "Skipped {} unchanged files (use --force to re-render them)",
+                        build_cache.skipped()
+                    ),
+                );
+            }
+            // A lost cache only costs a full rebuild next time
+            if let Err(e) = build_cache.save(&cache_path) {
+                console.error("⚠️  ", format_args!("{:#}", e));
+            }
+        }
+
+        if args.external_images.is_some() || args.sidecar_images {
+            console.status(
+                "🖼️  ",
+                format_args!(
+                    "Wrote {} new formula images",
+                    images_written.load(Ordering::Relaxed)
+                ),
+            );
+        }
+
+        if let Some(limit) = args.timings {
+            // Taken out of the mutex, which the options still borrow
+            std::mem::take(&mut *timings.lock().unwrap()).print(limit, console);
+        }
+
+        let failed_formulas: usize = reports.iter().map(|r| r.formula_errors.len()).sum();
+        let failed_files = reports.iter().filter(|r| r.error.is_some()).count();
+        let unchanged = reports
+            .iter()
+            .filter(|r| !r.formula_errors.is_empty())
+            .count();
+
+        if let Some(report_path) = &args.report_json {
+            write_json_report(report_path, paths.len(), reports)?;
+        }
+        if let Some(e) = file_error {
+            return Err(e);
+        }
+
+        if args.dry_run {
+            if failed_formulas > 0 || failed_files > 0 {
+                return Err(anyhow::anyhow!(
+                    "Dry run found {} failed formulas and {} failed files",
+                    failed_formulas,
+                    failed_files
+                ));
+            }
+            console.status("🔍 ", "Dry run: all formulas rendered, no files were written");
+        }
+
+        if args.keep_on_error && failed_formulas > 0 {
+            return Err(anyhow::anyhow!(
+                "{} formulas failed to render; left {} files unchanged",
+                failed_formulas,
+                unchanged
+            ));
+        }
+
+        if args.watch {
+            watch_inputs(&input, &args.extensions, &options)?;
+        }
+        Ok(())
+    };
+
+    // Rayon uses all cores unless a dedicated pool size is requested
+    match args.jobs {
+        Some(jobs) => rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.get())
+            .build()
+            .context("Failed to create thread pool")?
+            .install(process)?,
+        None => process()?,
     }
 
     Ok(())
 }
 
-fn run_batch(
-    paths: &[PathBuf],
-    output_dir_option: Option<&Path>,
-    ppi: f32,
-    format: Format,
-    font_config: &FontConfig,
-    verbose: bool,
-) -> Result<()> {
-    let multi_progress = MultiProgress::new();
-    let files_pb = multi_progress.add(ProgressBar::new(paths.len() as u64));
-    files_pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} Files: [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")
-            .context("Failed to set files progress style")?
-            .progress_chars("#>-"),
+/// How long to wait for a burst of file events (e.g. an editor save) to settle
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Directory to watch for an input: the leading components of the path
+/// without glob characters, trimmed back to an existing directory.
+fn watch_root(input: &str) -> PathBuf {
+    let mut root = PathBuf::new();
+    for component in Path::new(input).components() {
+        if component
+            .as_os_str()
+            .to_string_lossy()
+            .contains(['*', '?', '[', '{'])
+        {
+            break;
+        }
+        root.push(component);
+    }
+
+    while !root.is_dir() && root.pop() {}
+
+    if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
+    }
+}
+
+fn collect_changed_paths(
+    event: notify::Result<Event>,
+    changed: &mut HashSet<PathBuf>,
+    console: Console,
+) {
+    match event {
+        Ok(event) if !event.kind.is_access() && !event.kind.is_remove() => {
+            changed.extend(event.paths.iter().filter_map(|path| fs::canonicalize(path).ok()));
+        }
+        Ok(_) => {}
+        Err(e) => console.error("⚠️  ", format_args!("File watcher error: {}", e)),
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Re-render input files whenever they change, until the process is killed
+fn watch_inputs(input: &str, extensions: &[String], options: &ProcessOptions) -> Result<()> {
+    let root = watch_root(&expand_tilde(input));
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to create file watcher")?;
+    watcher
+        .watch(&root, RecursiveMode::Recursive)
+        .with_context(|| format!("Failed to watch directory: {:?}", root))?;
+
+    let console = options.console;
+    console.status(
+        "👀 ",
+        format_args!("Watching {:?} for changes (press Ctrl+C to stop)", root),
     );
+
+    // Modification times of the files we wrote, so our own output (e.g. HTML
+    // rewritten in place) doesn't trigger another rebuild
+    let mut written: HashMap<PathBuf, SystemTime> = HashMap::new();
+
+    loop {
+        let mut changed = HashSet::new();
+        collect_changed_paths(rx.recv().context("File watcher stopped")?, &mut changed, console);
+        while let Ok(event) = rx.recv_timeout(WATCH_DEBOUNCE) {
+            collect_changed_paths(event, &mut changed, console);
+        }
+
+        // Resolve the input again so newly created files are picked up
+        let targets: Vec<(PathBuf, PathBuf)> = collect_input_paths(input, extensions)?
+            .into_iter()
+            .filter_map(|path| {
+                let canonical = fs::canonicalize(&path).ok()?;
+                let is_own_output = written.get(&canonical) == modified_time(&canonical).as_ref();
+                (changed.contains(&canonical) && !is_own_output).then_some((path, canonical))
+            })
+            .collect();
+
+        for (path, canonical) in targets {
+            let timestamp = Local::now().format("%H:%M:%S");
+            match process_single_file(&path, options, None) {
+                Ok(ProcessedFile { skipped: true, .. }) => {}
+                Ok(ProcessedFile {
+                    output_path,
+                    written: false,
+                    ..
+                }) => {
+                    if !console.quiet {
+                        println!(
+                            "[{}] {}Left {:?} unchanged after formula errors",
+                            timestamp,
+                            console.icon("⚠️  "),
+                            output_path
+                        );
+                    }
+                }
+                Ok(ProcessedFile { output_path, .. }) => {
+                    let output_path = fs::canonicalize(&output_path).unwrap_or(output_path);
+                    if let Some(mtime) = modified_time(&output_path) {
+                        written.insert(output_path, mtime);
+                    }
+                    if !console.quiet {
+                        println!("[{}] {}Rebuilt {:?}", timestamp, console.icon("🔄 "), path);
+                    }
+                }
+                Err(e) => {
+                    written.remove(&canonical);
+                    eprintln!(
+                        "[{}] {}Failed to rebuild {:?}: {}",
+                        timestamp,
+                        console.icon("❌ "),
+                        path,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Process files in parallel, returning a report entry for every file that
+/// failed or had formula errors
+fn run_batch(paths: &[PathBuf], options: &ProcessOptions) -> Result<Vec<FileReport>> {
+    let verbose = options.verbose;
+    let console = options.console;
+    let multi_progress = MultiProgress::new();
+    let files_pb = multi_progress.add(console.progress_bar(
+        paths.len() as u64,
+        "{spinner:.green} Files: [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
+    )?);
     files_pb.set_message("Starting...");
 
     let errors = Arc::new(Mutex::new(Vec::<(PathBuf, anyhow::Error)>::new()));
+    let reports = Mutex::new(Vec::<FileReport>::new());
 
     paths.into_par_iter().for_each(|path| {
         let file_name = path.file_name().unwrap_or_default().to_string_lossy();
         files_pb.set_message(format!("Processing: {}", file_name));
 
-        if let Err(e) = process_single_file(path, output_dir_option, ppi, format, font_config, None, verbose)
-        {
-            let error_record = (
-                path.clone(),
-                e.context(format!("Processing failed for file: {:?}", path)),
-            );
-            errors.lock().unwrap().push(error_record);
+        match process_single_file(path, options, None) {
+            Ok(processed) => {
+                if !processed.formula_errors.is_empty() {
+                    let report = FileReport::new(path, None, &processed.formula_errors);
+                    reports.lock().unwrap().push(report);
+                }
+            }
+            Err(e) => {
+                let error_record = (
+                    path.clone(),
+                    e.context(format!("Processing failed for file: {:?}", path)),
+                );
+                errors.lock().unwrap().push(error_record);
+            }
         }
         files_pb.inc(1);
     });
@@ -473,25 +2049,55 @@ fn run_batch(
         .expect("Mutex should not be poisoned");
 
     if !collected_errors.is_empty() {
-        println!("\n❌ Batch Processing Errors ({}):", collected_errors.len());
-        println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+        eprintln!();
+        console.error(
+            "❌ ",
+            format_args!("Batch Processing Errors ({}):", collected_errors.len()),
+        );
+        eprintln!("{}", console.rule());
         for (i, (path, error)) in collected_errors.iter().enumerate() {
-            println!("🗂️  File #{}: {:?}", i + 1, path);
+            console.error("🗂️  ", format_args!("File #{}: {:?}", i + 1, path));
             if verbose {
                 for line in extract_detailed_error(error).lines() {
-                    println!("   {}", line);
+                    eprintln!("   {}", line);
                 }
             } else {
-                println!("   Error: {}", error);
+                eprintln!("   Error: {}", error);
             }
-            println!();
+            eprintln!();
         }
-        println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
-        println!("📊 Finished with {} file processing errors.", collected_errors.len());
+        eprintln!("{}", console.rule());
+        console.error(
+            "📊 ",
+            format_args!("Finished with {} file processing errors.", collected_errors.len()),
+        );
         if !verbose {
-            println!("💡 Use --verbose flag to see detailed error information");
+            console.error("💡 ", "Use --verbose flag to see detailed error information");
         }
     }
 
+    let mut reports = reports.into_inner().unwrap();
+    reports.extend(
+        collected_errors
+            .iter()
+            .map(|(path, error)| FileReport::new(path, Some(error), &[])),
+    );
+    reports.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(reports)
+}
+
+/// Write the `--report-json` file
+fn write_json_report(path: &Path, files_processed: usize, files: Vec<FileReport>) -> Result<()> {
+    let report = JsonReport {
+        files_processed,
+        failed_files: files.iter().filter(|file| file.error.is_some()).count(),
+        failed_formulas: files.iter().map(|file| file.formula_errors.len()).sum(),
+        files,
+    };
+
+    let json = serde_json::to_string_pretty(&report).context("Failed to serialize JSON report")?;
+    fs::write(path, json).with_context(|| format!("Failed to write JSON report: {:?}", path))?;
+
     Ok(())
 }
\ No newline at end of file